@@ -0,0 +1,137 @@
+use std::net::Ipv4Addr;
+use crate::bgp::{AsPath, AsPathSegment, AutonomousSystemNumber, Origin, PathAttribute};
+use crate::rib::{LocRib, RouteSource, RoutingInformationEntry, RoutingInformationStatus};
+use crate::routing::IpPrefix;
+use crate::parse_option_value;
+
+// Route Aggregation (RFC4271 9.2.2.2)
+// 設定ファイルには以下のように書く。
+//   aggregate-address <prefix> [summary-only] [as-set]
+// LocRibにprefixより細かいrouteが1つでもあれば集約したrouteをoriginateする。
+//  - summary-only: 集約元の細かいrouteは広告しない
+//  - as-set: 集約元のAS_PATHをAS_SETにまとめる。指定しなければATOMIC_AGGREGATEを付ける
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateAddress {
+    pub prefix: IpPrefix,
+    pub summary_only: bool,
+    pub as_set: bool,
+}
+
+impl AggregateAddress {
+    pub fn parse_from_args(args: &[&str]) -> Result<Self, String> {
+        // <prefix> [summary-only] [as-set]
+        let prefix = parse_option_value("prefix of aggregate-address", args.first().copied())?;
+        let mut aggregate_address = Self { prefix, summary_only: false, as_set: false };
+        for option in &args[1..] {
+            match *option {
+                "summary-only" => aggregate_address.summary_only = true,
+                "as-set" => aggregate_address.as_set = true,
+                _ => return Err(format!("unknown aggregate-address option: {}", option)),
+            }
+        }
+        Ok(aggregate_address)
+    }
+
+    fn get_contributors(&self, loc_rib: &LocRib) -> Vec<RoutingInformationEntry> {
+        // 集約元になるのはprefixより細かいbest pathだけ
+        loc_rib.select_best_paths().into_iter()
+            .filter(|entry| !matches!(entry.source, RouteSource::Aggregate { .. }))
+            .filter(|entry| entry.status != RoutingInformationStatus::Withdrawn)
+            .filter(|entry| self.prefix.is_less_specific_than(&entry.destnation_address))
+            .collect()
+    }
+
+    pub fn create_route(&self, loc_rib: &LocRib, as_number: AutonomousSystemNumber, router_id: Ipv4Addr) -> Option<RoutingInformationEntry> {
+        let contributors = self.get_contributors(loc_rib);
+        if contributors.is_empty() {
+            return None;
+        }
+        // ORIGINは集約元にINCOMPLETEがあればINCOMPLETE、EGPがあればEGP、それ以外はIGP
+        let origin = match contributors.iter().map(|entry| entry.get_origin()).max() {
+            Some(2) => Origin::Incompleted,
+            Some(1) => Origin::Egp,
+            _ => Origin::Igp,
+        };
+        let as_paths: Vec<Vec<u16>> = contributors.iter().map(|entry| entry.get_as_path().get_seq()).collect();
+        let mut path_attributes = vec![PathAttribute::Origin(origin)];
+        if self.as_set {
+            // 全ての集約元に共通する先頭のAS_SEQUENCEを残し、残りのASをAS_SETにまとめる
+            let mut common = as_paths[0].clone();
+            for as_path in &as_paths[1..] {
+                let length = common.iter().zip(as_path).take_while(|(a, b)| a == b).count();
+                common.truncate(length);
+            }
+            let mut as_set: Vec<u16> = vec![];
+            for as_path in &as_paths {
+                for as_number in &as_path[common.len()..] {
+                    if !as_set.contains(as_number) {
+                        as_set.push(*as_number);
+                    }
+                }
+            }
+            let mut segments = vec![];
+            if !common.is_empty() {
+                segments.push(AsPathSegment::AsSequence(common));
+            }
+            if !as_set.is_empty() {
+                segments.push(AsPathSegment::AsSet(as_set));
+            }
+            path_attributes.push(PathAttribute::AsPath(AsPath::new(segments)));
+        } else {
+            path_attributes.push(PathAttribute::AsPath(AsPath::new(vec![])));
+        }
+        path_attributes.push(PathAttribute::NextHop(router_id));
+        // AS_PATHの情報が失われた場合と、集約元がすでに集約されたrouteの場合はATOMIC_AGGREGATEを付ける
+        let is_as_path_lost = !self.as_set && as_paths.iter().any(|as_path| !as_path.is_empty());
+        let has_atomic_aggregate = contributors.iter()
+            .any(|entry| entry.path_attributes.contains(&PathAttribute::AtomicAggregate));
+        if is_as_path_lost || has_atomic_aggregate {
+            path_attributes.push(PathAttribute::AtomicAggregate);
+        }
+        path_attributes.push(PathAttribute::Aggregator(as_number.0, router_id));
+
+        // 集約したrouteはカーネルには書き込まない
+        let mut entry = RoutingInformationEntry::new(
+            Ipv4Addr::new(0, 0, 0, 0), self.prefix, RoutingInformationStatus::Updated, path_attributes);
+        entry.source = RouteSource::Aggregate { summary_only: self.summary_only };
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rib::Rib;
+
+    fn entry(destnation: &str, as_path: Vec<u16>) -> RoutingInformationEntry {
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(as_path)])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+        RoutingInformationEntry::new(
+            Ipv4Addr::new(10, 0, 0, 1), destnation.parse().unwrap(), RoutingInformationStatus::Updated, path_attributes)
+    }
+
+    #[test]
+    fn test_create_aggregate_route() {
+        let as_number = AutonomousSystemNumber::new(64512);
+        let router_id = Ipv4Addr::new(10, 0, 0, 2);
+        let aggregate_address = AggregateAddress::parse_from_args(&["10.1.0.0/16", "summary-only", "as-set"]).unwrap();
+        let mut loc_rib = Rib::new(vec![entry("10.1.0.0/16", vec![64600]), entry("10.2.0.0/24", vec![64600])]);
+        // 同じ長さのprefixや範囲外のprefixは集約元にならない
+        assert!(aggregate_address.create_route(&loc_rib, as_number, router_id).is_none());
+
+        loc_rib.add(vec![entry("10.1.1.0/24", vec![64513, 64600]), entry("10.1.2.0/24", vec![64513, 64601, 64602])]);
+        let route = aggregate_address.create_route(&loc_rib, as_number, router_id).unwrap();
+        assert_eq!(route.get_as_path().to_string(), "64513 {64600 64601 64602}");
+        assert!(!route.path_attributes.contains(&PathAttribute::AtomicAggregate));
+        assert!(route.path_attributes.contains(&PathAttribute::Aggregator(64512, router_id)));
+
+        let aggregate_address = AggregateAddress::parse_from_args(&["10.1.0.0/16"]).unwrap();
+        let route = aggregate_address.create_route(&loc_rib, as_number, router_id).unwrap();
+        assert_eq!(route.get_as_path().to_string(), "");
+        assert!(route.path_attributes.contains(&PathAttribute::AtomicAggregate));
+    }
+}
@@ -0,0 +1,189 @@
+use std::convert::TryFrom;
+use std::fs;
+use crate::ConfigError;
+use crate::bgp::{AsPath, AsPathSegment};
+use crate::rib::AspaValidationState;
+
+// Autonomous System Provider Authorization (draft-ietf-sidrops-aspa-verification)
+// customer ASが自分のprovider ASの一覧を証明したもの。
+// AS_PATHの隣り合うASがcustomerとproviderの関係になっているかを調べてroute leakを見つける。
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aspa {
+    pub customer_as: u32,
+    pub providers: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HopCheck {
+    // customer ASのASPAにprovider ASが含まれるか
+    ProviderPlus,
+    NotProviderPlus,
+    NoAttestation,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AspaTable(pub Vec<Aspa>);
+
+impl AspaTable {
+    pub fn load_from_json_file(filename: &str) -> Result<Self, ConfigError> {
+        // rpki-clientのjson出力と同じ形式
+        // {"aspas": [{"customer_asid": 64512, "providers": [64513, 64514]}, ...]}
+        let error = |message: String| ConfigError::new(filename, 0, message);
+        let json = fs::read_to_string(filename).map_err(|e| error(format!("cannot read aspa file: {}", e)))?;
+        let json: serde_json::Value = serde_json::from_str(&json)
+            .map_err(|e| ConfigError::new(filename, e.line(), format!("cannot parse aspa file: {}", e)))?;
+        let as_number = |value: &serde_json::Value| value.as_u64().and_then(|as_number| u32::try_from(as_number).ok());
+        let mut result = vec![];
+        for aspa in json["aspas"].as_array().ok_or_else(|| error("aspa file needs aspas".to_string()))? {
+            let customer_as = as_number(&aspa["customer_asid"])
+                .ok_or_else(|| error(format!("cannot parse customer_asid: {}", aspa["customer_asid"])))?;
+            let providers = aspa["providers"].as_array()
+                .ok_or_else(|| error(format!("cannot parse providers of AS{}", customer_as)))?
+                .iter()
+                .map(|provider| as_number(provider).ok_or_else(|| error(format!("cannot parse providers of AS{}: {}", customer_as, provider))))
+                .collect::<Result<_, _>>()?;
+            result.push(Aspa { customer_as, providers });
+        }
+        Ok(AspaTable(result))
+    }
+
+    pub fn merge(&self, other: &AspaTable) -> AspaTable {
+        // 同じcustomer ASのASPAがあればproviderの和集合にする
+        let mut result = self.clone();
+        for aspa in &other.0 {
+            match result.0.iter_mut().find(|a| a.customer_as == aspa.customer_as) {
+                Some(a) => {
+                    for provider in &aspa.providers {
+                        if !a.providers.contains(provider) {
+                            a.providers.push(*provider);
+                        }
+                    }
+                },
+                None => result.0.push(aspa.clone()),
+            }
+        }
+        result
+    }
+
+    fn check_hop(&self, customer_as: u32, provider_as: u32) -> HopCheck {
+        match self.0.iter().find(|aspa| aspa.customer_as == customer_as) {
+            Some(aspa) if aspa.providers.contains(&provider_as) => HopCheck::ProviderPlus,
+            Some(_) => HopCheck::NotProviderPlus,
+            None => HopCheck::NoAttestation,
+        }
+    }
+
+    pub fn verify(&self, as_path: &AsPath, is_downstream: bool) -> AspaValidationState {
+        // is_downstream: providerから受け取ったroute (downstream verification) か、
+        // customerやpeerから受け取ったroute (upstream verification) か
+        // AS_SETを含むpathはInvalid
+        let mut path: Vec<u32> = vec![];
+        for segment in &as_path.0 {
+            match segment {
+                AsPathSegment::AsSequence(v) => {
+                    for as_number in v {
+                        // prependされた同じASは1つにまとめる
+                        if path.last() != Some(&u32::from(*as_number)) {
+                            path.push(u32::from(*as_number));
+                        }
+                    }
+                },
+                AsPathSegment::AsSet(_) => return AspaValidationState::Invalid,
+                _ => (),
+            }
+        }
+        // originから順に並べる
+        path.reverse();
+        let n = path.len();
+        if n == 0 {
+            return AspaValidationState::Invalid;
+        }
+        // originから上っていく部分(up-ramp)と、neighborから下っていく部分(down-ramp)の長さ
+        let ramp_length = |is_upward: bool, is_allowed: &dyn Fn(HopCheck) -> bool| {
+            let mut length = 1;
+            while length < n {
+                let hop = if is_upward {
+                    self.check_hop(path[length - 1], path[length])
+                } else {
+                    self.check_hop(path[n - length], path[n - length - 1])
+                };
+                if !is_allowed(hop) {
+                    break;
+                }
+                length += 1;
+            }
+            length
+        };
+        let is_not_not_provider_plus = |hop: HopCheck| hop != HopCheck::NotProviderPlus;
+        let is_provider_plus = |hop: HopCheck| hop == HopCheck::ProviderPlus;
+        let max_up_ramp = ramp_length(true, &is_not_not_provider_plus);
+        let min_up_ramp = ramp_length(true, &is_provider_plus);
+        if !is_downstream {
+            if max_up_ramp < n {
+                return AspaValidationState::Invalid;
+            }
+            if min_up_ramp < n {
+                return AspaValidationState::Unknown;
+            }
+            return AspaValidationState::Valid;
+        }
+        if n <= 2 {
+            return AspaValidationState::Valid;
+        }
+        let max_down_ramp = ramp_length(false, &is_not_not_provider_plus);
+        let min_down_ramp = ramp_length(false, &is_provider_plus);
+        if max_up_ramp + max_down_ramp < n {
+            return AspaValidationState::Invalid;
+        }
+        if min_up_ramp + min_down_ramp < n {
+            return AspaValidationState::Unknown;
+        }
+        AspaValidationState::Valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        // 64500 -> 64501 -> 64502 はcustomerからproviderへ、64503は64502のcustomer
+        let aspa_table = AspaTable(vec![
+            Aspa { customer_as: 64500, providers: vec![64501] },
+            Aspa { customer_as: 64501, providers: vec![64502] },
+            Aspa { customer_as: 64503, providers: vec![64502] },
+        ]);
+        let as_path = |as_numbers: Vec<u16>| AsPath::new(vec![AsPathSegment::AsSequence(as_numbers)]);
+
+        // customerから受け取ったpathは全てのhopが上りである必要がある
+        assert_eq!(aspa_table.verify(&as_path(vec![64501, 64500]), false), AspaValidationState::Valid);
+        assert_eq!(aspa_table.verify(&as_path(vec![64502, 64501, 64501, 64500]), false), AspaValidationState::Valid);
+        assert_eq!(aspa_table.verify(&as_path(vec![64503, 64501, 64500]), false), AspaValidationState::Invalid);
+        assert_eq!(aspa_table.verify(&as_path(vec![64503, 64502, 64501, 64500]), false), AspaValidationState::Unknown);
+        assert_eq!(aspa_table.verify(&as_path(vec![64510, 64500]), false), AspaValidationState::Invalid);
+        assert_eq!(aspa_table.verify(&as_path(vec![64511, 64510]), false), AspaValidationState::Unknown);
+
+        // providerから受け取ったpathは上ってから下っていれば良い
+        assert_eq!(aspa_table.verify(&as_path(vec![64503, 64502, 64501, 64500]), true), AspaValidationState::Valid);
+        // 64501が64500(customer)から受け取ったrouteを別のprovider(64510)に流している
+        assert_eq!(aspa_table.verify(&as_path(vec![64502, 64503, 64510, 64501, 64500]), true), AspaValidationState::Invalid);
+    }
+
+    #[test]
+    fn test_load_from_json_file() {
+        let filename = std::env::temp_dir().join(format!("mrbgpd-test-{}-aspa.json", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        fs::write(filename, r#"{"aspas": [{"customer_asid": 64512, "providers": [64513, 64514]}]}"#).unwrap();
+        let aspa_table = AspaTable::load_from_json_file(filename).unwrap();
+        assert_eq!(aspa_table.0, vec![Aspa { customer_as: 64512, providers: vec![64513, 64514] }]);
+
+        fs::write(filename, "{\n\"aspas\": [{\"customer_asid\": 64512, \"providers\": [64513,]}]\n}").unwrap();
+        assert_eq!(AspaTable::load_from_json_file(filename).unwrap_err().line, 2);
+        fs::write(filename, r#"{"aspas": [{"customer_asid": 64512, "providers": ["AS64513"]}]}"#).unwrap();
+        assert!(AspaTable::load_from_json_file(filename).is_err());
+        fs::remove_file(filename).unwrap();
+        assert!(AspaTable::load_from_json_file(filename).is_err());
+    }
+}
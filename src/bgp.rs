@@ -1,10 +1,9 @@
 use std::{convert::TryInto, fmt, fs::create_dir_all, io::Read, net::{Ipv4Addr, IpAddr, TcpStream}, option, path::Path, str::FromStr};
 use crate::rib::{AdjRibOut, LocRib, AdjRibIn};
-use crate::Config;
 use crate::finite_state_machine::{Event, EventQueue, PacketQueue};
 use crate::routing::IpPrefix;
 use rtnetlink::packet::RouteMessage;
-use crate::rib::RoutingInformationStatus;
+use crate::rib::{RoutingInformationEntry, RoutingInformationStatus};
 
 enum BGPVersion{
     V1,
@@ -62,6 +61,7 @@ impl BgpMessageHeader {
             BgpMessageType::Update => 2,
             BgpMessageType::Notification => 3,
             BgpMessageType::Keepalive => 4,
+            BgpMessageType::RouteRefresh => 5,
         };
         raw_data
     }
@@ -83,6 +83,7 @@ enum BgpMessageType {
     Update,
     Notification,
     Keepalive,
+    RouteRefresh, // RFC2918
 }
 
 pub struct BgpOpenMessage {
@@ -97,17 +98,24 @@ pub struct BgpOpenMessage {
 
 impl BgpOpenMessage {
     pub fn new(my_as_number: AutonomousSystemNumber,
-               my_ip_address: Ipv4Addr) -> Self {
-        let header = BgpMessageHeader {
-            length: 29,
-            type_: BgpMessageType::Open,
-        };
+               my_ip_address: Ipv4Addr,
+               hold_time: u16,
+               capabilities: Vec<Capability>) -> Self {
         let version = BGPVersion::V4;
         let my_autonomous_system = my_as_number;
-        let hold_time = HoldTime(60 * 4);
+        let hold_time = HoldTime(hold_time);
         let bgp_identifier = my_ip_address;
-        let optional_parameter_length = 0;
-        let optional_parameters = vec![];
+        let optional_parameters = if capabilities.is_empty() {
+            vec![]
+        } else {
+            vec![OptionalParameter::Capabilities(capabilities)]
+        };
+        let optional_parameter_length: usize = optional_parameters.iter().map(|p| p.value().len()).sum();
+        let optional_parameter_length: u8 = optional_parameter_length.try_into().unwrap();
+        let header = BgpMessageHeader {
+            length: 29 + optional_parameter_length as u16,
+            type_: BgpMessageType::Open,
+        };
 
         BgpOpenMessage {
             header,
@@ -119,7 +127,7 @@ impl BgpOpenMessage {
             optional_parameters,
         }
     }
-    pub fn encode(raw_data: &Vec<u8>) -> Self {
+    pub fn encode(raw_data: &Vec<u8>) -> Result<Self, OpenMessageError> {
         let header = BgpMessageHeader::encode_from_u8(&raw_data);
         let version = BGPVersion::encode_from_u8(raw_data[19]).unwrap();
         let my_autonomous_system = AutonomousSystemNumber(
@@ -128,15 +136,14 @@ impl BgpOpenMessage {
             u16::from_be_bytes(raw_data[22..24].try_into().unwrap()));
         let bgp_identifier = Ipv4Addr::new(raw_data[24], raw_data[25], raw_data[26], raw_data[27]);
         let optional_parameter_length = raw_data[28];
+        let end_of_optional_parameters = 29 + optional_parameter_length as usize;
+        if end_of_optional_parameters > raw_data.len() {
+            // optional parameters lengthがmessageの残りより長い
+            return Err(OpenMessageError::MalformedOptionalParameters);
+        }
+        let optional_parameters = OptionalParameter::encode(&raw_data[29..end_of_optional_parameters].to_vec())?;
 
-        // ToDo: optional parameter ni taiou suru
-        let optional_parameters = if optional_parameter_length != 0 {
-            panic!()
-        } else {
-            vec![]
-        };
-
-        Self {
+        Ok(Self {
             header,
             version,
             my_autonomous_system,
@@ -144,7 +151,26 @@ impl BgpOpenMessage {
             bgp_identifier,
             optional_parameter_length,
             optional_parameters,
+        })
+    }
+
+    pub fn get_bgp_identifier(&self) -> Ipv4Addr {
+        self.bgp_identifier
+    }
+
+    pub fn get_hold_time(&self) -> u16 {
+        self.hold_time.0
+    }
+
+    pub fn get_capabilities(&self) -> Vec<Capability> {
+        let mut result = vec![];
+        for optional_parameter in &self.optional_parameters {
+            match optional_parameter {
+                OptionalParameter::Capabilities(capabilities) => result.append(&mut capabilities.clone()),
+                _ => (),
+            }
         }
+        result
     }
 
     pub fn decode(&self) -> Vec<u8> {
@@ -167,8 +193,10 @@ impl BgpOpenMessage {
 
         buf[9] = self.optional_parameter_length;
 
-        // ToDo: Optional Parameters ni taiou
         header_bytes.append(&mut buf.to_vec());
+        for optional_parameter in &self.optional_parameters {
+            header_bytes.append(&mut optional_parameter.value());
+        }
         header_bytes
     }
 }
@@ -177,56 +205,191 @@ impl BgpOpenMessage {
 pub struct BgpUpdateMessage {
     header: BgpMessageHeader,
     withdrawn_routes_length: u16,
-    withdrawn_routes: Vec<IpPrefix>,
+    withdrawn_routes: Vec<Nlri>,
     total_path_attribute_length: u16,
     pub path_attributes: Vec<PathAttribute>,
-    pub network_layer_reachability_information: Vec<IpPrefix>,
+    pub network_layer_reachability_information: Vec<Nlri>,
 }
 
-impl BgpUpdateMessage {
-    pub fn is_created_from_adj_rib_out(adj_rib_out: &AdjRibOut, config: &Config) -> Self {
-        // ToDo: adj_rib_outはpath_attributeが同じやつだけにフィルターしたものにしなければならない
-        let advertise_route = adj_rib_out.get_new_route();
-        let mut advertise_route_ip_prefixes = vec![];
-        for entry in advertise_route {
-            if entry.status == RoutingInformationStatus::Updated{
-                let ip_prefix = entry.destnation_address;
-                advertise_route_ip_prefixes.push(ip_prefix);
-            }
-        }
-        let path_attributes = adj_rib_out.0[0].path_attributes.clone();
-        let total_path_attributes_length: usize = path_attributes.iter().map(|p|p.decode().len()).sum();
-        let total_path_attributes_length = total_path_attributes_length.try_into().unwrap();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Nlri {
+    // ADD-PATH(RFC7911)がnegotiateされていればprefixの前にpath identifierが付く
+    pub path_identifier: Option<u32>,
+    pub ip_prefix: IpPrefix,
+}
 
-        let withdrawn_routes_length = 0;
+impl Nlri {
+    pub fn new(ip_prefix: IpPrefix, path_identifier: Option<u32>) -> Self {
+        Self { path_identifier, ip_prefix }
+    }
 
-        let header_length = 19;
-        let mut nlri_length = 0;
-        for i in &advertise_route_ip_prefixes {
-            nlri_length += i.decode().len();
+    pub fn decode(&self) -> Vec<u8> {
+        let mut result = match self.path_identifier {
+            Some(path_identifier) => path_identifier.to_be_bytes().to_vec(),
+            None => vec![],
+        };
+        result.append(&mut self.ip_prefix.decode());
+        result
+    }
+}
+
+// RFC4271のmessageの最大長と、Extended Message(RFC8654)がnegotiateされたときの最大長
+pub const BGP_MAX_MESSAGE_LENGTH: usize = 4096;
+pub const BGP_EXTENDED_MAX_MESSAGE_LENGTH: usize = 65535;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NegotiatedCapabilities {
+    // peerとnegotiateできたcapabilityのうち、messageのencode/decodeに関係するもの
+    pub add_path_receive: bool,
+    pub add_path_send: bool,
+    pub extended_message: bool,
+}
+
+impl NegotiatedCapabilities {
+    pub fn get_max_message_length(&self) -> usize {
+        if self.extended_message {
+            BGP_EXTENDED_MAX_MESSAGE_LENGTH
+        } else {
+            BGP_MAX_MESSAGE_LENGTH
         }
+    }
+}
+
+impl BgpUpdateMessage {
+    pub fn new(withdrawn_routes: Vec<Nlri>, path_attributes: Vec<PathAttribute>, network_layer_reachability_information: Vec<Nlri>) -> Self {
+        let withdrawn_routes_length: usize = withdrawn_routes.iter().map(|r| r.decode().len()).sum();
+        let withdrawn_routes_length: u16 = withdrawn_routes_length.try_into().unwrap();
+        let total_path_attribute_length: usize = path_attributes.iter().map(|p| p.decode().len()).sum();
+        let total_path_attribute_length: u16 = total_path_attribute_length.try_into().unwrap();
+        let nlri_length: usize = network_layer_reachability_information.iter().map(|r| r.decode().len()).sum();
         let nlri_length: u16 = nlri_length.try_into().unwrap();
-        let update_message_length = total_path_attributes_length
-            + withdrawn_routes_length
+
+        let header_length = 19;
+        let update_message_length = withdrawn_routes_length
+            + total_path_attribute_length
             + 4
             + nlri_length;
         let header = BgpMessageHeader::new(
             header_length + update_message_length,
-             BgpMessageType::Update);
+            BgpMessageType::Update);
         BgpUpdateMessage {
             header,
             withdrawn_routes_length,
+            withdrawn_routes,
+            total_path_attribute_length,
+            path_attributes,
+            network_layer_reachability_information,
+        }
+    }
+
+    pub fn are_created_from_adj_rib_out(adj_rib_out: &AdjRibOut, negotiated_capabilities: &NegotiatedCapabilities) -> Vec<Self> {
+        let mut result = vec![];
+        let to_nlri = |entry: &RoutingInformationEntry| {
+            let path_identifier = if negotiated_capabilities.add_path_send {
+                Some(entry.path_identifier)
+            } else {
+                None
+            };
+            Nlri::new(entry.destnation_address, path_identifier)
+        };
+        // 1つのUPDATE messageがnegotiateされた最大長を超えないように分割する
+        // header(19) + withdrawn routes length(2) + total path attribute length(2)
+        let max_message_length = negotiated_capabilities.get_max_message_length();
+        let fixed_length = 19 + 2 + 2;
+        let withdrawn_routes: Vec<Nlri> = adj_rib_out.0.iter()
+            .filter(|entry| entry.status == RoutingInformationStatus::Withdrawn)
+            .map(to_nlri)
+            .collect();
+        for withdrawn_routes in Self::pack_routes(withdrawn_routes, max_message_length - fixed_length) {
+            result.push(Self::new(withdrawn_routes, vec![], vec![]));
+        }
+
+        // path attributeが同じrouteごとに1つのUPDATE messageにまとめる
+        let mut path_attributes_and_routes: Vec<(Vec<PathAttribute>, Vec<Nlri>)> = vec![];
+        for entry in &adj_rib_out.0 {
+            if entry.status != RoutingInformationStatus::Updated {
+                continue;
+            }
+            match path_attributes_and_routes.iter_mut().find(|(p, _)| *p == entry.path_attributes) {
+                Some((_, routes)) => routes.push(to_nlri(entry)),
+                None => path_attributes_and_routes.push((entry.path_attributes.clone(), vec![to_nlri(entry)])),
+            }
+        }
+        for (path_attributes, routes) in path_attributes_and_routes {
+            let path_attributes_length: usize = path_attributes.iter().map(|p| p.decode().len()).sum();
+            let available_length = (max_message_length - fixed_length).saturating_sub(path_attributes_length);
+            for routes in Self::pack_routes(routes, available_length) {
+                result.push(Self::new(vec![], path_attributes.clone(), routes));
+            }
+        }
+        result
+    }
+
+    fn pack_routes(routes: Vec<Nlri>, available_length: usize) -> Vec<Vec<Nlri>> {
+        // routesをそれぞれのバイト長の合計がavailable_length以下になるように分ける
+        // (1つのrouteだけで超える場合はそのrouteだけのグループにする)
+        let mut result: Vec<Vec<Nlri>> = vec![];
+        let mut packed_routes = vec![];
+        let mut packed_length = 0;
+        for route in routes {
+            let route_length = route.decode().len();
+            if !packed_routes.is_empty() && packed_length + route_length > available_length {
+                result.push(packed_routes);
+                packed_routes = vec![];
+                packed_length = 0;
+            }
+            packed_length += route_length;
+            packed_routes.push(route);
+        }
+        if !packed_routes.is_empty() {
+            result.push(packed_routes);
+        }
+        result
+    }
+
+    pub fn new_end_of_rib(address_family_identifier: u16, subsequent_address_family_identifier: u8) -> Self {
+        // RFC4724: IPv4 unicastのEnd-of-RIBは中身が空のUPDATE messageで、
+        // それ以外のaddress familyは中身が空のMP_UNREACH_NLRIだけを持つUPDATE message
+        if (address_family_identifier, subsequent_address_family_identifier) == (1, 1) {
+            return Self::new(vec![], vec![], vec![]);
+        }
+        let mp_unreach_nlri = PathAttribute::MpUnreachNlri {
+            address_family_identifier,
+            subsequent_address_family_identifier,
             withdrawn_routes: vec![],
-            total_path_attribute_length: total_path_attributes_length,
-            path_attributes: path_attributes,
-            network_layer_reachability_information: advertise_route_ip_prefixes,
+        };
+        Self::new(vec![], vec![mp_unreach_nlri], vec![])
+    }
+
+    pub fn get_end_of_rib_address_family(&self) -> Option<(u16, u8)> {
+        // End-of-RIBであればそのaddress family (AFI, SAFI) を返す
+        if !self.withdrawn_routes.is_empty() || !self.network_layer_reachability_information.is_empty() {
+            return None;
         }
+        match &self.path_attributes[..] {
+            [] => Some((1, 1)),
+            [PathAttribute::MpUnreachNlri { address_family_identifier, subsequent_address_family_identifier, withdrawn_routes }]
+                if withdrawn_routes.is_empty() => Some((*address_family_identifier, *subsequent_address_family_identifier)),
+            _ => None,
+        }
+    }
+
+    pub fn get_withdrawn_routes(&self) -> &Vec<Nlri> {
+        &self.withdrawn_routes
+    }
+
+    pub fn get_network_layer_reachability_information(&self) -> &Vec<Nlri> {
+        &self.network_layer_reachability_information
     }
 
     pub fn decode(&self) -> Vec<u8> {
         let mut header_bytes = self.header.decode_to_u8();
         let withdrawn_length = self.withdrawn_routes_length.to_be_bytes();
-        let mut withdrawn_routes: Vec<u8> = vec![]; // ToDo: Withdrawn routesに対応しておく。
+        let mut withdrawn_routes: Vec<u8> = vec![];
+        for i in &self.withdrawn_routes {
+            let mut ip_prefix_byte = i.decode();
+            withdrawn_routes.append(&mut ip_prefix_byte);
+        }
         let total_path_attribute_length = self.total_path_attribute_length.to_be_bytes();
         let mut path_attributes = vec![];
         for p in &self.path_attributes {
@@ -245,55 +408,86 @@ impl BgpUpdateMessage {
         result.append(&mut total_path_attribute_length.to_vec());
         result.append(&mut path_attributes);
         result.append(&mut ip_prefix);
-        println!("{:?}", result);
+        debug!("{:?}", result);
         result
     }
 
-    pub fn encode(raw_data: &Vec<u8>) -> Self {
+    pub fn encode(raw_data: &Vec<u8>, negotiated_capabilities: &NegotiatedCapabilities) -> Result<Self, UpdateMessageError> {
         let header = BgpMessageHeader::encode_from_u8(raw_data);
-        println!("header {:?}", header);
+        debug!("header {:?}", header);
+        let add_path = negotiated_capabilities.add_path_receive;
         let withdrawn_routes_length = u16::from_be_bytes(raw_data[19..21].try_into().unwrap());
-        println!("withdrawn_routes_lenght: {}", withdrawn_routes_length);
-        let end_of_withdrawn_routes = 21 + withdrawn_routes_length;
-        let withdrawn_routes = Self::encode_routes(&raw_data[21..end_of_withdrawn_routes.into()].to_vec());
-        println!("withdrawn_routes: {:?}", withdrawn_routes);
-        let end_of_withdrawn_routes_usize = end_of_withdrawn_routes.try_into().unwrap();
+        debug!("withdrawn_routes_lenght: {}", withdrawn_routes_length);
+        // u16のまま足すとwithdrawn routes lengthが大きいときにoverflowする
+        let end_of_withdrawn_routes_usize = 21 + usize::from(withdrawn_routes_length);
+        if end_of_withdrawn_routes_usize + 2 > raw_data.len() {
+            // RFC4271 6.3: withdrawn routes lengthがmessageに収まらない
+            return Err(UpdateMessageError::MalformedAttributeList);
+        }
+        let mut withdrawn_routes = Self::encode_routes(&raw_data[21..end_of_withdrawn_routes_usize].to_vec(), add_path)?;
+        debug!("withdrawn_routes: {:?}", withdrawn_routes);
         let total_path_attribute_length = u16::from_be_bytes(
             raw_data[end_of_withdrawn_routes_usize..end_of_withdrawn_routes_usize+2].try_into().unwrap());
-        println!("total_path_attribute_length: {}", total_path_attribute_length);
+        debug!("total_path_attribute_length: {}", total_path_attribute_length);
         let start_of_path_attributes = end_of_withdrawn_routes_usize + 2;
         let total_path_attribute_length_usize :usize = total_path_attribute_length.into();
         let end_of_path_attributes :usize  = start_of_path_attributes + total_path_attribute_length_usize;
-        println!("path_attributes_bytes: {:?}", raw_data[start_of_path_attributes..end_of_path_attributes].to_vec());
-        let path_attributes = Self::encode_path_attributes(&raw_data[start_of_path_attributes..end_of_path_attributes].to_vec());
-        println!("path attributes: {:?}", path_attributes);
+        if end_of_path_attributes > raw_data.len() {
+            // RFC4271 6.3: total path attribute lengthがmessageに収まらない
+            return Err(UpdateMessageError::MalformedAttributeList);
+        }
+        debug!("path_attributes_bytes: {:?}", raw_data[start_of_path_attributes..end_of_path_attributes].to_vec());
+        let (path_attributes, treat_as_withdraw) = Self::encode_path_attributes(&raw_data[start_of_path_attributes..end_of_path_attributes].to_vec())?;
+        debug!("path attributes: {:?}", path_attributes);
         let start_of_nlri = end_of_path_attributes;
-        println!("nlri bytes: {:?}", &raw_data[start_of_nlri.into()..].to_vec());
-        let network_layer_reachability_information = Self::encode_routes(&raw_data[start_of_nlri.into()..].to_vec());
-        println!("network_layer_reachability_information: {:?}", path_attributes);
+        debug!("nlri bytes: {:?}", &raw_data[start_of_nlri.into()..].to_vec());
+        let mut network_layer_reachability_information = Self::encode_routes(&raw_data[start_of_nlri.into()..].to_vec(), add_path)?;
+        debug!("network_layer_reachability_information: {:?}", path_attributes);
+        // RFC7606 3 (d): well-known mandatoryのattributeが無いrouteもtreat-as-withdraw
+        let treat_as_withdraw = treat_as_withdraw
+            || (!network_layer_reachability_information.is_empty() && !Self::has_mandatory_attributes(&path_attributes));
+        if treat_as_withdraw {
+            // RFC7606 2: このUPDATEで広告されたrouteは全てwithdrawされたものとして扱う
+            warn!("treat as withdraw the update with malformed attribute: {:?}", network_layer_reachability_information);
+            withdrawn_routes.append(&mut network_layer_reachability_information);
+        }
 
-        Self {
+        Ok(Self {
             header,
             withdrawn_routes_length,
             withdrawn_routes,
             total_path_attribute_length,
             path_attributes,
             network_layer_reachability_information
-        }
+        })
     }
 
-    fn encode_path_attributes(raw_data: &Vec<u8>) -> Vec<PathAttribute> {
-        // path attributeのところだけを渡す
+    fn has_mandatory_attributes(path_attributes: &[PathAttribute]) -> bool {
+        // RFC4271 5: NLRIを広告するUPDATEにはORIGIN, AS_PATH, NEXT_HOPが必須
+        let has_origin = path_attributes.iter().any(|p| matches!(p, PathAttribute::Origin(_)));
+        let has_as_path = path_attributes.iter().any(|p| matches!(p, PathAttribute::AsPath(_)));
+        let has_next_hop = path_attributes.iter().any(|p| matches!(p, PathAttribute::NextHop(_)));
+        has_origin && has_as_path && has_next_hop
+    }
+
+    fn encode_path_attributes(raw_data: &Vec<u8>) -> Result<(Vec<PathAttribute>, bool), UpdateMessageError> {
+        // path attributeのところだけを渡す。
+        // treat-as-withdrawにするattributeがあれば2つ目にtrueを返す
         let mut result = vec![];
+        let mut treat_as_withdraw = false;
         let mut i = 0;
         while i < raw_data.len() {
             let path_attribute_flag = raw_data[i];
-            let path_attribute_type = raw_data[i+1];
             let number_of_octates_path_attribute_length = if 0b00010000 & path_attribute_flag == 16 {
                 2
             } else {
                 1
             };
+            if i + 2 + number_of_octates_path_attribute_length > raw_data.len() {
+                // RFC7606 4: attributeのtypeとlengthを読めなければ残りのattributeも読めない
+                return Err(UpdateMessageError::MalformedAttributeList);
+            }
+            let path_attribute_type = raw_data[i+1];
             let path_attribute_length: u16 = if 0b00010000 & path_attribute_flag == 16 {
                 u16::from_be_bytes(raw_data[i+2..i+4].try_into().unwrap())
             } else {
@@ -302,20 +496,48 @@ impl BgpUpdateMessage {
             let start_of_path_attrtibute_value = i + 2 + number_of_octates_path_attribute_length;
             let path_attribute_length_usize :usize = path_attribute_length.into();
             let end_of_path_attribute_value = start_of_path_attrtibute_value + path_attribute_length_usize;
+            if end_of_path_attribute_value > raw_data.len() {
+                // attribute lengthがpath attributesの残りより長い
+                return Err(UpdateMessageError::AttributeLengthError(raw_data[i..].to_vec()));
+            }
             let path_attribute_value = &raw_data[start_of_path_attrtibute_value..end_of_path_attribute_value];
+            let path_attribute = match PathAttribute::encode(path_attribute_flag, path_attribute_type, path_attribute_length, path_attribute_value.to_vec()) {
+                Ok(path_attribute) => path_attribute,
+                Err(MalformedAttributeError::AttributeLength) =>
+                    return Err(UpdateMessageError::AttributeLengthError(raw_data[i..end_of_path_attribute_value].to_vec())),
+                Err(MalformedAttributeError::TreatAsWithdraw) => {
+                    treat_as_withdraw = true;
+                    PathAttribute::DontKnow(path_attribute_value.to_vec())
+                },
+                Err(MalformedAttributeError::AttributeDiscard) => {
+                    // RFC7606 2: attributeだけを捨ててrouteは受け入れる
+                    warn!("discard the malformed attribute: {:?}", raw_data[i..end_of_path_attribute_value].to_vec());
+                    i = end_of_path_attribute_value;
+                    continue;
+                },
+            };
             i = end_of_path_attribute_value;
-
-            let path_attribute = PathAttribute::encode(path_attribute_flag, path_attribute_type, path_attribute_length, path_attribute_value.to_vec());
             result.push(path_attribute);
         }
-        result
+        Ok((result, treat_as_withdraw))
     }
 
-    fn encode_routes(raw_data: &Vec<u8>) -> Vec<IpPrefix> {
-        // withdrawn_routesやnetwork_layer_reachability_informationだけを渡す
+    fn encode_routes(raw_data: &Vec<u8>, add_path: bool) -> Result<Vec<Nlri>, UpdateMessageError> {
+        // withdrawn_routesやnetwork_layer_reachability_informationだけを渡す。
+        // RFC7606 5.3: prefixを読めなければ他のrouteも読めないので、treat-as-withdrawにはできずsessionをresetする
         let mut result = vec![];
         let mut i = 0;
         while i < raw_data.len() {
+            let path_identifier = if add_path {
+                if i + 4 >= raw_data.len() {
+                    return Err(UpdateMessageError::InvalidNetworkField);
+                }
+                let path_identifier = u32::from_be_bytes(raw_data[i..i+4].try_into().unwrap());
+                i += 4;
+                Some(path_identifier)
+            } else {
+                None
+            };
             let prefix_length = raw_data[i];
             // number_of_octatesはprefix_lengthが
             // 0 -> 0
@@ -329,14 +551,17 @@ impl BgpUpdateMessage {
                 9..17 => 2,
                 17..25 => 3,
                 25..33 => 4,
-                _ => panic!("prefix_length is wrong!"),
+                _ => return Err(UpdateMessageError::InvalidNetworkField),
             };
+            if i + 1 + number_of_octates > raw_data.len() {
+                return Err(UpdateMessageError::InvalidNetworkField);
+            }
             let ipaddr = &raw_data[i..i+number_of_octates+1];
             let ip_prefix = IpPrefix::encode(&ipaddr.to_vec());
-            result.push(ip_prefix);
+            result.push(Nlri::new(ip_prefix, path_identifier));
             i += 1 + number_of_octates;
         }
-        result
+        Ok(result)
     }
 }
 
@@ -347,7 +572,7 @@ struct Interface;
 fn lookup_routing_table(network: &IpPrefix) -> (Ipv4Addr, Interface) {
     (Ipv4Addr::from_str("192.168.2.5").unwrap(), Interface)
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Origin {
     Igp,
     Egp,
@@ -363,57 +588,192 @@ impl Origin {
         }
     }
 }
-#[derive(Debug, Clone)]
-pub enum AsPath {
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsPathSegment {
     AsSet(Vec<u16>),
     AsSequence(Vec<u16>),
+    AsConfedSequence(Vec<u16>), // Confederation(RFC5065)でつかう
+    AsConfedSet(Vec<u16>), // Confederation(RFC5065)でつかう
 }
 
-impl AsPath {
-    pub fn value(&self) -> Vec<u8> {
-        match &self {
-            &AsPath::AsSet(v) => {
-                let path_segment_type: u8 = 1;
-                let path_segment_length: u8 = v.len().try_into().unwrap();
-                let mut result = vec![path_segment_type, path_segment_length];
-                for i in v.iter() {
-                    let bytes = i.to_be_bytes();
-                    for j in bytes.iter() {
-                        result.push(*j);
-                    }
-                }
-                result
-            },
-            &AsPath::AsSequence(v) => {
-                let path_segment_type: u8 = 1;
-                let path_segment_length: u8 = v.len().try_into().unwrap();
-                let mut result = vec![path_segment_type, path_segment_length];
-                for i in v.iter() {
-                    let bytes = i.to_be_bytes();
-                    for j in bytes.iter() {
-                        result.push(*j);
-                    }
-                }
-                result
-            },
+impl AsPathSegment {
+    fn segment_type(&self) -> u8 {
+        match self {
+            AsPathSegment::AsSet(_) => 1,
+            AsPathSegment::AsSequence(_) => 2,
+            AsPathSegment::AsConfedSequence(_) => 3,
+            AsPathSegment::AsConfedSet(_) => 4,
         }
     }
 
     pub fn get_seq(&self) -> &Vec<u16> {
         match self {
-            AsPath::AsSequence(v) => v,
-            AsPath::AsSet(v) => v,
+            AsPathSegment::AsSet(v) => v,
+            AsPathSegment::AsSequence(v) => v,
+            AsPathSegment::AsConfedSequence(v) => v,
+            AsPathSegment::AsConfedSet(v) => v,
+        }
+    }
+
+    pub fn is_confederation_segment(&self) -> bool {
+        match self {
+            AsPathSegment::AsConfedSequence(_) | AsPathSegment::AsConfedSet(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn value(&self) -> Vec<u8> {
+        // segment lengthは1 byteなので、255個より多いAS番号は同じtypeのsegmentに分ける
+        let path_segment_type: u8 = self.segment_type();
+        if self.get_seq().is_empty() {
+            return vec![path_segment_type, 0];
+        }
+        let mut result = vec![];
+        for as_numbers in self.get_seq().chunks(MAX_AS_PATH_SEGMENT_LENGTH) {
+            result.push(path_segment_type);
+            result.push(as_numbers.len() as u8);
+            for i in as_numbers.iter() {
+                result.append(&mut i.to_be_bytes().to_vec());
+            }
+        }
+        result
+    }
+}
+
+// segment lengthは1 byteなので、1つのsegmentに入れられるAS番号の数
+const MAX_AS_PATH_SEGMENT_LENGTH: usize = 255;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsPath(pub Vec<AsPathSegment>);
+
+impl AsPath {
+    pub fn new(segments: Vec<AsPathSegment>) -> Self {
+        AsPath(segments)
+    }
+
+    pub fn value(&self) -> Vec<u8> {
+        let mut result = vec![];
+        for segment in &self.0 {
+            result.append(&mut segment.value());
+        }
+        result
+    }
+
+    pub fn encode(raw_data: &Vec<u8>) -> Self {
+        // AS_PATHのattribute valueだけを渡す
+        // (<segment type>: u8, <segment length>: u8, <AS number>: u16 * segment length) の繰り返し
+        let mut segments = vec![];
+        let mut i = 0;
+        while i + 2 <= raw_data.len() {
+            let path_segment_type = raw_data[i];
+            let path_segment_length: usize = raw_data[i+1].into();
+            let mut as_numbers = vec![];
+            let mut j = i + 2;
+            while j + 2 <= raw_data.len() && as_numbers.len() < path_segment_length {
+                as_numbers.push(u16::from_be_bytes(raw_data[j..j+2].try_into().unwrap()));
+                j += 2;
+            }
+            i = j;
+            let segment = match path_segment_type {
+                1 => AsPathSegment::AsSet(as_numbers),
+                3 => AsPathSegment::AsConfedSequence(as_numbers),
+                4 => AsPathSegment::AsConfedSet(as_numbers),
+                _ => AsPathSegment::AsSequence(as_numbers),
+            };
+            segments.push(segment);
+        }
+        AsPath(segments)
+    }
+
+    pub fn get_seq(&self) -> Vec<u16> {
+        // 全てのsegmentのAS番号を順番に並べたもの
+        let mut result = vec![];
+        for segment in &self.0 {
+            result.append(&mut segment.get_seq().clone());
+        }
+        result
+    }
+
+    pub fn get_neighbor_as_number(&self) -> Option<u16> {
+        // RFC4271 9.1.2.2 c): 隣のASはAS_PATHの先頭のAS_SEQUENCEの最初のAS
+        // RFC5065 5.3: confederationのsegmentは飛ばす
+        for segment in &self.0 {
+            match segment {
+                AsPathSegment::AsSequence(v) => return v.first().copied(),
+                AsPathSegment::AsSet(_) => return None,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn path_length(&self) -> usize {
+        // RFC4271 9.1.2.2: AS_SETは1つとして数える
+        // RFC5065 5.3: confederationのsegmentは数えない
+        let mut length = 0;
+        for segment in &self.0 {
+            length += match segment {
+                AsPathSegment::AsSequence(v) => v.len(),
+                AsPathSegment::AsSet(_) => 1,
+                _ => 0,
+            };
+        }
+        length
+    }
+
+    pub fn get_origin_as(&self) -> Option<u16> {
+        // RFC6811 2: 最後のsegmentがAS_SEQUENCEならその最後のAS番号、
+        // AS_SETなどならorigin ASは決まらない (confederationのsegmentは無視する)
+        match self.0.iter().filter(|segment| !segment.is_confederation_segment()).last() {
+            Some(AsPathSegment::AsSequence(v)) => v.last().copied(),
+            _ => None,
         }
     }
 
     pub fn does_have_the_as_number(&self, as_number: &AutonomousSystemNumber) -> bool {
-        match &self {
-            &AsPath::AsSequence(as_sec) => as_sec.contains(&as_number.0),
-            &AsPath::AsSet(as_set) => as_set.contains(&as_number.0),
+        self.get_seq().contains(&as_number.0)
+    }
+
+    pub fn prepend(&mut self, as_number: u16) {
+        // RFC4271 5.1.2: 先頭のAS_SEQUENCEが一杯なら新しいAS_SEQUENCEを作る
+        match self.0.first_mut() {
+            Some(AsPathSegment::AsSequence(v)) if v.len() < MAX_AS_PATH_SEGMENT_LENGTH => v.insert(0, as_number),
+            _ => self.0.insert(0, AsPathSegment::AsSequence(vec![as_number])),
+        }
+    }
+
+    pub fn prepend_confederation(&mut self, as_number: u16) {
+        match self.0.first_mut() {
+            Some(AsPathSegment::AsConfedSequence(v)) if v.len() < MAX_AS_PATH_SEGMENT_LENGTH => v.insert(0, as_number),
+            _ => self.0.insert(0, AsPathSegment::AsConfedSequence(vec![as_number])),
         }
     }
+
+    pub fn remove_confederation_segments(&mut self) {
+        self.0.retain(|segment| !segment.is_confederation_segment());
+    }
 }
-#[derive(Debug, Clone)]
+
+impl fmt::Display for AsPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // AS_SEQUENCEは"64512 64513"、AS_SETは"{64512 64513}"、
+        // AS_CONFED_SEQUENCEは"(65001 65002)"、AS_CONFED_SETは"[65001 65002]"のように表す
+        let mut segments = vec![];
+        for segment in &self.0 {
+            let as_numbers: Vec<String> = segment.get_seq().iter().map(|as_number| as_number.to_string()).collect();
+            let as_numbers = as_numbers.join(" ");
+            segments.push(match segment {
+                AsPathSegment::AsSequence(_) => as_numbers,
+                AsPathSegment::AsSet(_) => format!("{{{}}}", as_numbers),
+                AsPathSegment::AsConfedSequence(_) => format!("({})", as_numbers),
+                AsPathSegment::AsConfedSet(_) => format!("[{}]", as_numbers),
+            });
+        }
+        write!(f, "{}", segments.join(" "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PathAttribute {
     // PathAttributeのバイト列の表現は以下の通り
     // (<PathAttribute Type>, <attribute length>, <attribute value>)
@@ -431,9 +791,19 @@ pub enum PathAttribute {
     Origin(Origin),
     AsPath(AsPath),
     NextHop(Ipv4Addr),
-    LocalPref, // EBGPではつかわない
-    AtomicAggregate, // 実装は後でで良い
-    Aggregator, // 実装は後でで良い
+    MultiExitDisc(u32),
+    LocalPref(u32), // EBGPではつかわない
+    AtomicAggregate, // 集約でAS_PATHの情報が失われたことを示す
+    Aggregator(u16, Ipv4Addr), // 集約したrouterの(<AS番号>, <BGP Identifier>)
+    OriginatorId(Ipv4Addr), // Route Reflector(RFC4456)でつかう
+    ClusterList(Vec<Ipv4Addr>), // Route Reflector(RFC4456)でつかう
+    Communities(Vec<u32>), // RFC1997, (<AS番号>: u16, <値>: u16)を1つのu32として持つ
+    OnlyToCustomer(u32), // RFC9234, customerにだけ広告してよいrouteに付けるAS番号
+    MpUnreachNlri { // RFC4760, IPv4 unicast以外のEnd-of-RIBの検出にだけつかう
+        address_family_identifier: u16,
+        subsequent_address_family_identifier: u8,
+        withdrawn_routes: Vec<u8>,
+    },
     DontKnow(Vec<u8>), // 不明なやつ
 }
 
@@ -449,11 +819,7 @@ impl PathAttribute {
             &PathAttribute::AsPath(as_path) => {
                 let attribute_flag: u8 = 0b01000000;
                 let attribute_type_code = 2;
-                let mut attribute_value = as_path.value();
-                let attribute_length: u8 = attribute_value.len().try_into().unwrap();
-                let mut result = vec![attribute_flag, attribute_type_code, attribute_length];
-                result.append(&mut attribute_value);
-                result
+                Self::with_attribute_length(attribute_flag, attribute_type_code, as_path.value())
             },
             &PathAttribute::NextHop(next_hop) => {
                 let attribute_flag: u8 = 0b01000000;
@@ -464,37 +830,204 @@ impl PathAttribute {
                 result.append(&mut attribute_value);
                 result
             },
+            &PathAttribute::MultiExitDisc(multi_exit_disc) => {
+                let attribute_flag: u8 = 0b10000000;
+                let attribute_type_code :u8 = 4;
+                let attribute_length :u8 = 4;
+                let mut attribute_value = multi_exit_disc.to_be_bytes().to_vec();
+                let mut result = vec![attribute_flag, attribute_type_code, attribute_length];
+                result.append(&mut attribute_value);
+                result
+            },
+            &PathAttribute::LocalPref(local_pref) => {
+                let attribute_flag: u8 = 0b01000000;
+                let attribute_type_code :u8 = 5;
+                let attribute_length :u8 = 4;
+                let mut attribute_value = local_pref.to_be_bytes().to_vec();
+                let mut result = vec![attribute_flag, attribute_type_code, attribute_length];
+                result.append(&mut attribute_value);
+                result
+            },
+            &PathAttribute::AtomicAggregate => {
+                let attribute_flag: u8 = 0b01000000;
+                let attribute_type_code :u8 = 6;
+                let attribute_length :u8 = 0;
+                vec![attribute_flag, attribute_type_code, attribute_length]
+            },
+            &PathAttribute::Aggregator(as_number, router_id) => {
+                let attribute_flag: u8 = 0b11000000;
+                let attribute_type_code :u8 = 7;
+                let attribute_length :u8 = 6;
+                let mut attribute_value = as_number.to_be_bytes().to_vec();
+                attribute_value.append(&mut router_id.octets().to_vec());
+                let mut result = vec![attribute_flag, attribute_type_code, attribute_length];
+                result.append(&mut attribute_value);
+                result
+            },
+            &PathAttribute::OriginatorId(originator_id) => {
+                let attribute_flag: u8 = 0b10000000;
+                let attribute_type_code :u8 = 9;
+                let attribute_length :u8 = 4;
+                let mut attribute_value = originator_id.octets().to_vec();
+                let mut result = vec![attribute_flag, attribute_type_code, attribute_length];
+                result.append(&mut attribute_value);
+                result
+            },
+            &PathAttribute::ClusterList(cluster_list) => {
+                let attribute_flag: u8 = 0b10000000;
+                let attribute_type_code :u8 = 10;
+                let mut attribute_value = vec![];
+                for cluster_id in cluster_list {
+                    attribute_value.append(&mut cluster_id.octets().to_vec());
+                }
+                Self::with_attribute_length(attribute_flag, attribute_type_code, attribute_value)
+            },
+            &PathAttribute::Communities(communities) => {
+                let attribute_flag: u8 = 0b11010000;
+                let attribute_type_code :u8 = 8;
+                let mut attribute_value = vec![];
+                for community in communities {
+                    attribute_value.append(&mut community.to_be_bytes().to_vec());
+                }
+                let attribute_length: u16 = attribute_value.len().try_into().unwrap();
+                let mut result = vec![attribute_flag, attribute_type_code];
+                result.append(&mut attribute_length.to_be_bytes().to_vec());
+                result.append(&mut attribute_value);
+                result
+            },
+            &PathAttribute::OnlyToCustomer(as_number) => {
+                let attribute_flag: u8 = 0b11000000;
+                let attribute_type_code :u8 = 35;
+                let attribute_length :u8 = 4;
+                let mut attribute_value = as_number.to_be_bytes().to_vec();
+                let mut result = vec![attribute_flag, attribute_type_code, attribute_length];
+                result.append(&mut attribute_value);
+                result
+            },
+            &PathAttribute::MpUnreachNlri { address_family_identifier, subsequent_address_family_identifier, withdrawn_routes } => {
+                let attribute_flag: u8 = 0b10010000;
+                let attribute_type_code :u8 = 15;
+                let mut attribute_value = address_family_identifier.to_be_bytes().to_vec();
+                attribute_value.push(*subsequent_address_family_identifier);
+                attribute_value.append(&mut withdrawn_routes.clone());
+                let attribute_length: u16 = attribute_value.len().try_into().unwrap();
+                let mut result = vec![attribute_flag, attribute_type_code];
+                result.append(&mut attribute_length.to_be_bytes().to_vec());
+                result.append(&mut attribute_value);
+                result
+            },
             _ => vec![],
         }
     }
-    pub fn encode(attribute_flag: u8, attribute_type: u8, attribute_length: u16, attribute_value: Vec<u8>) -> Self {
+    fn with_attribute_length(attribute_flag: u8, attribute_type_code: u8, mut attribute_value: Vec<u8>) -> Vec<u8> {
+        // 長さが変わるattributeは、255バイトを超えたらExtended Lengthのflagを立ててlengthを2バイトにする
+        let mut result = vec![attribute_flag, attribute_type_code];
+        if attribute_value.len() <= 255 {
+            result.push(attribute_value.len() as u8);
+        } else {
+            let attribute_length: u16 = attribute_value.len().try_into().unwrap();
+            result[0] |= 0b00010000;
+            result.append(&mut attribute_length.to_be_bytes().to_vec());
+        }
+        result.append(&mut attribute_value);
+        result
+    }
+
+    fn check_length(attribute_type: u8, attribute_length: usize) -> Result<(), MalformedAttributeError> {
+        // 長さが正しくないattributeの扱いはRFC7606 7に従う
+        let is_valid = match attribute_type {
+            1 => attribute_length == 1, // ORIGIN
+            3 | 4 | 5 | 9 | 35 => attribute_length == 4, // NEXT_HOP, MULTI_EXIT_DISC, LOCAL_PREF, ORIGINATOR_ID, OTC(RFC9234 5)
+            8 | 10 => attribute_length.is_multiple_of(4), // COMMUNITIES, CLUSTER_LIST
+            6 => attribute_length == 0, // ATOMIC_AGGREGATE
+            7 => attribute_length == 6, // AGGREGATOR
+            15 => attribute_length >= 3, // MP_UNREACH_NLRI
+            _ => true,
+        };
+        if is_valid {
+            return Ok(());
+        }
         match attribute_type {
+            // routeの選択に使わないattributeは捨てるだけでよい
+            6 | 7 => Err(MalformedAttributeError::AttributeDiscard),
+            // AFIとSAFIが無いMP_UNREACH_NLRIはどのrouteをwithdrawするかわからないのでsessionをresetする
+            15 => Err(MalformedAttributeError::AttributeLength),
+            _ => Err(MalformedAttributeError::TreatAsWithdraw),
+        }
+    }
+
+    pub fn encode(attribute_flag: u8, attribute_type: u8, attribute_length: u16, attribute_value: Vec<u8>) -> Result<Self, MalformedAttributeError> {
+        // 誤っているattributeはErrを返し、呼び出し元がRFC7606に従ってtreat-as-withdrawなどにする
+        Self::check_length(attribute_type, attribute_value.len())?;
+        let path_attribute = match attribute_type {
             1 => {
                 let origin = match attribute_value[0] {
                     0 => Origin::Igp,
                     1 => Origin::Egp,
                     2 => Origin::Incompleted,
-                    _ => panic!(),
+                    // RFC7606 7.1: 未定義のORIGINはtreat-as-withdraw
+                    _ => return Err(MalformedAttributeError::TreatAsWithdraw),
                 };
                 PathAttribute::Origin(origin)
             },
             2 => {
-                let mut as_sequence = vec![];
-                let mut i = 2;
-                while i < attribute_value.len() {
-                    let as_number = u16::from_be_bytes(attribute_value[i..i+2].try_into().unwrap());
-                    i += 2;
-                    as_sequence.push(as_number);
-                };
-                let as_path = AsPath::AsSequence(as_sequence);
+                let as_path = AsPath::encode(&attribute_value);
                 PathAttribute::AsPath(as_path)
             },
             3 => {
                 let ip_addr = Ipv4Addr::new(attribute_value[0], attribute_value[1], attribute_value[2], attribute_value[3]);
                 PathAttribute::NextHop(ip_addr)
             },
+            4 => {
+                let multi_exit_disc = u32::from_be_bytes(attribute_value[0..4].try_into().unwrap());
+                PathAttribute::MultiExitDisc(multi_exit_disc)
+            },
+            5 => {
+                let local_pref = u32::from_be_bytes(attribute_value[0..4].try_into().unwrap());
+                PathAttribute::LocalPref(local_pref)
+            },
+            6 => PathAttribute::AtomicAggregate,
+            7 => {
+                let as_number = u16::from_be_bytes(attribute_value[0..2].try_into().unwrap());
+                let router_id = Ipv4Addr::new(attribute_value[2], attribute_value[3], attribute_value[4], attribute_value[5]);
+                PathAttribute::Aggregator(as_number, router_id)
+            },
+            9 => {
+                let originator_id = Ipv4Addr::new(attribute_value[0], attribute_value[1], attribute_value[2], attribute_value[3]);
+                PathAttribute::OriginatorId(originator_id)
+            },
+            10 => {
+                let mut cluster_list = vec![];
+                let mut i = 0;
+                while i + 4 <= attribute_value.len() {
+                    cluster_list.push(Ipv4Addr::new(attribute_value[i], attribute_value[i+1], attribute_value[i+2], attribute_value[i+3]));
+                    i += 4;
+                }
+                PathAttribute::ClusterList(cluster_list)
+            },
+            8 => {
+                let mut communities = vec![];
+                let mut i = 0;
+                while i + 4 <= attribute_value.len() {
+                    communities.push(u32::from_be_bytes(attribute_value[i..i+4].try_into().unwrap()));
+                    i += 4;
+                }
+                PathAttribute::Communities(communities)
+            },
+            35 => {
+                let as_number = u32::from_be_bytes(attribute_value[0..4].try_into().unwrap());
+                PathAttribute::OnlyToCustomer(as_number)
+            },
+            15 => {
+                PathAttribute::MpUnreachNlri {
+                    address_family_identifier: u16::from_be_bytes(attribute_value[0..2].try_into().unwrap()),
+                    subsequent_address_family_identifier: attribute_value[2],
+                    withdrawn_routes: attribute_value[3..].to_vec(),
+                }
+            },
             _ => PathAttribute::DontKnow(attribute_value)
-        }
+        };
+        Ok(path_attribute)
     }
 }
 
@@ -513,19 +1046,196 @@ impl BgpKeepaliveMessage {
     }
 }
 
+pub struct BgpRouteRefreshMessage {
+    header: BgpMessageHeader,
+    address_family_identifier: u16,
+    subtype: RouteRefreshSubtype,
+    subsequent_address_family_identifier: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteRefreshSubtype {
+    Request, // RFC2918ではReservedで0
+    BeginningOfRouteRefresh, // RFC7313
+    EndOfRouteRefresh, // RFC7313
+}
+
+impl BgpRouteRefreshMessage {
+    pub fn new(subtype: RouteRefreshSubtype) -> Self {
+        // ToDo: IPv4 unicast以外のaddress familyに対応する
+        let header = BgpMessageHeader::new(23, BgpMessageType::RouteRefresh);
+        Self {
+            header,
+            address_family_identifier: 1,
+            subtype,
+            subsequent_address_family_identifier: 1,
+        }
+    }
+
+    pub fn encode(raw_data: &Vec<u8>) -> Option<Self> {
+        // RFC7313 5: 知らないsubtypeのmessageは無視するのでNoneを返す
+        let header = BgpMessageHeader::encode_from_u8(raw_data);
+        let address_family_identifier = u16::from_be_bytes(raw_data[19..21].try_into().unwrap());
+        let subtype = match raw_data[21] {
+            0 => RouteRefreshSubtype::Request,
+            1 => RouteRefreshSubtype::BeginningOfRouteRefresh,
+            2 => RouteRefreshSubtype::EndOfRouteRefresh,
+            _ => return None,
+        };
+        let subsequent_address_family_identifier = raw_data[22];
+        Some(Self {
+            header,
+            address_family_identifier,
+            subtype,
+            subsequent_address_family_identifier,
+        })
+    }
+
+    pub fn decode(&self) -> Vec<u8> {
+        let mut result = self.header.decode_to_u8();
+        result.append(&mut self.address_family_identifier.to_be_bytes().to_vec());
+        result.push(match self.subtype {
+            RouteRefreshSubtype::Request => 0,
+            RouteRefreshSubtype::BeginningOfRouteRefresh => 1,
+            RouteRefreshSubtype::EndOfRouteRefresh => 2,
+        });
+        result.push(self.subsequent_address_family_identifier);
+        result
+    }
+
+    pub fn get_subtype(&self) -> RouteRefreshSubtype {
+        self.subtype
+    }
+
+    pub fn get_address_family(&self) -> (u16, u8) {
+        (self.address_family_identifier, self.subsequent_address_family_identifier)
+    }
+}
+
 pub struct BgpNotificationMessage{
     header: BgpMessageHeader,
     error_code: BgpErrorCode,
     data: Vec<u8>, // とりあえず
 }
 
+impl BgpNotificationMessage {
+    fn new(error_code: BgpErrorCode, data: Vec<u8>) -> Self {
+        let header_length: u16 = 19;
+        let data_length: u16 = data.len().try_into().unwrap();
+        let header = BgpMessageHeader::new(header_length + 2 + data_length, BgpMessageType::Notification);
+        Self { header, error_code, data }
+    }
+
+    pub fn new_bad_message_length(length: u16) -> Self {
+        // RFC4271: dataには誤っているlength fieldの値をいれる
+        Self::new(
+            BgpErrorCode::MessageHeaderError(MessageHeaderErrorSubcode::BadMessageLength),
+            length.to_be_bytes().to_vec())
+    }
+
+    pub fn new_role_mismatch() -> Self {
+        // RFC9234: dataは空でよい
+        Self::new(BgpErrorCode::OpenMessageError(OpenMessageErrorSubCode::RoleMismatch), vec![])
+    }
+
+    pub fn new_malformed_optional_parameters() -> Self {
+        // RFC4271 6.2: optional parametersの長さがおかしいときは特定のsubcodeが無いので0を使う
+        Self::new(BgpErrorCode::OpenMessageError(OpenMessageErrorSubCode::Unspecific), vec![])
+    }
+
+    pub fn new_maximum_number_of_prefixes_reached(address_family_identifier: u16, subsequent_address_family_identifier: u8, max_prefix: u32) -> Self {
+        // RFC4486: dataには(<AFI>: u16, <SAFI>: u8, <prefixの上限>: u32)をいれる
+        let mut data = address_family_identifier.to_be_bytes().to_vec();
+        data.push(subsequent_address_family_identifier);
+        data.append(&mut max_prefix.to_be_bytes().to_vec());
+        Self::new(BgpErrorCode::Cease(CeaseSubcode::MaximumNumberOfPrefixesReached), data)
+    }
+
+    pub fn new_attribute_length_error(attribute: Vec<u8>) -> Self {
+        // RFC4271 6.3: dataには誤っているattribute (type, length, value)をいれる
+        Self::new(BgpErrorCode::UpdateMessageError(UpdateMessageErrorSubcode::AttributeLengthError), attribute)
+    }
+
+    pub fn new_malformed_attribute_list() -> Self {
+        Self::new(BgpErrorCode::UpdateMessageError(UpdateMessageErrorSubcode::MalformedAttributeList), vec![])
+    }
+
+    pub fn new_invalid_network_field() -> Self {
+        Self::new(BgpErrorCode::UpdateMessageError(UpdateMessageErrorSubcode::InvalidNetworkField), vec![])
+    }
+
+    pub fn new_cease(subcode: CeaseSubcode) -> Self {
+        Self::new(BgpErrorCode::Cease(subcode), vec![])
+    }
+
+    pub fn decode(&self) -> Vec<u8> {
+        let (error_code, error_subcode) = self.error_code.value();
+        let mut result = self.header.decode_to_u8();
+        result.push(error_code);
+        result.push(error_subcode);
+        result.append(&mut self.data.clone());
+        result
+    }
+}
+
 enum BgpErrorCode {
     MessageHeaderError(MessageHeaderErrorSubcode),
     OpenMessageError(OpenMessageErrorSubCode),
     UpdateMessageError(UpdateMessageErrorSubcode),
     HoldTimerExpired,
     FaniteStateMachineError,
-    Cease,
+    Cease(CeaseSubcode),
+}
+
+impl BgpErrorCode {
+    fn value(&self) -> (u8, u8) {
+        // (<error code>, <error subcode>)
+        match self {
+            BgpErrorCode::MessageHeaderError(subcode) => (1, match subcode {
+                MessageHeaderErrorSubcode::ConnectionNotSynchronized => 1,
+                MessageHeaderErrorSubcode::BadMessageLength => 2,
+                MessageHeaderErrorSubcode::BadMessageType => 3,
+            }),
+            BgpErrorCode::OpenMessageError(subcode) => (2, match subcode {
+                OpenMessageErrorSubCode::Unspecific => 0,
+                OpenMessageErrorSubCode::UnsupportedVersionNumber => 1,
+                OpenMessageErrorSubCode::BadPeerAs => 2,
+                OpenMessageErrorSubCode::BadBgpIdentifier => 3,
+                OpenMessageErrorSubCode::UnsupportedOptionalParameter => 4,
+                OpenMessageErrorSubCode::UnacceptableHoldTime => 6,
+                OpenMessageErrorSubCode::RoleMismatch => 11,
+            }),
+            BgpErrorCode::UpdateMessageError(subcode) => (3, match subcode {
+                UpdateMessageErrorSubcode::MalformedAttributeList => 1,
+                UpdateMessageErrorSubcode::UnrecognizedWellKnownAttribute => 2,
+                UpdateMessageErrorSubcode::MissingWellKnownAttribute => 3,
+                UpdateMessageErrorSubcode::AttributeFlagsError => 4,
+                UpdateMessageErrorSubcode::AttributeLengthError => 5,
+                UpdateMessageErrorSubcode::InvalidOriginAttribute => 6,
+                UpdateMessageErrorSubcode::InvalidNextHopAttribute => 8,
+                UpdateMessageErrorSubcode::OptinalAttributeError => 9,
+                UpdateMessageErrorSubcode::InvalidNetworkField => 10,
+                UpdateMessageErrorSubcode::MalformedAsPath => 11,
+            }),
+            BgpErrorCode::HoldTimerExpired => (4, 0),
+            BgpErrorCode::FaniteStateMachineError => (5, 0),
+            BgpErrorCode::Cease(subcode) => (6, match subcode {
+                CeaseSubcode::MaximumNumberOfPrefixesReached => 1,
+                CeaseSubcode::AdministrativeShutdown => 2,
+                CeaseSubcode::PeerDeconfigured => 3,
+                CeaseSubcode::AdministrativeReset => 4,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CeaseSubcode {
+    // RFC4486
+    MaximumNumberOfPrefixesReached,
+    AdministrativeShutdown,
+    PeerDeconfigured,
+    AdministrativeReset,
 }
 
 enum MessageHeaderErrorSubcode {
@@ -535,11 +1245,13 @@ enum MessageHeaderErrorSubcode {
 }
 
 enum OpenMessageErrorSubCode {
+    Unspecific,
     UnsupportedVersionNumber,
     BadPeerAs,
     BadBgpIdentifier,
     UnsupportedOptionalParameter,
     UnacceptableHoldTime,
+    RoleMismatch, // RFC9234
 }
 
 enum UpdateMessageErrorSubcode {
@@ -555,18 +1267,223 @@ enum UpdateMessageErrorSubcode {
     MalformedAsPath,
 }
 
-struct OptionalParameter {
-    type_: BgpOpenMessageOptionalParameterType,
-    length: u8, // すでにパース後であるこのデータストラクチャには不要かも
-    value: Vec<u8>,
+enum OptionalParameter {
+    // OptionalParameterのバイト列の表現は以下の通り
+    // (<parameter type>: u8, <parameter length>: u8, <parameter value>)
+    Capabilities(Vec<Capability>), // parameter type 2 (RFC5492)
+    DontKnow(u8, Vec<u8>), // 不明なやつ
+}
+
+impl OptionalParameter {
+    fn value(&self) -> Vec<u8> {
+        let (parameter_type, mut parameter_value) = match self {
+            OptionalParameter::Capabilities(capabilities) => {
+                let mut parameter_value = vec![];
+                for capability in capabilities {
+                    parameter_value.append(&mut capability.value());
+                }
+                (2, parameter_value)
+            },
+            OptionalParameter::DontKnow(parameter_type, parameter_value) => (*parameter_type, parameter_value.clone()),
+        };
+        let parameter_length: u8 = parameter_value.len().try_into().unwrap();
+        let mut result = vec![parameter_type, parameter_length];
+        result.append(&mut parameter_value);
+        result
+    }
+
+    fn encode(raw_data: &Vec<u8>) -> Result<Vec<Self>, OpenMessageError> {
+        // optional parametersのところだけを渡す
+        let mut result = vec![];
+        let mut i = 0;
+        while i < raw_data.len() {
+            if i + 2 > raw_data.len() || i + 2 + usize::from(raw_data[i+1]) > raw_data.len() {
+                // parameter lengthがoptional parametersの残りより長い
+                return Err(OpenMessageError::MalformedOptionalParameters);
+            }
+            let parameter_type = raw_data[i];
+            let parameter_length: usize = raw_data[i+1].into();
+            let parameter_value = raw_data[i+2..i+2+parameter_length].to_vec();
+            i += 2 + parameter_length;
+            let optional_parameter = match parameter_type {
+                2 => OptionalParameter::Capabilities(Capability::encode(&parameter_value)?),
+                _ => OptionalParameter::DontKnow(parameter_type, parameter_value),
+            };
+            result.push(optional_parameter);
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capability {
+    // Capabilityのバイト列の表現は以下の通り
+    // (<capability code>: u8, <capability length>: u8, <capability value>)
+    RouteRefresh, // capability code 2 (RFC2918)
+    ExtendedMessage, // capability code 6 (RFC8654)
+    Role(Role), // capability code 9 (RFC9234)
+    EnhancedRouteRefresh, // capability code 70 (RFC7313)
+    GracefulRestart { // capability code 64 (RFC4724)
+        restart_state: bool,
+        restart_time: u16,
+        forwarding_state: bool, // IPv4 unicastのforwarding stateを保持しているか
+    },
+    AddPath { // capability code 69 (RFC7911)
+        // IPv4 unicastについて複数のpathを受け取れるか、送れるか
+        receive: bool,
+        send: bool,
+    },
+    DontKnow(u8, Vec<u8>), // 不明なやつ
+}
+
+impl Capability {
+    pub fn value(&self) -> Vec<u8> {
+        let (capability_code, mut capability_value) = match self {
+            Capability::RouteRefresh => (2, vec![]),
+            Capability::ExtendedMessage => (6, vec![]),
+            Capability::Role(role) => (9, vec![role.value()]),
+            Capability::EnhancedRouteRefresh => (70, vec![]),
+            Capability::GracefulRestart { restart_state, restart_time, forwarding_state } => {
+                // (<restart flags>: 4bit, <restart time>: 12bit) + (<AFI>: u16, <SAFI>: u8, <flags>: u8)
+                let restart_flags: u16 = if *restart_state { 0b1000_0000_0000_0000 } else { 0 };
+                let mut capability_value = (restart_flags | (restart_time & 0x0fff)).to_be_bytes().to_vec();
+                let address_family_flags: u8 = if *forwarding_state { 0b1000_0000 } else { 0 };
+                capability_value.append(&mut vec![0, 1, 1, address_family_flags]);
+                (64, capability_value)
+            },
+            Capability::AddPath { receive, send } => {
+                // (<AFI>: u16, <SAFI>: u8, <Send/Receive>: u8)
+                let send_receive: u8 = (if *receive { 1 } else { 0 }) | (if *send { 2 } else { 0 });
+                (69, vec![0, 1, 1, send_receive])
+            },
+            Capability::DontKnow(capability_code, capability_value) => (*capability_code, capability_value.clone()),
+        };
+        let capability_length: u8 = capability_value.len().try_into().unwrap();
+        let mut result = vec![capability_code, capability_length];
+        result.append(&mut capability_value);
+        result
+    }
+
+    pub fn encode(raw_data: &Vec<u8>) -> Result<Vec<Self>, OpenMessageError> {
+        // capabilities optional parameterのvalueだけを渡す
+        let mut result = vec![];
+        let mut i = 0;
+        while i < raw_data.len() {
+            if i + 2 > raw_data.len() || i + 2 + usize::from(raw_data[i+1]) > raw_data.len() {
+                // capability lengthがparameterの残りより長い
+                return Err(OpenMessageError::MalformedOptionalParameters);
+            }
+            let capability_code = raw_data[i];
+            let capability_length: usize = raw_data[i+1].into();
+            let capability_value = raw_data[i+2..i+2+capability_length].to_vec();
+            i += 2 + capability_length;
+            let capability = match capability_code {
+                2 => Capability::RouteRefresh,
+                6 => Capability::ExtendedMessage,
+                9 if capability_length == 1 && Role::from_u8(capability_value[0]).is_some() => {
+                    Capability::Role(Role::from_u8(capability_value[0]).unwrap())
+                },
+                70 => Capability::EnhancedRouteRefresh,
+                64 if capability_length >= 2 => {
+                    let restart_flags_and_time = u16::from_be_bytes(capability_value[0..2].try_into().unwrap());
+                    let mut forwarding_state = false;
+                    let mut j = 2;
+                    while j + 4 <= capability_length {
+                        // IPv4 unicast (AFI 1, SAFI 1) のforwarding stateだけをみる
+                        if capability_value[j..j+3] == [0, 1, 1] {
+                            forwarding_state = capability_value[j+3] & 0b1000_0000 != 0;
+                        }
+                        j += 4;
+                    }
+                    Capability::GracefulRestart {
+                        restart_state: restart_flags_and_time & 0b1000_0000_0000_0000 != 0,
+                        restart_time: restart_flags_and_time & 0x0fff,
+                        forwarding_state,
+                    }
+                },
+                69 => {
+                    let mut receive = false;
+                    let mut send = false;
+                    let mut j = 0;
+                    while j + 4 <= capability_length {
+                        // IPv4 unicast (AFI 1, SAFI 1) だけをみる
+                        if capability_value[j..j+3] == [0, 1, 1] {
+                            receive = capability_value[j+3] & 1 != 0;
+                            send = capability_value[j+3] & 2 != 0;
+                        }
+                        j += 4;
+                    }
+                    Capability::AddPath { receive, send }
+                },
+                _ => Capability::DontKnow(capability_code, capability_value),
+            };
+            result.push(capability);
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    // RFC9234 BGP Role, peerに対する自分の役割
+    Provider,
+    RouteServer,
+    RouteServerClient,
+    Customer,
+    Peer,
 }
 
-enum BgpOpenMessageOptionalParameterType {
-    i_dont_know_now, // あとでRFC3392をみておく。
+impl Role {
+    pub fn value(&self) -> u8 {
+        match self {
+            Role::Provider => 0,
+            Role::RouteServer => 1,
+            Role::RouteServerClient => 2,
+            Role::Customer => 3,
+            Role::Peer => 4,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Role::Provider),
+            1 => Some(Role::RouteServer),
+            2 => Some(Role::RouteServerClient),
+            3 => Some(Role::Customer),
+            4 => Some(Role::Peer),
+            _ => None,
+        }
+    }
+
+    pub fn is_compatible_with(&self, peer_role: Role) -> bool {
+        // RFC9234 4.2: 自分とpeerのroleの組み合わせとして正しいもの
+        match (self, peer_role) {
+            (Role::Provider, Role::Customer) | (Role::Customer, Role::Provider) => true,
+            (Role::RouteServer, Role::RouteServerClient) | (Role::RouteServerClient, Role::RouteServer) => true,
+            (Role::Peer, Role::Peer) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_peer_downstream(&self) -> bool {
+        // peerがcustomerかRS-Clientか (自分がproviderかRS)
+        match self {
+            Role::Provider | Role::RouteServer => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_sent_to_customer(&self) -> bool {
+        // peerがcustomer, peer, RS-Clientか (OTCを付けて広告する相手)
+        match self {
+            Role::Provider | Role::Peer | Role::RouteServer => true,
+            _ => false,
+        }
+    }
 }
 
 struct HoldTime(u16);
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct AutonomousSystemNumber(pub u16);
 
 impl AutonomousSystemNumber {
@@ -580,36 +1497,120 @@ pub enum BgpMessage {
     Update(BgpUpdateMessage),
     Notification(BgpNotificationMessage),
     Keepalive(BgpKeepaliveMessage),
+    RouteRefresh(BgpRouteRefreshMessage),
 }
 
-pub fn bgp_packet_handler(raw_data: &Vec<u8>, event_queue: &mut EventQueue, packet_queue: &mut PacketQueue) {
+pub fn bgp_packet_handler(raw_data: &Vec<u8>, event_queue: &mut EventQueue, packet_queue: &mut PacketQueue, negotiated_capabilities: &NegotiatedCapabilities) {
     let bgp_message_type = identify_what_kind_of_bgp_packet_is(raw_data);
     match bgp_message_type {
         Ok(t) => {
             match t {
                 BgpMessageType::Open => {
-                    // if valid open message
-                    let bgp_message = BgpOpenMessage::encode(raw_data);
-                    event_queue.push(Event::BgpOpen);
-                    // ToDo: else error open message ni taiou
-                    // event_queue.push(Event::BgpOpenMsgErr);
+                    match BgpOpenMessage::encode(raw_data) {
+                        Ok(bgp_message) => {
+                            packet_queue.push(BgpMessage::Open(bgp_message));
+                            event_queue.push(Event::BgpOpen);
+                        },
+                        Err(OpenMessageError::MalformedOptionalParameters) => {
+                            // 送り返すNOTIFICATIONを積んでおく
+                            packet_queue.push(BgpMessage::Notification(BgpNotificationMessage::new_malformed_optional_parameters()));
+                            event_queue.push(Event::BgpOpenMsgErr);
+                        },
+                    }
                 },
                 BgpMessageType::Update => {
-                    let bgp_message = BgpUpdateMessage::encode(raw_data);
-                    // packet_bufferに積むかも？
-                    packet_queue.push(BgpMessage::Update(bgp_message));
-                    event_queue.push(Event::UpdateMsg);
+                    match BgpUpdateMessage::encode(raw_data, negotiated_capabilities) {
+                        Ok(bgp_message) => {
+                            // packet_bufferに積むかも？
+                            packet_queue.push(BgpMessage::Update(bgp_message));
+                            event_queue.push(Event::UpdateMsg);
+                        },
+                        Err(UpdateMessageError::AttributeLengthError(attribute)) => {
+                            // 送り返すNOTIFICATIONを積んでおく
+                            packet_queue.push(BgpMessage::Notification(BgpNotificationMessage::new_attribute_length_error(attribute)));
+                            event_queue.push(Event::UpdateMsgErr);
+                        },
+                        Err(UpdateMessageError::MalformedAttributeList) => {
+                            packet_queue.push(BgpMessage::Notification(BgpNotificationMessage::new_malformed_attribute_list()));
+                            event_queue.push(Event::UpdateMsgErr);
+                        },
+                        Err(UpdateMessageError::InvalidNetworkField) => {
+                            packet_queue.push(BgpMessage::Notification(BgpNotificationMessage::new_invalid_network_field()));
+                            event_queue.push(Event::UpdateMsgErr);
+                        },
+                    }
                 },
                 BgpMessageType::Notification => (),
                 BgpMessageType::Keepalive => {
                     event_queue.push(Event::KeepAliveMsg);
                 },
+                BgpMessageType::RouteRefresh => {
+                    match BgpRouteRefreshMessage::encode(raw_data) {
+                        Some(bgp_message) => {
+                            packet_queue.push(BgpMessage::RouteRefresh(bgp_message));
+                            event_queue.push(Event::RouteRefreshMsg);
+                        },
+                        None => warn!("ignore the route refresh message with unknown subtype: {}", raw_data[21]),
+                    }
+                },
             }
         },
         Err(_) => (),
     }
 }
 
+#[derive(Debug)]
+pub struct BadMessageLengthError {
+    pub length: u16,
+}
+impl fmt::Display for BadMessageLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad bgp message length: {}", self.length)
+    }
+}
+
+pub fn check_bgp_message_length(raw_data: &Vec<u8>, max_message_length: usize) -> Result<(), BadMessageLengthError> {
+    // RFC4271 6.1: lengthは19以上、最大長以下で、message typeごとの最小長を満たす
+    // RFC8654: OPENとKEEPALIVEはExtended Messageがnegotiateされても4096以下
+    let length = u16::from_be_bytes(raw_data[16..18].try_into().unwrap());
+    let (min_length, max_length) = match raw_data[18] {
+        1 => (29, BGP_MAX_MESSAGE_LENGTH),
+        2 => (23, max_message_length),
+        3 => (21, max_message_length),
+        4 => (19, BGP_MAX_MESSAGE_LENGTH),
+        5 => (23, max_message_length),
+        _ => (19, max_message_length),
+    };
+    let length_usize: usize = length.into();
+    if length_usize < min_length || length_usize > max_length {
+        return Err(BadMessageLengthError { length });
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MalformedAttributeError {
+    AttributeLength, // Attribute Length ErrorのNOTIFICATIONを送ってsessionをresetする
+    TreatAsWithdraw, // RFC7606
+    AttributeDiscard, // RFC7606
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OpenMessageError {
+    // optional parameterかcapabilityのlengthが残りのバイト列より長い
+    MalformedOptionalParameters,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UpdateMessageError {
+    // 誤っているattributeを持つ
+    AttributeLengthError(Vec<u8>),
+    // withdrawn routes lengthかtotal path attribute lengthがmessageに収まらない
+    MalformedAttributeList,
+    // withdrawn routesかNLRIのprefixを読めない
+    InvalidNetworkField,
+}
+
 #[derive(Debug)]
 struct CannotIdentifyTheRawDataAsBgpPacketError;
 impl fmt::Display for CannotIdentifyTheRawDataAsBgpPacketError {
@@ -624,6 +1625,310 @@ fn identify_what_kind_of_bgp_packet_is(raw_data: &Vec<u8>) -> Result<BgpMessageT
         2 => Ok(BgpMessageType::Update),
         3 => Ok(BgpMessageType::Notification),
         4 => Ok(BgpMessageType::Keepalive),
+        5 => Ok(BgpMessageType::RouteRefresh),
         _ => Err(CannotIdentifyTheRawDataAsBgpPacketError),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_as_path_encode_and_value() {
+        // AS_CONFED_SEQUENCE(65001) + AS_SEQUENCE(64513, 64514)
+        let raw_data = vec![3, 1, 0xfd, 0xe9, 2, 2, 0xfc, 0x01, 0xfc, 0x02];
+        let as_path = AsPath::encode(&raw_data);
+        assert_eq!(as_path.get_seq(), vec![65001, 64513, 64514]);
+        assert_eq!(as_path.path_length(), 2);
+        assert_eq!(as_path.value(), raw_data);
+    }
+
+    #[test]
+    fn test_as_path_confederation_segments_are_removed() {
+        let mut as_path = AsPath::new(vec![AsPathSegment::AsSequence(vec![64513])]);
+        as_path.prepend_confederation(65002);
+        as_path.prepend_confederation(65001);
+        assert_eq!(as_path.get_seq(), vec![65001, 65002, 64513]);
+
+        as_path.remove_confederation_segments();
+        as_path.prepend(64512);
+        assert_eq!(as_path.get_seq(), vec![64512, 64513]);
+        assert_eq!(as_path.value(), vec![2, 2, 0xfc, 0x00, 0xfc, 0x01]);
+    }
+
+    #[test]
+    fn test_prepend_to_full_as_sequence() {
+        // 255個のAS番号が入ったAS_SEQUENCEにprependすると新しいAS_SEQUENCEを作る
+        let mut as_path = AsPath::new(vec![AsPathSegment::AsSequence(vec![64513; 255])]);
+        as_path.prepend(64512);
+        assert_eq!(as_path.0.len(), 2);
+        assert_eq!(as_path.path_length(), 256);
+        let raw_data = as_path.value();
+        assert_eq!(raw_data[..4], [2, 1, 0xfc, 0x00]);
+        assert_eq!(raw_data[4..6], [2, 255]);
+        assert_eq!(AsPath::encode(&raw_data), as_path);
+
+        // 255個より多いAS_SETは分けてencodeする
+        let as_set = AsPathSegment::AsSet((0..300).collect());
+        let raw_data = as_set.value();
+        assert_eq!(raw_data.len(), 2 + 255 * 2 + 2 + 45 * 2);
+        assert_eq!(raw_data[2 + 255 * 2..2 + 255 * 2 + 2], [1, 45]);
+    }
+
+    #[test]
+    fn test_open_message_with_capabilities() {
+        let open_message = BgpOpenMessage::new(
+            AutonomousSystemNumber::new(64512),
+            Ipv4Addr::new(10, 0, 0, 1),
+            30,
+            vec![Capability::RouteRefresh, Capability::EnhancedRouteRefresh]);
+        let raw_data = open_message.decode();
+        assert_eq!(raw_data.len(), 29 + 6);
+        assert_eq!(u16::from_be_bytes([raw_data[16], raw_data[17]]) as usize, raw_data.len());
+
+        let open_message = BgpOpenMessage::encode(&raw_data).unwrap();
+        assert_eq!(open_message.get_bgp_identifier(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(open_message.get_hold_time(), 30);
+        assert_eq!(open_message.get_capabilities(), vec![Capability::RouteRefresh, Capability::EnhancedRouteRefresh]);
+    }
+
+    #[test]
+    fn test_open_message_with_malformed_optional_parameters() {
+        let open_message = BgpOpenMessage::new(
+            AutonomousSystemNumber::new(64512),
+            Ipv4Addr::new(10, 0, 0, 1),
+            30,
+            vec![Capability::RouteRefresh]);
+        let raw_data = open_message.decode();
+        // capability lengthがparameterより長い
+        let mut overlong_capability = raw_data.clone();
+        overlong_capability[32] = 10;
+        assert_eq!(BgpOpenMessage::encode(&overlong_capability).err(), Some(OpenMessageError::MalformedOptionalParameters));
+        // parameter lengthがoptional parametersより長い
+        let mut overlong_parameter = raw_data.clone();
+        overlong_parameter[30] = 10;
+        assert_eq!(BgpOpenMessage::encode(&overlong_parameter).err(), Some(OpenMessageError::MalformedOptionalParameters));
+        // optional parameters lengthがmessageより長い
+        let mut overlong_parameters = raw_data;
+        overlong_parameters[28] = 10;
+        assert_eq!(BgpOpenMessage::encode(&overlong_parameters).err(), Some(OpenMessageError::MalformedOptionalParameters));
+    }
+
+    #[test]
+    fn test_graceful_restart_capability() {
+        let capability = Capability::GracefulRestart {
+            restart_state: true,
+            restart_time: 120,
+            forwarding_state: true,
+        };
+        let raw_data = capability.value();
+        assert_eq!(raw_data, vec![64, 6, 0x80, 120, 0, 1, 1, 0x80]);
+        assert_eq!(Capability::encode(&raw_data).unwrap(), vec![capability]);
+    }
+
+    #[test]
+    fn test_role_capability_and_only_to_customer() {
+        let capability = Capability::Role(Role::Customer);
+        let raw_data = capability.value();
+        assert_eq!(raw_data, vec![9, 1, 3]);
+        assert_eq!(Capability::encode(&raw_data).unwrap(), vec![capability]);
+        assert!(Role::Customer.is_compatible_with(Role::Provider));
+        assert!(!Role::Peer.is_compatible_with(Role::Customer));
+
+        let only_to_customer = PathAttribute::OnlyToCustomer(64512);
+        let raw_data = only_to_customer.decode();
+        assert_eq!(raw_data, vec![0xC0, 35, 4, 0, 0, 0xfc, 0x00]);
+        assert_eq!(PathAttribute::encode(raw_data[0], raw_data[1], 4, raw_data[3..].to_vec()), Ok(only_to_customer));
+        assert_eq!(PathAttribute::encode(raw_data[0], raw_data[1], 2, vec![0xfc, 0x00]), Err(MalformedAttributeError::TreatAsWithdraw));
+        assert_eq!(PathAttribute::encode(0x90, 15, 2, vec![0, 1]), Err(MalformedAttributeError::AttributeLength));
+    }
+
+    #[test]
+    fn test_long_cluster_list_uses_extended_length() {
+        let cluster_list: Vec<Ipv4Addr> = (0..64).map(|i| Ipv4Addr::new(10, 0, 0, i)).collect();
+        let raw_data = PathAttribute::ClusterList(cluster_list.clone()).decode();
+        assert_eq!(raw_data[..4], [0x90, 10, 1, 0]);
+        assert_eq!(raw_data.len(), 4 + 256);
+        // 255バイト以下なら1バイトのまま
+        assert_eq!(PathAttribute::ClusterList(cluster_list[..2].to_vec()).decode()[..3], [0x80, 10, 8]);
+
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64512; 200])])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+            PathAttribute::ClusterList(cluster_list),
+        ];
+        let nlri = vec![Nlri::new(IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24), None)];
+        let raw_data = BgpUpdateMessage::new(vec![], path_attributes.clone(), nlri).decode();
+        let update_message = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default()).unwrap();
+        assert_eq!(update_message.path_attributes, path_attributes);
+    }
+
+    #[test]
+    fn test_update_message_with_path_identifiers() {
+        let nlri = vec![
+            Nlri::new(IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24), Some(1)),
+            Nlri::new(IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24), Some(2)),
+        ];
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64512])])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+        let update_message = BgpUpdateMessage::new(vec![], path_attributes.clone(), nlri.clone());
+        let raw_data = update_message.decode();
+        assert_eq!(u16::from_be_bytes([raw_data[16], raw_data[17]]) as usize, raw_data.len());
+
+        let negotiated_capabilities = NegotiatedCapabilities { add_path_receive: true, ..Default::default() };
+        let update_message = BgpUpdateMessage::encode(&raw_data, &negotiated_capabilities).unwrap();
+        assert_eq!(update_message.path_attributes, path_attributes);
+        assert_eq!(update_message.network_layer_reachability_information, nlri);
+    }
+
+    #[test]
+    fn test_update_message_with_truncated_nlri() {
+        let nlri = vec![Nlri::new(IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24), Some(1))];
+        let raw_data = BgpUpdateMessage::new(vec![], vec![], nlri).decode();
+        let negotiated_capabilities = NegotiatedCapabilities { add_path_receive: true, ..Default::default() };
+        // path identifierの途中で切れている
+        let result = BgpUpdateMessage::encode(&raw_data[..raw_data.len() - 5].to_vec(), &negotiated_capabilities);
+        assert_eq!(result.err(), Some(UpdateMessageError::InvalidNetworkField));
+        // prefixの途中で切れている
+        let result = BgpUpdateMessage::encode(&raw_data[..raw_data.len() - 1].to_vec(), &negotiated_capabilities);
+        assert_eq!(result.err(), Some(UpdateMessageError::InvalidNetworkField));
+        // prefix lengthが32より長い
+        let mut wrong_prefix_length = raw_data.clone();
+        wrong_prefix_length[27] = 33;
+        let result = BgpUpdateMessage::encode(&wrong_prefix_length, &negotiated_capabilities);
+        assert_eq!(result.err(), Some(UpdateMessageError::InvalidNetworkField));
+        // withdrawn routes lengthがmessageより長い
+        let mut wrong_withdrawn_routes_length = raw_data;
+        wrong_withdrawn_routes_length[20] = 100;
+        let result = BgpUpdateMessage::encode(&wrong_withdrawn_routes_length, &negotiated_capabilities);
+        assert_eq!(result.err(), Some(UpdateMessageError::MalformedAttributeList));
+        wrong_withdrawn_routes_length[19..21].copy_from_slice(&[0xff, 0xff]);
+        let result = BgpUpdateMessage::encode(&wrong_withdrawn_routes_length, &negotiated_capabilities);
+        assert_eq!(result.err(), Some(UpdateMessageError::MalformedAttributeList));
+
+        let raw_data = BgpNotificationMessage::new_invalid_network_field().decode();
+        assert_eq!(raw_data[18..], [3, 3, 10]);
+    }
+
+    #[test]
+    fn test_update_message_with_wrong_attribute_length() {
+        let nlri = Nlri::new(IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24), None);
+        let update_message_with = |mut path_attributes: Vec<u8>| {
+            let mut raw_data = BgpUpdateMessage::new(vec![], vec![], vec![nlri]).decode();
+            let mut nlri_bytes = raw_data.split_off(21);
+            nlri_bytes.drain(..2);
+            raw_data.append(&mut (path_attributes.len() as u16).to_be_bytes().to_vec());
+            raw_data.append(&mut path_attributes);
+            raw_data.append(&mut nlri_bytes);
+            let length = raw_data.len() as u16;
+            raw_data[16..18].copy_from_slice(&length.to_be_bytes());
+            raw_data
+        };
+
+        // LOCAL_PREFの長さが誤っていればsessionはresetせず、routeをwithdrawされたものとして扱う
+        let mut path_attributes = PathAttribute::Origin(Origin::Igp).decode();
+        let mut local_pref = PathAttribute::LocalPref(100).decode();
+        local_pref.truncate(5);
+        local_pref[2] = 2;
+        path_attributes.append(&mut local_pref);
+        let raw_data = update_message_with(path_attributes);
+        let update_message = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default()).unwrap();
+        assert_eq!(update_message.withdrawn_routes, vec![nlri]);
+        assert!(update_message.network_layer_reachability_information.is_empty());
+        assert_eq!(PathAttribute::encode(0x80, 9, 3, vec![10, 0, 0]), Err(MalformedAttributeError::TreatAsWithdraw));
+        assert_eq!(PathAttribute::encode(0x40, 1, 1, vec![3]), Err(MalformedAttributeError::TreatAsWithdraw));
+
+        // ATOMIC_AGGREGATEの長さが誤っていればattributeだけを捨てる
+        let mandatory_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64513])])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 2)),
+        ];
+        let mut path_attributes: Vec<u8> = mandatory_attributes.iter().flat_map(|p| p.decode()).collect();
+        path_attributes.append(&mut vec![0x40, 6, 1, 0]);
+        let raw_data = update_message_with(path_attributes);
+        let update_message = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default()).unwrap();
+        assert_eq!(update_message.path_attributes, mandatory_attributes);
+        assert_eq!(update_message.network_layer_reachability_information, vec![nlri]);
+
+        // NEXT_HOPが無いrouteはwithdrawされたものとして扱う
+        let path_attributes: Vec<u8> = mandatory_attributes[..2].iter().flat_map(|p| p.decode()).collect();
+        let raw_data = update_message_with(path_attributes);
+        let update_message = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default()).unwrap();
+        assert_eq!(update_message.withdrawn_routes, vec![nlri]);
+        assert!(update_message.network_layer_reachability_information.is_empty());
+
+        // attributeのtypeやlengthの途中で切れていればMalformed Attribute List
+        for path_attributes in [vec![0x40], vec![0x40, 1], vec![0x50, 1, 0]] {
+            let raw_data = update_message_with(path_attributes);
+            let result = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default());
+            assert_eq!(result.err(), Some(UpdateMessageError::MalformedAttributeList));
+        }
+
+        // AFIとSAFIが無いMP_UNREACH_NLRIはAttribute Length Error
+        let path_attributes = vec![0x90, 15, 0, 2, 0, 1];
+        let raw_data = update_message_with(path_attributes.clone());
+        let result = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default());
+        assert_eq!(result.err(), Some(UpdateMessageError::AttributeLengthError(path_attributes.clone())));
+
+        let raw_data = BgpNotificationMessage::new_attribute_length_error(path_attributes).decode();
+        assert_eq!(raw_data[18..], [3, 3, 5, 0x90, 15, 0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_pack_routes_with_max_message_length() {
+        // /24のrouteは4バイトなので、1100個だと4096バイトに収まらない
+        let routes: Vec<Nlri> = (0..1100u16)
+            .map(|i| Nlri::new(IpPrefix::new(Ipv4Addr::new(10, (i / 256) as u8, (i % 256) as u8, 0), 24), None))
+            .collect();
+        let fixed_length = 19 + 2 + 2;
+        let packed = BgpUpdateMessage::pack_routes(routes.clone(), BGP_MAX_MESSAGE_LENGTH - fixed_length);
+        assert_eq!(packed.len(), 2);
+        for routes in packed {
+            assert!(BgpUpdateMessage::new(routes, vec![], vec![]).decode().len() <= BGP_MAX_MESSAGE_LENGTH);
+        }
+        let packed = BgpUpdateMessage::pack_routes(routes, BGP_EXTENDED_MAX_MESSAGE_LENGTH - fixed_length);
+        assert_eq!(packed.len(), 1);
+    }
+
+    #[test]
+    fn test_maximum_number_of_prefixes_reached_notification() {
+        let raw_data = BgpNotificationMessage::new_maximum_number_of_prefixes_reached(1, 1, 1000).decode();
+        assert_eq!(raw_data.len(), 28);
+        assert_eq!(u16::from_be_bytes([raw_data[16], raw_data[17]]), 28);
+        assert_eq!(raw_data[18..], [3, 6, 1, 0, 1, 1, 0, 0, 0x03, 0xe8]);
+    }
+
+    #[test]
+    fn test_end_of_rib() {
+        let negotiated_capabilities = NegotiatedCapabilities::default();
+        let raw_data = BgpUpdateMessage::new_end_of_rib(1, 1).decode();
+        assert_eq!(raw_data.len(), 23);
+        let update_message = BgpUpdateMessage::encode(&raw_data, &negotiated_capabilities).unwrap();
+        assert_eq!(update_message.get_end_of_rib_address_family(), Some((1, 1)));
+
+        let raw_data = BgpUpdateMessage::new_end_of_rib(2, 1).decode();
+        let update_message = BgpUpdateMessage::encode(&raw_data, &negotiated_capabilities).unwrap();
+        assert_eq!(update_message.get_end_of_rib_address_family(), Some((2, 1)));
+    }
+
+    #[test]
+    fn test_route_refresh_message() {
+        let route_refresh_message = BgpRouteRefreshMessage::new(RouteRefreshSubtype::BeginningOfRouteRefresh);
+        let raw_data = route_refresh_message.decode();
+        assert_eq!(raw_data.len(), 23);
+        assert_eq!(raw_data[19..23].to_vec(), vec![0, 1, 1, 1]);
+        let route_refresh_message = BgpRouteRefreshMessage::encode(&raw_data).unwrap();
+        assert_eq!(route_refresh_message.get_subtype(), RouteRefreshSubtype::BeginningOfRouteRefresh);
+        assert_eq!(route_refresh_message.get_address_family(), (1, 1));
+
+        let mut unknown_subtype = raw_data;
+        unknown_subtype[21] = 3;
+        assert!(BgpRouteRefreshMessage::encode(&unknown_subtype).is_none());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::log::LogLevel;
+
+// mrbgpdのcommand line option
+// 以前のように最初の引数に設定ファイルを書いて起動することもできる。
+
+pub const USAGE: &str = "usage: mrbgpd [--config] <path> [options]
+  --config <path>       config file (.toml or the line based format)
+  --check               validate the config file and exit (non-zero on errors)
+  --log-level <level>   error, warn, info (default) or debug
+  --no-fib              do not install routes into the kernel
+  --pid-file <path>     write the process id to the file
+  --foreground          run in the foreground (default)
+  --daemonize           run in the background
+  --help                print this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandLineOptions {
+    pub config: String,
+    pub check: bool,
+    pub log_level: LogLevel,
+    pub no_fib: bool,
+    pub pid_file: Option<String>,
+    pub daemonize: bool,
+    pub help: bool,
+}
+
+impl CommandLineOptions {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        // argsにはprogram名を含めない。
+        // 値をとるoptionは`--key value`と`--key=value`のどちらでも書ける
+        let mut config = None;
+        let mut options = Self {
+            config: String::new(),
+            check: false,
+            log_level: LogLevel::Info,
+            no_fib: false,
+            pid_file: None,
+            daemonize: false,
+            help: false,
+        };
+        let mut foreground = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (key, inline_value) = match arg.split_once('=') {
+                Some((key, value)) if key.starts_with("--") => (key, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || inline_value.clone().or_else(|| args.next().cloned()).ok_or_else(|| format!("{} needs value", key));
+            match key {
+                "--config" => config = Some(value()?),
+                "--check" => options.check = true,
+                "--log-level" => options.log_level = value()?.parse()?,
+                "--no-fib" => options.no_fib = true,
+                "--pid-file" => options.pid_file = Some(value()?),
+                "--foreground" => foreground = true,
+                "--daemonize" => options.daemonize = true,
+                "--help" | "-h" => options.help = true,
+                _ if key.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+                _ if config.is_none() => config = Some(arg.to_string()),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        if foreground && options.daemonize {
+            return Err("--foreground and --daemonize cannot be used together".to_string());
+        }
+        match config {
+            Some(config) => options.config = config,
+            None if options.help => (),
+            None => return Err("config file is required".to_string()),
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CommandLineOptions, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        CommandLineOptions::parse(&args)
+    }
+
+    #[test]
+    fn test_parse_command_line_options() {
+        let options = parse(&["--config", "bgp.toml", "--log-level=debug", "--no-fib", "--pid-file", "/run/mrbgpd.pid", "--daemonize"]).unwrap();
+        assert_eq!(options.config, "bgp.toml");
+        assert_eq!(options.log_level, LogLevel::Debug);
+        assert!(options.no_fib && options.daemonize && !options.check);
+        assert_eq!(options.pid_file, Some("/run/mrbgpd.pid".to_string()));
+
+        // 以前の起動方法
+        let options = parse(&["bgp.config", "--no-fib"]).unwrap();
+        assert_eq!(options.config, "bgp.config");
+        assert!(options.no_fib && !options.daemonize);
+
+        assert_eq!(parse(&[]).unwrap_err(), "config file is required");
+        assert_eq!(parse(&["--check", "--config"]).unwrap_err(), "--config needs value");
+        assert!(parse(&["bgp.toml", "--foreground", "--daemonize"]).is_err());
+        assert!(parse(&["bgp.toml", "--log-level", "trace"]).is_err());
+        assert!(parse(&["bgp.toml", "--verbose"]).is_err());
+    }
+}
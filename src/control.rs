@@ -0,0 +1,81 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+// 動いているdaemonを操作するためのunix domain socket
+// 設定ファイルにcontrol-socketを書くとそのpathで待ち受ける。
+// 1行に1つcommandを送ると、結果を返して切断する。
+//   reload: 設定ファイルを読み直して差分を反映する (SIGHUPと同じ)
+//   soft-reset <in|out> <peerのaddress|all>: sessionを張り直さずにrouteを受け取り直す/送り直す
+//   show dampening: dampeningのpenaltyとsuppressされているprefixを表示する
+//   show aspa: ASPAの数と、peerから受け取ったrouteのASPAによるverificationの結果を表示する
+// 例: echo reload | nc -U /run/mrbgpd.sock
+
+pub struct ControlServer {
+    listener: UnixListener,
+}
+
+pub struct ControlRequest {
+    pub command: String,
+    stream: UnixStream,
+}
+
+impl ControlRequest {
+    pub fn reply(mut self, message: &str) {
+        if let Err(e) = writeln!(self.stream, "{}", message) {
+            error!("cannot reply to the control command: {}", e);
+        }
+    }
+}
+
+impl ControlServer {
+    pub fn new(path: &str) -> Self {
+        // 前回の起動時のsocketが残っているとbindできないので消す
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).expect("cannot bind the control socket");
+        listener.set_nonblocking(true).expect("cannot set the control socket to non-blocking");
+        Self { listener }
+    }
+
+    pub fn poll(&self) -> Vec<ControlRequest> {
+        let mut result = vec![];
+        while let Ok((stream, _)) = self.listener.accept() {
+            // commandを送ってこないclientで止まらないようにtimeoutを付ける
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let mut command = String::new();
+            match BufReader::new(&stream).read_line(&mut command) {
+                Ok(_) => result.push(ControlRequest { command: command.trim().to_string(), stream }),
+                Err(e) => error!("cannot read the control command: {}", e),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_control_server() {
+        let path = std::env::temp_dir().join(format!("mrbgpd-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let control_server = ControlServer::new(path);
+        assert!(control_server.poll().is_empty());
+
+        let mut client = UnixStream::connect(path).unwrap();
+        client.write_all(b"reload\n").unwrap();
+        let mut requests = control_server.poll();
+        assert_eq!(requests.len(), 1);
+        let request = requests.pop().unwrap();
+        assert_eq!(request.command, "reload");
+        request.reply("ok");
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ok\n");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use std::time::{Duration, SystemTime};
+use crate::routing::IpPrefix;
+
+// Route Flap Dampening (RFC2439)
+// peerから受け取ったprefixがwithdrawされたりattributeが変わったりするたびにpenaltyを加え、
+// penaltyがsuppress thresholdを超えたらそのprefixをbest pathの選択から外す。
+// penaltyはhalf lifeごとに半分になり、reuse thresholdを下回るか
+// max suppress timeが経つとまた使えるようになる。
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DampeningConfig {
+    pub half_life: Duration,
+    pub reuse_threshold: f64,
+    pub suppress_threshold: f64,
+    pub max_suppress_time: Duration,
+    pub withdraw_penalty: f64,
+    pub attribute_change_penalty: f64,
+}
+
+impl Default for DampeningConfig {
+    fn default() -> Self {
+        // RFC2439で例として挙げられている値
+        Self {
+            half_life: Duration::from_secs(15 * 60),
+            reuse_threshold: 750.0,
+            suppress_threshold: 2000.0,
+            max_suppress_time: Duration::from_secs(60 * 60),
+            withdraw_penalty: 1000.0,
+            attribute_change_penalty: 500.0,
+        }
+    }
+}
+
+impl DampeningConfig {
+    fn get_max_penalty(&self) -> f64 {
+        // max suppress timeで減衰しきってreuse thresholdになるpenaltyを上限にする
+        let half_lives = self.max_suppress_time.as_secs_f64() / self.half_life.as_secs_f64();
+        self.reuse_threshold * 2f64.powf(half_lives)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DampeningState {
+    pub prefix: IpPrefix,
+    pub penalty: f64,
+    pub last_updated: SystemTime,
+    pub suppressed_since: Option<SystemTime>,
+}
+
+impl DampeningState {
+    pub fn is_suppressed(&self) -> bool {
+        self.suppressed_since.is_some()
+    }
+
+    fn decay(&mut self, config: &DampeningConfig, now: SystemTime) {
+        let elapsed = now.duration_since(self.last_updated).unwrap_or_default();
+        self.penalty *= 0.5f64.powf(elapsed.as_secs_f64() / config.half_life.as_secs_f64());
+        self.last_updated = now;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dampening {
+    config: DampeningConfig,
+    states: Vec<DampeningState>,
+}
+
+impl Dampening {
+    pub fn new(config: DampeningConfig) -> Self {
+        Self { config, states: vec![] }
+    }
+
+    pub fn add_withdraw_penalty(&mut self, prefix: &IpPrefix) {
+        self.add_penalty(prefix, self.config.withdraw_penalty, SystemTime::now());
+    }
+
+    pub fn add_attribute_change_penalty(&mut self, prefix: &IpPrefix) {
+        self.add_penalty(prefix, self.config.attribute_change_penalty, SystemTime::now());
+    }
+
+    fn add_penalty(&mut self, prefix: &IpPrefix, penalty: f64, now: SystemTime) {
+        let config = self.config;
+        let state = match self.states.iter_mut().find(|state| state.prefix == *prefix) {
+            Some(state) => state,
+            None => {
+                self.states.push(DampeningState { prefix: *prefix, penalty: 0.0, last_updated: now, suppressed_since: None });
+                self.states.last_mut().unwrap()
+            },
+        };
+        state.decay(&config, now);
+        state.penalty = (state.penalty + penalty).min(config.get_max_penalty());
+        if !state.is_suppressed() && state.penalty > config.suppress_threshold {
+            info!("dampening: suppress {:?} (penalty {})", prefix, state.penalty);
+            state.suppressed_since = Some(now);
+        }
+    }
+
+    pub fn is_suppressed(&self, prefix: &IpPrefix) -> bool {
+        self.states.iter().any(|state| state.prefix == *prefix && state.is_suppressed())
+    }
+
+    pub fn reuse_prefixes_if_possible(&mut self) -> Vec<IpPrefix> {
+        self.reuse_prefixes_at(SystemTime::now())
+    }
+
+    fn reuse_prefixes_at(&mut self, now: SystemTime) -> Vec<IpPrefix> {
+        // 再び使えるようになったprefixを返す
+        let config = self.config;
+        let mut result = vec![];
+        for state in &mut self.states {
+            state.decay(&config, now);
+            if let Some(suppressed_since) = state.suppressed_since {
+                let suppressed_time = now.duration_since(suppressed_since).unwrap_or_default();
+                if state.penalty < config.reuse_threshold || suppressed_time >= config.max_suppress_time {
+                    info!("dampening: reuse {:?} (penalty {})", state.prefix, state.penalty);
+                    state.suppressed_since = None;
+                    result.push(state.prefix);
+                }
+            }
+        }
+        // penaltyが十分小さくなったものは履歴を捨てる
+        self.states.retain(|state| state.is_suppressed() || state.penalty >= config.reuse_threshold / 2.0);
+        result
+    }
+
+    pub fn get_states(&self) -> &Vec<DampeningState> {
+        &self.states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_suppress_and_reuse() {
+        let mut dampening = Dampening::new(DampeningConfig::default());
+        let prefix = IpPrefix::new(Ipv4Addr::new(10, 1, 0, 0), 16);
+        let now = SystemTime::now();
+        dampening.add_penalty(&prefix, 1000.0, now);
+        dampening.add_penalty(&prefix, 1000.0, now);
+        assert!(!dampening.is_suppressed(&prefix));
+        dampening.add_penalty(&prefix, 1000.0, now);
+        assert!(dampening.is_suppressed(&prefix));
+
+        // half lifeが2回経っても750を下回らない
+        assert!(dampening.reuse_prefixes_at(now + Duration::from_secs(30 * 60)).is_empty());
+        assert_eq!(dampening.reuse_prefixes_at(now + Duration::from_secs(45 * 60)), vec![prefix]);
+        assert!(!dampening.is_suppressed(&prefix));
+    }
+}
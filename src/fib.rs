@@ -0,0 +1,651 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::future::{Future, ready};
+use std::net::{IpAddr, Ipv4Addr};
+use std::pin::Pin;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use futures::future::join_all;
+use futures::stream::{StreamExt, TryStreamExt};
+use rtnetlink::{Handle, IpVersion, constants::RTMGRP_IPV4_ROUTE, new_connection, sys::SocketAddr};
+use rtnetlink::packet::{NetlinkMessage, NetlinkPayload, RouteMessage, RtnlMessage, nlas::route::Nla};
+use rtnetlink::packet::constants::{RTN_BLACKHOLE, RTN_UNICAST, RTN_UNREACHABLE, RT_TABLE_UNSPEC};
+use rtnetlink::packet::{NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REPLACE, NLM_F_REQUEST};
+use crate::rib::{LocRib, RoutingInformationStatus};
+use crate::routing::IpPrefix;
+use crate::parse_option_value;
+
+// FIB(カーネルのrouting table)の管理
+// カーネルに書き込んだrouteを自分でも覚えておき、LocRibのbest pathとの差分だけを書き込む。
+//  - best pathのnexthopが変わったらreplaceする
+//  - multipathが有効ならequal-costなpathのnexthopをまとめて1つのrouteとして書き込む
+//  - LocRibから消えたrouteはカーネルからも消す
+//  - 起動時には前回の起動時に書き込んだ(FibConfigのtableとprotocolの)routeを読み込んでおき、差分を取る
+// カーネルへの読み書きはFib traitを通して行う。
+//  - NetlinkFib: rtnetlinkでカーネルのrouting tableを読み書きする
+//  - MemoryFib: メモリ上のtableだけを読み書きする。testと--no-fibで使う
+
+// mrbgpdがカーネルに書き込むrouteのprotocolのdefault
+pub const MRBGPD_ROUTE_PROTOCOL: u8 = 3;
+// main table
+pub const RT_TABLE_MAIN: u32 = 254;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteType {
+    Unicast,
+    // 黙って捨てる
+    Blackhole,
+    // ICMP unreachableを返して捨てる
+    Unreachable,
+}
+
+impl RouteType {
+    pub fn value(&self) -> u8 {
+        match self {
+            RouteType::Unicast => RTN_UNICAST,
+            RouteType::Blackhole => RTN_BLACKHOLE,
+            RouteType::Unreachable => RTN_UNREACHABLE,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            RTN_UNICAST => Some(RouteType::Unicast),
+            RTN_BLACKHOLE => Some(RouteType::Blackhole),
+            RTN_UNREACHABLE => Some(RouteType::Unreachable),
+            _ => None,
+        }
+    }
+}
+
+// カーネルにrouteを書き込むときの設定
+// 設定ファイルには以下のように書く。
+//   fib [table=<番号>] [protocol=bgp|<番号>] [metric=<値>] [type=unicast|blackhole|unreachable]
+//       [maximum-paths=<数>] [multipath-relax]
+// 指定しなければmain tableにprotocol 3、metric無しのunicastのrouteとして書き込む。
+// 起動時の読み込みと削除も同じtableとprotocolのrouteだけを対象にする。
+//  - maximum-paths: best pathとequal-costなpathを最大この数だけまとめてECMPのrouteにする
+//  - multipath-relax: 隣のASが違うpathもequal-costとして扱う
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FibConfig {
+    pub table: u32,
+    pub protocol: u8,
+    pub metric: u32,
+    pub route_type: RouteType,
+    pub maximum_paths: usize,
+    pub multipath_relax: bool,
+}
+
+impl Default for FibConfig {
+    fn default() -> Self {
+        Self {
+            table: RT_TABLE_MAIN,
+            protocol: MRBGPD_ROUTE_PROTOCOL,
+            metric: 0,
+            route_type: RouteType::Unicast,
+            maximum_paths: 1,
+            multipath_relax: false,
+        }
+    }
+}
+
+impl FibConfig {
+    pub fn parse_from_args(args: &[&str]) -> Result<Self, String> {
+        let mut config = Self::default();
+        for option in args {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (*option, None),
+            };
+            match key {
+                "table" => config.table = match value {
+                    Some("main") => RT_TABLE_MAIN,
+                    value => parse_option_value("table of fib", value)?,
+                },
+                "protocol" => config.protocol = match value {
+                    Some("bgp") => 186,
+                    value => parse_option_value("protocol of fib", value)?,
+                },
+                "metric" => config.metric = parse_option_value("metric of fib", value)?,
+                "maximum-paths" => {
+                    config.maximum_paths = parse_option_value("maximum-paths of fib", value)?;
+                    if config.maximum_paths == 0 {
+                        return Err(format!("maximum-paths must be at least 1: {}", option));
+                    }
+                },
+                "multipath-relax" => config.multipath_relax = true,
+                "type" => config.route_type = match value.ok_or("type needs value")? {
+                    "unicast" => RouteType::Unicast,
+                    "blackhole" => RouteType::Blackhole,
+                    "unreachable" => RouteType::Unreachable,
+                    _ => return Err(format!("type of fib must be unicast, blackhole or unreachable: {}", option)),
+                },
+                _ => return Err(format!("unknown fib option: {}", option)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn create_route(&self, destination: IpPrefix, mut gateways: Vec<Ipv4Addr>) -> FibRoute {
+        // blackholeとunreachableのrouteにはgatewayを付けない
+        // gatewayの順番が変わっただけで書き直さないように並べておく
+        match self.route_type {
+            RouteType::Unicast => {
+                gateways.sort();
+                gateways.dedup();
+            },
+            _ => gateways.clear(),
+        }
+        FibRoute { destination, gateways, protocol: self.protocol, table: self.table, metric: self.metric, route_type: self.route_type }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FibRoute {
+    pub destination: IpPrefix,
+    // 直結のrouteにはgatewayが無い。2つ以上ならmultipathのroute
+    pub gateways: Vec<Ipv4Addr>,
+    pub protocol: u8,
+    pub table: u32,
+    // 0ならmetric無し
+    pub metric: u32,
+    pub route_type: RouteType,
+}
+
+impl FibRoute {
+    pub fn has_same_key(&self, other: &Self) -> bool {
+        // カーネルは同じtableの同じdestinationで同じmetricのrouteを1つしか持てない
+        self.destination == other.destination && self.table == other.table && self.metric == other.metric
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FibChange {
+    Added(FibRoute),
+    Removed(FibRoute),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FibError {
+    AlreadyExists(FibRoute),
+    NotFound(FibRoute),
+    Netlink(String),
+}
+
+impl fmt::Display for FibError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FibError::AlreadyExists(route) => write!(f, "route already exists: {:?}", route),
+            FibError::NotFound(route) => write!(f, "route not found: {:?}", route),
+            FibError::Netlink(e) => write!(f, "netlink error: {}", e),
+        }
+    }
+}
+
+impl From<rtnetlink::Error> for FibError {
+    fn from(e: rtnetlink::Error) -> Self {
+        FibError::Netlink(e.to_string())
+    }
+}
+
+pub type FibFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FibError>> + 'a>>;
+
+pub trait Fib {
+    // 同じtableの同じdestinationで同じmetricのrouteがあればAlreadyExistsになる
+    fn install(&self, route: FibRoute) -> FibFuture<'_, ()>;
+    // 同じtableの同じdestinationで同じmetricのrouteがあれば置き換える
+    fn replace(&self, route: FibRoute) -> FibFuture<'_, ()>;
+    fn remove(&self, route: FibRoute) -> FibFuture<'_, ()>;
+    // 全てのtableのIPv4のrouteを返す
+    fn list(&self) -> FibFuture<'_, Vec<FibRoute>>;
+    // 以降のrouteの追加/削除を通知する
+    fn subscribe(&self) -> Result<UnboundedReceiver<FibChange>, FibError>;
+}
+
+pub struct NetlinkFib {
+    handle: Handle,
+}
+
+impl NetlinkFib {
+    pub fn new() -> Result<Self, FibError> {
+        // netlinkのconnectionは1つだけ作って使い回す
+        let (connection, handle, _) = new_connection()
+            .map_err(|e| FibError::Netlink(format!("cannot open the netlink socket: {}", e)))?;
+        tokio::spawn(connection);
+        Ok(Self { handle })
+    }
+
+    async fn request(&self, route: FibRoute, flags: u16) -> Result<(), FibError> {
+        let mut request = NetlinkMessage::from(RtnlMessage::NewRoute(self.create_route_message(&route)));
+        // rtnetlinkのaddはNLM_F_EXCL固定なので、flagを自分で付ける
+        request.header.flags = flags;
+        let mut response = self.handle.clone().request(request)?;
+        while let Some(message) = response.next().await {
+            if let NetlinkPayload::Error(e) = message.payload {
+                // EEXIST
+                if e.code == -17 {
+                    return Err(FibError::AlreadyExists(route));
+                }
+                return Err(rtnetlink::Error::NetlinkError(e).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn create_route_message(&self, route: &FibRoute) -> RouteMessage {
+        let mut request = self.handle.route().add().v4()
+            .protocol(route.protocol)
+            .kind(route.route_type.value())
+            .destination_prefix(route.destination.get_network_address(), route.destination.get_prefix_length());
+        if let [gateway] = route.gateways[..] {
+            request = request.gateway(gateway);
+        }
+        let mut message = request.message_mut().clone();
+        if route.gateways.len() > 1 {
+            message.nlas.push(Nla::MultiPath(decode_multipath(&route.gateways)));
+        }
+        // headerのtableは8bitなので、iproute2と同じく256以上のtableはRTA_TABLEで指定する
+        if route.table < 256 {
+            message.header.table = route.table as u8;
+        } else {
+            message.header.table = RT_TABLE_UNSPEC;
+            message.nlas.push(Nla::Table(route.table));
+        }
+        if route.metric != 0 {
+            message.nlas.push(Nla::Priority(route.metric));
+        }
+        message
+    }
+}
+
+fn parse_route_message(route: &RouteMessage) -> Option<FibRoute> {
+    let destination = match route.destination_prefix() {
+        Some((IpAddr::V4(network_address), prefix_length)) => IpPrefix::new(network_address, prefix_length),
+        _ => return None,
+    };
+    // localやbroadcastのrouteは扱わない
+    let route_type = RouteType::from_u8(route.header.kind)?;
+    let mut gateways = match route.gateway() {
+        Some(IpAddr::V4(gateway)) => vec![gateway],
+        _ => vec![],
+    };
+    let mut table = route.header.table as u32;
+    let mut metric = 0;
+    for nla in &route.nlas {
+        match nla {
+            Nla::Table(value) => table = *value,
+            Nla::Priority(value) => metric = *value,
+            Nla::MultiPath(raw_data) => gateways = encode_multipath(raw_data),
+            _ => (),
+        }
+    }
+    gateways.sort();
+    Some(FibRoute { destination, gateways, protocol: route.header.protocol, table, metric, route_type })
+}
+
+// RTA_MULTIPATHの中身はstruct rtnexthopの並び
+//   rtnh_len(2) rtnh_flags(1) rtnh_hops(1) rtnh_ifindex(4) の後にRTA_GATEWAYなどのattribute
+// ifindexを0にしておけばカーネルがgatewayから出力interfaceを決める
+const RTNH_LENGTH: usize = 8;
+const RTA_GATEWAY: u16 = 5;
+
+fn decode_multipath(gateways: &Vec<Ipv4Addr>) -> Vec<u8> {
+    let mut result = vec![];
+    for gateway in gateways {
+        let attribute_length: u16 = 4 + 4;
+        let length = RTNH_LENGTH as u16 + attribute_length;
+        result.extend_from_slice(&length.to_ne_bytes());
+        result.extend_from_slice(&[0, 0]);
+        result.extend_from_slice(&0i32.to_ne_bytes());
+        result.extend_from_slice(&attribute_length.to_ne_bytes());
+        result.extend_from_slice(&RTA_GATEWAY.to_ne_bytes());
+        result.extend_from_slice(&gateway.octets());
+    }
+    result
+}
+
+fn encode_multipath(raw_data: &[u8]) -> Vec<Ipv4Addr> {
+    let mut result = vec![];
+    let mut i = 0;
+    while i + RTNH_LENGTH <= raw_data.len() {
+        let length = u16::from_ne_bytes([raw_data[i], raw_data[i + 1]]) as usize;
+        if length < RTNH_LENGTH || i + length > raw_data.len() {
+            break;
+        }
+        // rtnexthopの後ろのattributeからRTA_GATEWAYを探す
+        let mut j = i + RTNH_LENGTH;
+        while j + 4 <= i + length {
+            let attribute_length = u16::from_ne_bytes([raw_data[j], raw_data[j + 1]]) as usize;
+            let attribute_type = u16::from_ne_bytes([raw_data[j + 2], raw_data[j + 3]]);
+            if attribute_length < 4 {
+                break;
+            }
+            if attribute_type == RTA_GATEWAY && attribute_length == 8 {
+                result.push(Ipv4Addr::new(raw_data[j + 4], raw_data[j + 5], raw_data[j + 6], raw_data[j + 7]));
+            }
+            // attributeは4byte境界に揃えられている
+            j += (attribute_length + 3) & !3;
+        }
+        i += (length + 3) & !3;
+    }
+    result
+}
+
+impl Fib for NetlinkFib {
+    fn install(&self, route: FibRoute) -> FibFuture<'_, ()> {
+        Box::pin(self.request(route, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL))
+    }
+
+    fn replace(&self, route: FibRoute) -> FibFuture<'_, ()> {
+        Box::pin(self.request(route, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE))
+    }
+
+    fn remove(&self, route: FibRoute) -> FibFuture<'_, ()> {
+        let message = self.create_route_message(&route);
+        Box::pin(async move {
+            self.handle.route().del(message).execute().await?;
+            Ok(())
+        })
+    }
+
+    fn list(&self) -> FibFuture<'_, Vec<FibRoute>> {
+        Box::pin(async move {
+            let routes: Vec<RouteMessage> = self.handle.route().get(IpVersion::V4).execute().try_collect().await?;
+            Ok(routes.iter().filter_map(parse_route_message).collect())
+        })
+    }
+
+    fn subscribe(&self) -> Result<UnboundedReceiver<FibChange>, FibError> {
+        // 購読用のconnectionを別に作り、RTM_NEWROUTE/RTM_DELROUTEをFibChangeに変換して流す
+        let (mut connection, _, mut messages) = new_connection()
+            .map_err(|e| FibError::Netlink(format!("cannot open the netlink socket: {}", e)))?;
+        connection.socket_mut().bind(&SocketAddr::new(0, RTMGRP_IPV4_ROUTE))
+            .map_err(|e| FibError::Netlink(format!("cannot subscribe to route changes: {}", e)))?;
+        tokio::spawn(connection);
+        let (sender, receiver) = unbounded();
+        tokio::spawn(async move {
+            while let Some((message, _)) = messages.next().await {
+                let change = match message.payload {
+                    NetlinkPayload::InnerMessage(RtnlMessage::NewRoute(route)) => parse_route_message(&route).map(FibChange::Added),
+                    NetlinkPayload::InnerMessage(RtnlMessage::DelRoute(route)) => parse_route_message(&route).map(FibChange::Removed),
+                    _ => None,
+                };
+                if let Some(change) = change {
+                    if sender.unbounded_send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryFib {
+    routes: RefCell<Vec<FibRoute>>,
+    subscribers: RefCell<Vec<UnboundedSender<FibChange>>>,
+}
+
+impl MemoryFib {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn notify(&self, change: FibChange) {
+        // 受け取る側が居なくなったsubscriberは捨てる
+        self.subscribers.borrow_mut().retain(|subscriber| subscriber.unbounded_send(change.clone()).is_ok());
+    }
+}
+
+impl Fib for MemoryFib {
+    fn install(&self, route: FibRoute) -> FibFuture<'_, ()> {
+        if self.routes.borrow().iter().any(|installed| installed.has_same_key(&route)) {
+            return Box::pin(ready(Err(FibError::AlreadyExists(route))));
+        }
+        self.routes.borrow_mut().push(route.clone());
+        self.notify(FibChange::Added(route));
+        Box::pin(ready(Ok(())))
+    }
+
+    fn replace(&self, route: FibRoute) -> FibFuture<'_, ()> {
+        self.routes.borrow_mut().retain(|installed| !installed.has_same_key(&route));
+        self.routes.borrow_mut().push(route.clone());
+        self.notify(FibChange::Added(route));
+        Box::pin(ready(Ok(())))
+    }
+
+    fn remove(&self, route: FibRoute) -> FibFuture<'_, ()> {
+        let length = self.routes.borrow().len();
+        self.routes.borrow_mut().retain(|installed| *installed != route);
+        if self.routes.borrow().len() == length {
+            return Box::pin(ready(Err(FibError::NotFound(route))));
+        }
+        self.notify(FibChange::Removed(route));
+        Box::pin(ready(Ok(())))
+    }
+
+    fn list(&self) -> FibFuture<'_, Vec<FibRoute>> {
+        Box::pin(ready(Ok(self.routes.borrow().clone())))
+    }
+
+    fn subscribe(&self) -> Result<UnboundedReceiver<FibChange>, FibError> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.borrow_mut().push(sender);
+        Ok(receiver)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FibOperation {
+    Install(FibRoute),
+    // 同じdestinationのrouteを置き換える
+    Replace(FibRoute),
+    Delete(FibRoute),
+}
+
+pub struct FibManager {
+    backend: Box<dyn Fib>,
+    config: FibConfig,
+    installed_routes: Vec<FibRoute>,
+}
+
+impl FibManager {
+    pub fn new(backend: Box<dyn Fib>, config: FibConfig) -> Self {
+        Self { backend, config, installed_routes: vec![] }
+    }
+
+    pub fn get_backend(&self) -> &dyn Fib {
+        self.backend.as_ref()
+    }
+
+    pub fn get_config(&self) -> &FibConfig {
+        &self.config
+    }
+
+    pub async fn load_installed_routes(&mut self) {
+        // 前回の起動時に書き込んだ、同じtableとprotocolのrouteを自分が書き込んだものとして扱う。
+        // metricやtypeが前回と違えば次のsyncで書き直す
+        let routes = match self.backend.list().await {
+            Ok(routes) => routes,
+            Err(e) => {
+                error!("cannot read the routes in the kernel: {}", e);
+                return;
+            },
+        };
+        self.installed_routes = routes.into_iter()
+            .filter(|route| route.protocol == self.config.protocol && route.table == self.config.table)
+            .collect();
+    }
+
+    pub fn get_installed_routes(&self) -> &Vec<FibRoute> {
+        &self.installed_routes
+    }
+
+    pub async fn sync(&mut self, loc_rib: &LocRib, retains_unknown_routes: bool) {
+        // retains_unknown_routes: Graceful Restart中はLocRibに無いrouteも消さずに残す
+        let desired_routes = desired_routes(loc_rib, &self.config);
+        let mut operations = diff(&self.installed_routes, &desired_routes);
+        if retains_unknown_routes {
+            operations.retain(|operation| !matches!(operation, FibOperation::Delete(_)));
+        }
+        if operations.is_empty() {
+            return;
+        }
+        // まとめてbackendに投げ、成功したものだけを反映する
+        let results = join_all(operations.iter().map(|operation| self.execute(operation.clone()))).await;
+        for (operation, result) in operations.into_iter().zip(results) {
+            match (operation, result) {
+                (FibOperation::Install(route), Ok(())) | (FibOperation::Replace(route), Ok(())) => {
+                    self.installed_routes.retain(|installed| !installed.has_same_key(&route));
+                    self.installed_routes.push(route);
+                },
+                (FibOperation::Delete(route), Ok(())) => {
+                    self.installed_routes.retain(|installed| *installed != route);
+                },
+                (operation, Err(e)) => error!("fib: cannot {:?}: {}", operation, e),
+            }
+        }
+    }
+
+    async fn execute(&self, operation: FibOperation) -> Result<(), FibError> {
+        match operation {
+            FibOperation::Install(route) => {
+                info!("fib: install {:?} via {:?}", route.destination, route.gateways);
+                self.backend.install(route).await
+            },
+            FibOperation::Replace(route) => {
+                info!("fib: replace {:?} via {:?}", route.destination, route.gateways);
+                self.backend.replace(route).await
+            },
+            FibOperation::Delete(route) => {
+                info!("fib: delete {:?} via {:?}", route.destination, route.gateways);
+                self.backend.remove(route).await
+            },
+        }
+    }
+}
+
+fn desired_routes(loc_rib: &LocRib, config: &FibConfig) -> Vec<FibRoute> {
+    // peerから学習したbest path(multipathならequal-costなpathも)だけを書き込む。
+    // 自分でoriginateしたrouteは書き込まない
+    loc_rib.select_multipaths(config.maximum_paths, config.multipath_relax).into_iter()
+        .filter(|paths| paths[0].status != RoutingInformationStatus::Withdrawn)
+        .filter(|paths| paths[0].source.get_peer_ip_addr().is_some())
+        .map(|paths| {
+            // 複数のiBGP peerから同じnexthopのpathが届くことがあるので、gatewayは重複させない
+            let mut gateways = vec![];
+            for entry in paths.iter().filter(|entry| entry.status != RoutingInformationStatus::Withdrawn) {
+                if !gateways.contains(&entry.nexthop) {
+                    gateways.push(entry.nexthop);
+                }
+            }
+            config.create_route(paths[0].destnation_address, gateways)
+        })
+        .collect()
+}
+
+fn diff(installed_routes: &Vec<FibRoute>, desired_routes: &Vec<FibRoute>) -> Vec<FibOperation> {
+    let mut operations = vec![];
+    for route in desired_routes {
+        match installed_routes.iter().find(|installed| installed.has_same_key(route)) {
+            None => operations.push(FibOperation::Install(route.clone())),
+            Some(installed) if installed != route => operations.push(FibOperation::Replace(route.clone())),
+            Some(_) => (),
+        }
+    }
+    for route in installed_routes {
+        if !desired_routes.iter().any(|desired| desired.has_same_key(route)) {
+            operations.push(FibOperation::Delete(route.clone()));
+        }
+    }
+    operations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::{AsPath, AsPathSegment, Origin, PathAttribute};
+    use crate::rib::{RouteSource, RoutingInformationEntry};
+
+    fn route(destination: &str, gateway: [u8; 4]) -> FibRoute {
+        FibConfig::default().create_route(destination.parse().unwrap(), vec![Ipv4Addr::from(gateway)])
+    }
+
+    #[test]
+    fn test_diff() {
+        let installed_routes = vec![route("10.1.0.0/16", [10, 0, 0, 1]), route("10.2.0.0/16", [10, 0, 0, 1]), route("10.3.0.0/16", [10, 0, 0, 1])];
+        let desired_routes = vec![route("10.1.0.0/16", [10, 0, 0, 1]), route("10.2.0.0/16", [10, 0, 0, 2]), route("10.4.0.0/16", [10, 0, 0, 1])];
+        assert_eq!(diff(&installed_routes, &desired_routes), vec![
+            FibOperation::Replace(route("10.2.0.0/16", [10, 0, 0, 2])),
+            FibOperation::Install(route("10.4.0.0/16", [10, 0, 0, 1])),
+            FibOperation::Delete(route("10.3.0.0/16", [10, 0, 0, 1])),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_fib() {
+        let fib = MemoryFib::new();
+        let mut changes = fib.subscribe().unwrap();
+        fib.install(route("10.1.0.0/16", [10, 0, 0, 1])).await.unwrap();
+        assert_eq!(fib.install(route("10.1.0.0/16", [10, 0, 0, 2])).await, Err(FibError::AlreadyExists(route("10.1.0.0/16", [10, 0, 0, 2]))));
+        fib.replace(route("10.1.0.0/16", [10, 0, 0, 2])).await.unwrap();
+        assert_eq!(fib.list().await.unwrap(), vec![route("10.1.0.0/16", [10, 0, 0, 2])]);
+        assert_eq!(fib.remove(route("10.2.0.0/16", [10, 0, 0, 1])).await, Err(FibError::NotFound(route("10.2.0.0/16", [10, 0, 0, 1]))));
+        fib.remove(route("10.1.0.0/16", [10, 0, 0, 2])).await.unwrap();
+        assert!(fib.list().await.unwrap().is_empty());
+        assert_eq!(changes.try_recv().unwrap(), FibChange::Added(route("10.1.0.0/16", [10, 0, 0, 1])));
+        assert_eq!(changes.try_recv().unwrap(), FibChange::Added(route("10.1.0.0/16", [10, 0, 0, 2])));
+        assert_eq!(changes.try_recv().unwrap(), FibChange::Removed(route("10.1.0.0/16", [10, 0, 0, 2])));
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_fib_config() {
+        let config = FibConfig::parse_from_args(&["table=100", "protocol=bgp", "metric=20", "type=blackhole"]).unwrap();
+        let fib = MemoryFib::new();
+        // 前回の起動時に書き込んだrouteと、他のdaemonのroute
+        let stale_route = config.create_route("10.1.0.0/16".parse().unwrap(), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        fib.install(stale_route.clone()).await.unwrap();
+        fib.install(route("10.1.0.0/16", [10, 0, 0, 1])).await.unwrap();
+        let mut fib_manager = FibManager::new(Box::new(fib), config);
+        fib_manager.load_installed_routes().await;
+        assert_eq!(fib_manager.get_installed_routes(), &vec![stale_route]);
+
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+        let mut entry = RoutingInformationEntry::new(
+            Ipv4Addr::new(10, 0, 0, 1), "10.2.0.0/16".parse().unwrap(), RoutingInformationStatus::Updated, path_attributes);
+        entry.source = RouteSource::External(Ipv4Addr::new(10, 0, 0, 1));
+        fib_manager.sync(&LocRib::new(vec![entry]), false).await;
+        let desired_route = FibRoute {
+            destination: "10.2.0.0/16".parse().unwrap(), gateways: vec![], protocol: 186, table: 100, metric: 20, route_type: RouteType::Blackhole,
+        };
+        assert_eq!(fib_manager.get_installed_routes(), &vec![desired_route.clone()]);
+        assert_eq!(fib_manager.get_backend().list().await.unwrap(), vec![route("10.1.0.0/16", [10, 0, 0, 1]), desired_route]);
+    }
+
+    #[test]
+    fn test_multipath() {
+        let gateways = vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3)];
+        assert_eq!(encode_multipath(&decode_multipath(&gateways)), gateways);
+
+        let config = FibConfig::parse_from_args(&["maximum-paths=2", "multipath-relax"]).unwrap();
+        let path = |nexthop: [u8; 4], as_path: Vec<u16>| {
+            let path_attributes = vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(as_path)])),
+                PathAttribute::NextHop(Ipv4Addr::from(nexthop)),
+            ];
+            let mut entry = RoutingInformationEntry::new(
+                Ipv4Addr::from(nexthop), "10.1.0.0/16".parse().unwrap(), RoutingInformationStatus::Updated, path_attributes);
+            entry.source = RouteSource::External(Ipv4Addr::from(nexthop));
+            entry
+        };
+        let loc_rib = LocRib::new(vec![path([10, 0, 0, 2], vec![64513]), path([10, 0, 0, 1], vec![64514]), path([10, 0, 0, 3], vec![64515])]);
+        assert_eq!(desired_routes(&loc_rib, &config)[0].gateways, vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+        assert_eq!(desired_routes(&loc_rib, &FibConfig::default())[0].gateways, vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+}
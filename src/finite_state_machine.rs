@@ -1,13 +1,20 @@
-use crate::{Config, Mode, bgp::BgpKeepaliveMessage, bgp::BgpMessage, bgp::BgpOpenMessage, bgp::BgpUpdateMessage, routing::write_ip_v4_route};
+use crate::{Config, Mode, bgp::BgpKeepaliveMessage, bgp::BgpMessage, bgp::BgpOpenMessage, bgp::BgpUpdateMessage};
+use crate::bgp::{BgpRouteRefreshMessage, Capability, NegotiatedCapabilities, RouteRefreshSubtype};
+use crate::bgp::{BadMessageLengthError, BgpNotificationMessage, CeaseSubcode, check_bgp_message_length};
 use std::{alloc::System, convert::TryInto, time::{Duration, SystemTime}};
 use std::net;
 use std::{thread, time};
-use net::{TcpListener, TcpStream};
-use std::io::Write;
-use crate::rib::{LocRib, AdjRibOut, AdjRibIn};
-use crate::routing::lookup_network_route;
-use crate::bgp::{PathAttribute, Origin, AsPath};
-use rtnetlink::RouteAddRequest;
+use net::TcpStream;
+use std::io::{self, Write};
+use crate::rib::{LocRib, AdjRibOut, AdjRibIn, RouteSource, RoutingInformationEntry, RoutingInformationStatus};
+use crate::routing::IpPrefix;
+use crate::bgp::{PathAttribute, Origin, AsPath, Role};
+use crate::fib::Fib;
+use crate::dampening::{Dampening, DampeningState};
+use crate::rpki::RoaTable;
+use crate::aspa::AspaTable;
+use std::rc::Rc;
+use std::collections::HashSet;
 
 pub struct SessionAttribute {
     state: State,
@@ -18,19 +25,36 @@ pub struct SessionAttribute {
     hold_time: Duration,
     keepalive_timer: SystemTime,
     keepalive_time: Duration,
+    peer_bgp_identifier: Option<net::Ipv4Addr>,
+    peer_capabilities: Vec<Capability>,
+    // End-of-RIBを送った/受け取ったaddress family (AFI, SAFI)
+    end_of_rib_sent: Vec<(u16, u8)>,
+    end_of_rib_received: Vec<(u16, u8)>,
+    restart_timer: Option<SystemTime>,
+    restart_time: Duration,
+    // max-prefixの警告を出したaddress family (AFI, SAFI)
+    max_prefix_warned: Vec<(u16, u8)>,
+    max_prefix_restart_timer: Option<SystemTime>,
 }
 
 pub struct fsm {
     config: Config,
     session_attribute: SessionAttribute,
-    tcp_listener: net::TcpListener,
     pub tcp_connection: Option<net::TcpStream>,
-    packet_buffer: [u8; 1024],
+    // passiveのpeerにBgpPeersのlistenerがacceptして渡したconnection
+    incoming_connection: Option<net::TcpStream>,
+    packet_buffer: Vec<u8>,
     pub event_queue: EventQueue,
     pub packet_queue: PacketQueue,
     adj_rib_out: AdjRibOut,
     adj_rib_in: AdjRibIn,
     pub data_buffer: DataBuffer,
+    // non-blockingのsocketに書ききれなかったmessageのバイト列。次のloopで続きから送る
+    send_buffer: Vec<u8>,
+    graceful_restart_state: bool,
+    dampening: Option<Dampening>,
+    roa_table: Rc<RoaTable>,
+    aspa_table: Rc<AspaTable>,
 }
 
 pub struct DataBuffer {
@@ -51,15 +75,24 @@ impl DataBuffer {
         bgp_header
     }
 
-    pub fn retrive_one_bgp_message(&mut self) -> Vec<u8> {
+    pub fn retrive_one_bgp_message(&mut self, max_message_length: usize) -> Result<Option<Vec<u8>>, BadMessageLengthError> {
+        // messageがまだ全部届いていなければNoneを返す。
+        // header checkでlengthがおかしければbufferはそのまま残してErrを返す
+        let bgp_header_length: u16 = 19;
+        if self.buf.len() < bgp_header_length as usize {
+            return Ok(None);
+        }
+        check_bgp_message_length(&self.buf, max_message_length)?;
+        let next_bgp_message_length: u16 = u16::from_be_bytes(self.buf[16..18].try_into().unwrap());
+        if self.buf.len() < next_bgp_message_length as usize {
+            return Ok(None);
+        }
         let mut bgp_message = self.retrieve_bgp_header_data();
-        let bgp_header_length = 19;
-        let next_bgp_message_length: u16 = u16::from_be_bytes(bgp_message[16..18].try_into().unwrap());
         let (bgp_data, buf )= self.buf.split_at((next_bgp_message_length - bgp_header_length) as usize);
         let mut bgp_data = bgp_data.to_vec();
         self.buf = buf.to_vec();
         bgp_message.append(&mut bgp_data);
-        bgp_message
+        Ok(Some(bgp_message))
     }
 }
 
@@ -87,57 +120,558 @@ impl<T> Queue<T> {
 }
 
 impl fsm {
-    pub fn new(config: Config, tcp_listener: TcpListener) -> Self {
+    pub fn new(config: Config) -> Self {
         let session_attribute = SessionAttribute::new();
-        let tcp_listener = tcp_listener;
         let tcp_connection = None;
         let event_queue = EventQueue::new();
-        let packet_buffer = [0u8; 1024];
+        let packet_buffer = vec![];
         let packet_queue = PacketQueue::new();
         let adj_rib_in = AdjRibIn::new(vec![]);
         let adj_rib_out = AdjRibOut::new(vec![]);
         let data_buffer = DataBuffer::new();
+        let dampening = config.dampening.map(Dampening::new);
         Self {
             config,
             session_attribute,
-            tcp_listener,
             tcp_connection,
+            incoming_connection: None,
             packet_buffer,
             event_queue,
             packet_queue,
             adj_rib_in,
             adj_rib_out,
             data_buffer,
+            send_buffer: vec![],
+            graceful_restart_state: false,
+            dampening,
+            roa_table: Rc::new(RoaTable::default()),
+            aspa_table: Rc::new(AspaTable::default()),
         }
     }
 
     fn phase3_disseminate_route(&mut self, loc_rib: &LocRib) {
-        // ToDo: nexthopが存在するかなどのチェックや、
-        // ルートが消えることのチェックを行っていない。
-        let mut loc_rib = loc_rib.clone();
+        // nexthopに到達できないpathはNexthopTrackerがUnreachableにしていて、best pathに選ばれない
+        // ADD-PATHがnegotiateされていれば1つのdestinationに対して複数のpathを広告する
+        let add_path_send = self.get_negotiated_capabilities().add_path_send;
+        let mut best_paths = if add_path_send {
+            loc_rib.select_best_n_paths(self.config.add_path_best)
+        } else {
+            loc_rib.select_best_paths()
+        };
+        for entry in &mut best_paths {
+            entry.path_identifier = if add_path_send { entry.local_path_identifier } else { 0 };
+        }
+        // summary-onlyの集約routeに含まれる細かいrouteは広告しない
+        let summary_only_prefixes: Vec<IpPrefix> = best_paths.iter()
+            .filter(|entry| entry.source == RouteSource::Aggregate { summary_only: true })
+            .map(|entry| entry.destnation_address)
+            .collect();
+        best_paths.retain(|entry| !summary_only_prefixes.iter().any(|prefix| prefix.is_less_specific_than(&entry.destnation_address)));
         self.adj_rib_out.change_state_of_all_routing_information_to_unchanged();
-        for entry in &mut loc_rib.0 {
-            entry.add_as_path(self.config.as_number.0);
-            entry.change_nexthop(self.config.my_ip_addr);
-        }
-        // remote as がas pathにはいってたらriboutに追加しない
-        for entry in loc_rib.0 {
-            if !entry.get_as_path().does_have_the_as_number(&self.config.remote_as_number) {
-                self.adj_rib_out.add_one_entry(entry);
+        let is_external_peer = !self.config.is_internal_peer() && !self.config.is_confederation_external_peer();
+        if is_external_peer {
+            // 受け取ったMEDは他のASには渡さない。export policyで付けたMEDは広告する
+            for entry in &mut best_paths {
+                entry.remove_multi_exit_disc();
+            }
+        }
+        // export policyはAS_PATHやnexthopを書き換える前のLoc-RIBのattributeに対して適用する
+        if let Some(export_policy) = &self.config.export_policy {
+            let local_as_number = self.config.get_local_as_number_for_peer();
+            best_paths = best_paths.iter()
+                .filter_map(|entry| export_policy.apply(entry, &local_as_number, self.config.my_ip_addr))
+                .collect();
+        }
+        if self.config.is_internal_peer() {
+            // iBGPで学習したrouteは基本的に他のiBGP peerには広告しない。
+            // ただしRoute Reflector(RFC4456)として以下はreflectする。
+            // - clientから学習したroute: 学習元以外の全てのiBGP peerへ
+            // - non-clientから学習したroute: clientへ
+            let is_client = self.config.route_reflector_client;
+            let remote_ip_addr = self.config.remote_ip_addr;
+            best_paths.retain(|entry| match entry.source {
+                RouteSource::Internal(peer) => is_client && peer != remote_ip_addr,
+                RouteSource::RouteReflectorClient(peer) => peer != remote_ip_addr,
+                _ => true,
+            });
+            // iBGPではAS_PATHとnexthopはそのままで、LOCAL_PREFを付けて広告する
+            for entry in &mut best_paths {
+                let local_pref = entry.get_local_pref();
+                entry.set_local_pref(local_pref);
+                if entry.source.is_internal() {
+                    // RFC4456 8: reflectするときに学習元のpeerのBGP IdentifierをORIGINATOR_IDとして付ける
+                    if let Some(peer_bgp_identifier) = entry.peer_bgp_identifier {
+                        entry.set_originator_id_if_absent(peer_bgp_identifier);
+                    }
+                    entry.prepend_cluster_id(self.config.get_cluster_id());
+                }
+            }
+        } else if self.config.is_confederation_external_peer() {
+            // confederation内の別のmember ASへはAS_CONFED_SEQUENCEに自分のASを追加し、
+            // nexthopとLOCAL_PREFはiBGPと同じように扱う
+            for entry in &mut best_paths {
+                entry.add_confederation_as_path(self.config.as_number.0);
+                let local_pref = entry.get_local_pref();
+                entry.set_local_pref(local_pref);
+                entry.remove_route_reflector_attributes();
+            }
+            best_paths.retain(|entry| !entry.get_as_path().does_have_the_as_number(&self.config.remote_as_number));
+        } else {
+            // confederationの外へはconfederationのsegmentを取り除き、
+            // confederation identifierを自分のASとして追加する
+            for entry in &mut best_paths {
+                entry.remove_confederation_as_path();
+                entry.add_as_path(self.config.get_public_as_number().0);
+                entry.change_nexthop(self.config.my_ip_addr);
+                entry.remove_local_pref();
+                entry.remove_route_reflector_attributes();
+            }
+            // remote as がas pathにはいってたらriboutに追加しない
+            best_paths.retain(|entry| !entry.get_as_path().does_have_the_as_number(&self.config.remote_as_number));
+            // RFC9234 5: OTC attributeのegress処理
+            // OTCの付いたrouteはprovider, peer, RSには広告せず、
+            // customer, peer, RS-Clientへ広告するrouteにはOTCを付ける
+            if let Some(local_role) = self.config.get_local_role() {
+                if !local_role.is_peer_downstream() {
+                    best_paths.retain(|entry| entry.get_only_to_customer().is_none());
+                }
+                if local_role.is_sent_to_customer() {
+                    let local_as_number = u32::from(self.config.get_public_as_number().0);
+                    for entry in &mut best_paths {
+                        entry.set_only_to_customer_if_absent(local_as_number);
+                    }
+                }
+            }
+        }
+        // 同じprefixとpath identifierで別のpeerから学習したpathを広告し直すなら、UPDATEで置き換わるのでwithdrawしない
+        self.adj_rib_out.0.retain(|entry| best_paths.contains(entry) || !best_paths.iter().any(|path| {
+            path.destnation_address == entry.destnation_address && path.path_identifier == entry.path_identifier
+        }));
+        // 前回広告したがbest pathではなくなったroute、policyでrejectされたrouteはwithdrawする
+        for entry in &mut self.adj_rib_out.0 {
+            if !best_paths.contains(entry) {
+                entry.status = RoutingInformationStatus::Withdrawn;
+            }
+        }
+        for entry in best_paths {
+            self.adj_rib_out.add_one_entry(entry);
+        }
+    }
+
+    fn local_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = vec![Capability::RouteRefresh, Capability::EnhancedRouteRefresh, Capability::ExtendedMessage];
+        if self.config.graceful_restart {
+            // 再起動中でカーネルのrouteを残しているならforwarding stateを保持している
+            capabilities.push(Capability::GracefulRestart {
+                restart_state: self.graceful_restart_state,
+                restart_time: self.config.restart_time,
+                forwarding_state: self.graceful_restart_state,
+            });
+        }
+        if let Some(role) = self.config.get_local_role() {
+            capabilities.push(Capability::Role(role));
+        }
+        if self.config.add_path_receive || self.config.add_path_send {
+            capabilities.push(Capability::AddPath {
+                receive: self.config.add_path_receive,
+                send: self.config.add_path_send,
+            });
+        }
+        capabilities
+    }
+
+    pub fn get_negotiated_capabilities(&self) -> NegotiatedCapabilities {
+        let mut negotiated_capabilities = NegotiatedCapabilities::default();
+        for capability in &self.session_attribute.peer_capabilities {
+            match capability {
+                Capability::AddPath { receive, send } => {
+                    negotiated_capabilities.add_path_receive = self.config.add_path_receive && *send;
+                    negotiated_capabilities.add_path_send = self.config.add_path_send && *receive;
+                },
+                Capability::ExtendedMessage => {
+                    // Extended Messageは常に広告しているのでpeerが広告していればnegotiateできる
+                    negotiated_capabilities.extended_message = true;
+                },
+                _ => (),
+            }
+        }
+        negotiated_capabilities
+    }
+
+    fn get_peer_restart_time(&self) -> Option<Duration> {
+        // peerとGraceful Restartがnegotiateされていればpeerのrestart timeを返す
+        if !self.config.graceful_restart {
+            return None;
+        }
+        for capability in &self.session_attribute.peer_capabilities {
+            match capability {
+                Capability::GracefulRestart { restart_time, .. } => return Some(Duration::from_secs((*restart_time).into())),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn is_graceful_restart_enabled(&self) -> bool {
+        self.config.graceful_restart
+    }
+
+    pub fn get_restart_time(&self) -> Duration {
+        Duration::from_secs(self.config.restart_time.into())
+    }
+
+    pub fn set_graceful_restart_state(&mut self, graceful_restart_state: bool) {
+        self.graceful_restart_state = graceful_restart_state;
+    }
+
+    pub fn is_end_of_rib_received(&self) -> bool {
+        self.session_attribute.end_of_rib_received.contains(&(1, 1))
+    }
+
+    pub fn is_end_of_rib_sent(&self) -> bool {
+        self.session_attribute.end_of_rib_sent.contains(&(1, 1))
+    }
+
+    pub fn is_initial_synchronization_done(&self) -> bool {
+        // 初期のrouteの交換がお互いに終わっているか
+        self.is_end_of_rib_sent() && self.is_end_of_rib_received()
+    }
+
+    fn send_end_of_rib_if_needed(&mut self) {
+        // 最初にAdj-RIB-Outを全て送り終えたらEnd-of-RIBを送る
+        // ToDo: IPv4 unicast以外のaddress familyに対応する
+        let address_family = (1, 1);
+        if self.session_attribute.end_of_rib_sent.contains(&address_family) {
+            return;
+        }
+        let end_of_rib = BgpUpdateMessage::new_end_of_rib(address_family.0, address_family.1);
+        let end_of_rib = end_of_rib.decode();
+        self.send_message(&end_of_rib);
+        self.session_attribute.end_of_rib_sent.push(address_family);
+    }
+
+    pub fn check_timers(&mut self) {
+        if let Some(restart_timer) = self.session_attribute.restart_timer {
+            if restart_timer.elapsed().unwrap_or_default() >= self.session_attribute.restart_time {
+                self.session_attribute.restart_timer = None;
+                self.event_queue.push(Event::GracefulRestartTimerExpires);
+            }
+        }
+        if let Some(dampening) = &mut self.dampening {
+            // 抑制していたprefixが使えるようになったらLoc-RIBに入れ直す
+            if !dampening.reuse_prefixes_if_possible().is_empty() {
+                self.event_queue.push(Event::AdjRibInChanged);
+            }
+        }
+        if let (Some(max_prefix_restart_timer), Some(max_prefix_restart_time)) = (self.session_attribute.max_prefix_restart_timer, self.config.max_prefix_restart_time) {
+            if max_prefix_restart_timer.elapsed().unwrap_or_default() >= Duration::from_secs(max_prefix_restart_time) {
+                self.session_attribute.max_prefix_restart_timer = None;
+                self.event_queue.push(Event::ManualStart);
+            }
+        }
+    }
+
+    pub fn update_rpki_tables(&mut self, roa_table: Rc<RoaTable>, aspa_table: Rc<AspaTable>) {
+        self.roa_table = roa_table;
+        self.aspa_table = aspa_table;
+        self.event_queue.push(Event::RpkiTableChanged);
+    }
+
+    pub fn set_incoming_connection(&mut self, tcp_connection: TcpStream) {
+        // peerからのconnectionはpassiveでIdleのときだけ受け付ける (collision detectionは実装していない)
+        if self.config.mode != Mode::Passive || !matches!(self.session_attribute.state, State::Idle) {
+            debug!("ignore the connection from {} in {:?}", self.config.remote_ip_addr, self.session_attribute.state);
+            return;
+        }
+        self.incoming_connection = Some(tcp_connection);
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_adj_rib_in(&self) -> &AdjRibIn {
+        &self.adj_rib_in
+    }
+
+    pub fn get_dampening_states(&self) -> Vec<DampeningState> {
+        match &self.dampening {
+            Some(dampening) => dampening.get_states().clone(),
+            None => vec![],
+        }
+    }
+
+    fn is_suppressed_by_dampening(&self, prefix: &IpPrefix) -> bool {
+        match &self.dampening {
+            Some(dampening) => dampening.is_suppressed(prefix),
+            None => false,
+        }
+    }
+
+    fn number_of_prefixes(&self, address_family: (u16, u8)) -> usize {
+        // ToDo: IPv4 unicast以外のaddress familyに対応する
+        if address_family != (1, 1) {
+            return 0;
+        }
+        // ADD-PATHでは1つのprefixに複数のpathが来るので、pathの数ではなくprefixの数を数える
+        self.adj_rib_in.0.iter()
+            .map(|entry| entry.destnation_address)
+            .collect::<HashSet<IpPrefix>>()
+            .len()
+    }
+
+    fn find_exceeded_max_prefix(&mut self) -> Option<((u16, u8), usize)> {
+        // address familyごとに上限の閾値を超えたら一度だけ警告し、上限を超えたaddress familyとその上限を返す
+        let mut exceeded = None;
+        for (address_family, max_prefix) in self.config.max_prefix.clone() {
+            let number_of_prefixes = self.number_of_prefixes(address_family);
+            if !self.session_attribute.max_prefix_warned.contains(&address_family)
+                && number_of_prefixes * 100 >= max_prefix * self.config.max_prefix_warning_threshold {
+                warn!("{} prefixes of {:?} received from {} (max-prefix is {})",
+                    number_of_prefixes, address_family, self.config.remote_ip_addr, max_prefix);
+                self.session_attribute.max_prefix_warned.push(address_family);
+            }
+            if exceeded.is_none() && number_of_prefixes > max_prefix {
+                exceeded = Some((address_family, max_prefix));
+            }
+        }
+        exceeded
+    }
+
+    fn remove_stale_routes(&mut self, loc_rib: &mut LocRib) {
+        let stale_routes = self.adj_rib_in.remove_stale_routes();
+        if !stale_routes.is_empty() {
+            // カーネルのroutingテーブルからは次のsync_fibでFibManagerが消す
+            loc_rib.remove_routes(&stale_routes);
+            self.event_queue.push(Event::LocRibChanged);
+        }
+    }
+
+    fn delete_routes_or_mark_as_stale(&mut self, loc_rib: &mut LocRib, is_graceful: bool) {
+        // Graceful Restartがnegotiateされていればpeerが戻ってくるまでrouteをstaleとして残し、
+        // そうでなければこのpeerから学習したrouteを全て消す
+        match self.get_peer_restart_time() {
+            Some(restart_time) if is_graceful => {
+                self.adj_rib_in.mark_all_routes_as_stale();
+                self.session_attribute.restart_timer = Some(SystemTime::now());
+                self.session_attribute.restart_time = restart_time;
+            },
+            _ => {
+                self.adj_rib_in.mark_all_routes_as_stale();
+                self.remove_stale_routes(loc_rib);
+            },
+        }
+    }
+
+    fn release_bgp_resources(&mut self) {
+        if let Some(tcp_connection) = self.tcp_connection.take() {
+            let _ = tcp_connection.shutdown(std::net::Shutdown::Both);
+        }
+        self.packet_buffer = vec![];
+        self.data_buffer = DataBuffer::new();
+        self.send_buffer = vec![];
+        self.packet_queue = PacketQueue::new();
+        // 次のsessionでは全てのrouteを広告し直す
+        self.adj_rib_out = AdjRibOut::new(vec![]);
+    }
+
+    fn stop_session(&mut self, loc_rib: &mut LocRib, subcode: CeaseSubcode) {
+        // NOTIFICATION(Cease)を送ってsessionを閉じ、このpeerから学習したrouteを消してIdleに戻る
+        let notification_message = BgpNotificationMessage::new_cease(subcode).decode();
+        self.send_message(&notification_message);
+        self.delete_routes_or_mark_as_stale(loc_rib, false);
+        self.session_attribute.connect_retry_timer = SystemTime::now();
+        self.release_bgp_resources();
+        self.session_attribute.connect_retry_counter = 0;
+        self.session_attribute.state = State::Idle;
+    }
+
+    pub fn stop(&mut self, loc_rib: &mut LocRib, subcode: CeaseSubcode) {
+        // 設定のreloadでpeerを消したり、sessionを張り直すときに使う。
+        // OPENを送る前ならNOTIFICATIONは送らずにTCP connectionだけ閉じる
+        match self.session_attribute.state {
+            State::OpenSent | State::OpenConfirm | State::Established => self.stop_session(loc_rib, subcode),
+            _ => {
+                self.release_bgp_resources();
+                self.session_attribute.state = State::Idle;
+            },
+        }
+        self.event_queue = EventQueue::new();
+        self.session_attribute.max_prefix_restart_timer = None;
+    }
+
+    pub fn shutdown(&mut self, loc_rib: &mut LocRib) {
+        // daemonを終了するときに使う。
+        // RFC4724: Graceful RestartがnegotiateされていればNOTIFICATIONを送らずにTCP connectionだけ閉じる。
+        // NOTIFICATIONを受け取ったpeerはこちらのrouteを消してしまい、再起動の間の転送が止まる
+        let is_graceful = matches!(self.session_attribute.state, State::Established) && self.get_peer_restart_time().is_some();
+        if !is_graceful {
+            self.stop(loc_rib, CeaseSubcode::AdministrativeShutdown);
+            return;
+        }
+        self.release_bgp_resources();
+        self.session_attribute.state = State::Idle;
+        self.event_queue = EventQueue::new();
+    }
+
+    pub fn update_config(&mut self, config: Config) {
+        // sessionを張り直さずに設定を変える (Config::requires_session_resetがfalseの変更だけ)。
+        // import policyの変更はAdj-RIB-Inから、それ以外はLoc-RIBから広告し直して反映する
+        if config == self.config {
+            return;
+        }
+        let is_import_policy_changed = config.import_policy != self.config.import_policy;
+        if config.dampening != self.config.dampening {
+            self.dampening = config.dampening.map(Dampening::new);
+        }
+        self.config = config;
+        if let State::Established = self.session_attribute.state {
+            self.event_queue.push(Event::LocRibChanged);
+            if is_import_policy_changed {
+                self.event_queue.push(Event::ImportPolicyChanged);
+            }
+        }
+    }
+
+    fn send_bad_message_length_notification(&mut self) {
+        // header checkでErrになったmessageはdata bufferの先頭に残っている
+        if self.data_buffer.buf.len() < 18 {
+            return;
+        }
+        let length = u16::from_be_bytes(self.data_buffer.buf[16..18].try_into().unwrap());
+        let notification_message = BgpNotificationMessage::new_bad_message_length(length).decode();
+        self.send_message(&notification_message);
+    }
+
+    fn send_queued_notification(&mut self) {
+        // messageのerrorを見つけたときにbgp_packet_handlerがpacket queueに積んだNOTIFICATIONを送る
+        if let Some(BgpMessage::Notification(notification_message)) = self.packet_queue.pop() {
+            let notification_message = notification_message.decode();
+            self.send_message(&notification_message);
+        }
+    }
+
+    fn is_role_mismatched(&self) -> bool {
+        // RFC9234 4.2: peerのroleが自分のroleと組み合わせとして正しくなければRole Mismatch
+        let local_role = match self.config.get_local_role() {
+            Some(role) => role,
+            None => return false,
+        };
+        let peer_roles: Vec<Role> = self.session_attribute.peer_capabilities.iter()
+            .filter_map(|capability| match capability {
+                Capability::Role(role) => Some(*role),
+                _ => None,
+            })
+            .collect();
+        match peer_roles.first() {
+            // 異なるroleが複数広告されていてもRole Mismatch
+            Some(peer_role) => peer_roles.iter().any(|role| role != peer_role) || !local_role.is_compatible_with(*peer_role),
+            None => self.config.role_strict,
+        }
+    }
+
+    fn accept_only_to_customer(&self, entry: &mut RoutingInformationEntry) -> bool {
+        // RFC9234 5: OTC attributeのingress処理。route leakならfalseを返す
+        let local_role = match self.config.get_local_role() {
+            Some(role) => role,
+            None => return true,
+        };
+        let remote_as_number = u32::from(self.config.remote_as_number.0);
+        match entry.get_only_to_customer() {
+            // customerやRS-Clientから受け取ったrouteにOTCがあればleak
+            Some(_) if local_role.is_peer_downstream() => false,
+            // peerから受け取ったrouteのOTCがpeerのAS番号でなければleak
+            Some(as_number) if local_role == Role::Peer => as_number == remote_as_number,
+            Some(_) => true,
+            // provider, peer, RSから受け取ったrouteにはpeerのAS番号でOTCを付ける
+            None => {
+                if !local_role.is_peer_downstream() {
+                    entry.set_only_to_customer_if_absent(remote_as_number);
+                }
+                true
+            },
+        }
+    }
+
+    fn is_capability_negotiated(&self, capability: &Capability) -> bool {
+        self.local_capabilities().contains(capability)
+            && self.session_attribute.peer_capabilities.contains(capability)
+    }
+
+    fn send_message(&mut self, message: &[u8]) {
+        // messageの途中までしか送れなくてもbyte列が壊れないように、send bufferに積んでから送る
+        self.send_buffer.extend_from_slice(message);
+        self.flush_send_buffer();
+    }
+
+    pub fn flush_send_buffer(&mut self) {
+        // socketはnon-blockingなので、送れなかった分はsend bufferに残して次に呼ばれたときに送る
+        let mut tcp_connection = match self.tcp_connection.as_ref() {
+            Some(tcp_connection) => tcp_connection,
+            None => return,
+        };
+        while !self.send_buffer.is_empty() {
+            match tcp_connection.write(&self.send_buffer) {
+                Ok(0) => {
+                    self.event_queue.push(Event::TcpConnectionFails);
+                    break;
+                },
+                Ok(length) => {
+                    self.send_buffer.drain(..length);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("cannot send the message to {}: {}", self.config.remote_ip_addr, e);
+                    self.event_queue.push(Event::TcpConnectionFails);
+                    break;
+                },
             }
         }
     }
 
-    fn send_update_message(&self) {
-        ()
+    fn send_update_message(&mut self) {
+        let bgp_update_messages = BgpUpdateMessage::are_created_from_adj_rib_out(&self.adj_rib_out, &self.get_negotiated_capabilities());
+        for bgp_update_message in bgp_update_messages {
+            let bgp_update_message = bgp_update_message.decode();
+            self.send_message(&bgp_update_message);
+        }
+        self.adj_rib_out.remove_withdrawn_routes();
+        self.adj_rib_out.change_state_of_all_routing_information_to_unchanged();
+        self.send_end_of_rib_if_needed();
+    }
+
+    fn send_route_refresh_message(&mut self, subtype: RouteRefreshSubtype) {
+        let route_refresh_message = BgpRouteRefreshMessage::new(subtype);
+        let route_refresh_message = route_refresh_message.decode();
+        self.send_message(&route_refresh_message);
+    }
+
+    fn replay_adj_rib_out(&mut self) {
+        // Adj-RIB-Outの全てのrouteを再送する。
+        // Enhanced Route Refreshがnegotiateされていれば前後をBoRRとEoRRで挟む。
+        let is_enhanced = self.is_capability_negotiated(&Capability::EnhancedRouteRefresh);
+        if is_enhanced {
+            self.send_route_refresh_message(RouteRefreshSubtype::BeginningOfRouteRefresh);
+        }
+        self.adj_rib_out.change_state_of_all_routing_information_to_updated();
+        self.send_update_message();
+        if is_enhanced {
+            self.send_route_refresh_message(RouteRefreshSubtype::EndOfRouteRefresh);
+        }
     }
 
     pub fn get_state(&self) -> &State {
         self.session_attribute.get_state()
     }
 
-    pub async fn handle_event(&mut self, event: &Event, loc_rib: &mut LocRib) {
-        println!("{:?}", event);
+    pub async fn handle_event(&mut self, event: &Event, loc_rib: &mut LocRib, fib: &dyn Fib) {
+        debug!("{:?}", event);
+        if let &Event::GracefulRestartTimerExpires = event {
+            // restart timerはsessionの状態に関係なく処理する
+            self.remove_stale_routes(loc_rib);
+            return;
+        }
         match self.get_state() {
             &State::Idle => {
                 match event {
@@ -149,22 +683,28 @@ impl fsm {
                         // - listens for a connection that may be initiated by the remote
                         //   BGP peer, and
                         // - changes its state to Connect.
-                        self.packet_buffer = [0u8; 1024];
+                        self.packet_buffer = vec![];
                         self.session_attribute.connect_retry_counter = 0;
                         self.session_attribute.connect_retry_timer = SystemTime::now();
-                        self.session_attribute.connect_retry_time = std::time::Duration::from_secs(120);
+                        self.session_attribute.connect_retry_time = std::time::Duration::from_secs(self.config.connect_retry_time);
                         self.tcp_connection = match &self.config.mode {
                             &Mode::Active =>
                                 net::TcpStream::connect(format!("{}:{}", &self.config.remote_ip_addr, "179")).ok(),
-                            &Mode::Passive =>
-                                Some(self.tcp_listener.accept().unwrap().0),
+                            &Mode::Passive => match self.incoming_connection.take() {
+                                Some(tcp_connection) => Some(tcp_connection),
+                                None => {
+                                    // peerからconnectionが来るまでIdleで待つ
+                                    self.event_queue.push(Event::ManualStart);
+                                    return;
+                                },
+                            },
                         };
-                        if self.tcp_connection.is_some() {
+                        if let Some(tcp_connection) = self.tcp_connection.as_ref() {
+                            tcp_connection.set_nonblocking(true).unwrap();
                             self.event_queue.push(Event::TcpConnectionConfirmed);
                         } else {
                             self.event_queue.push(Event::TcpConnectionFails);
                         }
-                        self.tcp_connection.as_ref().unwrap().set_nonblocking(true).unwrap();
                         self.session_attribute.state = State::Connect;
                     },
                     _ => (),
@@ -177,7 +717,7 @@ impl fsm {
                         self.tcp_connection.as_ref().unwrap().shutdown(std::net::Shutdown::Both).unwrap();
                         self.tcp_connection = None;
                         // - releases all BGP resources,
-                        self.packet_buffer = [0u8; 1024];
+                        self.packet_buffer = vec![];
                         // - sets ConnectRetryCounter to zero,
                         self.session_attribute.connect_retry_counter = 0;
                         // - stops the ConnectRetryTimer and sets ConnectRetryTimer to
@@ -211,11 +751,13 @@ impl fsm {
                         // A HoldTimer value of 4 minutes is suggested.
                         self.session_attribute.connect_retry_timer = SystemTime::now();
                         let open_message = BgpOpenMessage::new(
-                            self.config.as_number,
-                            self.config.my_ip_addr,
+                            self.config.get_local_as_number_for_peer(),
+                            self.config.get_router_id(),
+                            self.config.get_hold_time(),
+                            self.local_capabilities(),
                         );
                         let open_message = open_message.decode();
-                        self.tcp_connection.as_ref().unwrap().write(&open_message[..]).expect("cannot send open message");
+                        self.send_message(&open_message);
                         self.session_attribute.hold_time = time::Duration::from_secs(4 * 60);
                         self.session_attribute.hold_timer = SystemTime::now();
                         self.session_attribute.state = State::OpenSent;
@@ -284,6 +826,7 @@ impl fsm {
                         // - drops the TCP connection,
                         // - sets the ConnectRetryCounter to zero, and
                         // - changes its state to Idle.
+                        self.stop_session(loc_rib, CeaseSubcode::AdministrativeShutdown);
                     },
                     &Event::HoldTimerExpires => {
                         // - sends a NOTIFICATION message with the error code Hold Timer
@@ -307,10 +850,15 @@ impl fsm {
                         // If a TcpConnectionFails event (Event 18) is received, the local
                         // system:
                         // - closes the BGP connection,
+                        self.release_bgp_resources();
                         // - restarts the ConnectRetryTimer,
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
                         // - continues to listen for a connection that may be initiated by
                         //   the remote BGP peer, and
                         // - changes its state to Active.
+                        // Active stateは実装していないのでIdleからやり直す
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
                     },
                     &Event::BgpOpen => {
                         // When an OPEN message is received, all fields are checked for
@@ -323,14 +871,37 @@ impl fsm {
                         //   - sets the HoldTimer according to the negotiated value (see
                         //     Section 4.2),
                         //   - changes its state to OpenConfirm.
+                        let bgp_open_message = match self.packet_queue.pop().unwrap() {
+                            BgpMessage::Open(d) => d,
+                            _ => panic!(),
+                        };
+                        self.session_attribute.peer_bgp_identifier = Some(bgp_open_message.get_bgp_identifier());
+                        self.session_attribute.peer_capabilities = bgp_open_message.get_capabilities();
+                        if self.is_role_mismatched() {
+                            // RFC9234: Role Mismatchの場合はOPEN Message ErrorのNOTIFICATIONを送り、
+                            // BgpOpenMsgErrと同じようにIdleに戻る
+                            warn!("role mismatch with {}", self.config.remote_ip_addr);
+                            let notification_message = BgpNotificationMessage::new_role_mismatch().decode();
+                            self.send_message(&notification_message);
+                            self.session_attribute.connect_retry_timer = SystemTime::now();
+                            self.release_bgp_resources();
+                            self.session_attribute.connect_retry_counter += 1;
+                            self.session_attribute.state = State::Idle;
+                            self.event_queue.push(Event::ManualStart);
+                            return;
+                        }
+                        self.session_attribute.max_prefix_warned = vec![];
+                        self.session_attribute.end_of_rib_sent = vec![];
+                        self.session_attribute.end_of_rib_received = vec![];
                         self.session_attribute.connect_retry_timer = SystemTime::now();
                         let keepalive_message = BgpKeepaliveMessage::new();
                         let raw_data = keepalive_message.decode_to_u8();
-                        self.tcp_connection.as_ref().unwrap().write(&raw_data[..]).unwrap();
+                        self.send_message(&raw_data);
 
                         self.session_attribute.hold_timer = SystemTime::now();
-                        // ToDo: Nego wo RFC doori ni yaru (mijikai hou wo saiyou suru)
-                        self.session_attribute.hold_time = time::Duration::from_secs(90);
+                        // RFC4271 4.2: 自分が提案したHold Timeとpeerが提案したHold Timeの短いほうを使う
+                        let hold_time = self.config.get_hold_time().min(bgp_open_message.get_hold_time());
+                        self.session_attribute.hold_time = time::Duration::from_secs(hold_time.into());
 
                         self.session_attribute.keepalive_timer = SystemTime::now();
                         self.session_attribute.keepalive_time = self.session_attribute.hold_time / 3;
@@ -348,13 +919,23 @@ impl fsm {
                     }
                     &Event::BgpHeaderErr | &Event::BgpOpenMsgErr => {
                         // - sends a NOTIFICATION message with the appropriate error code,
+                        if matches!(event, &Event::BgpHeaderErr) {
+                            self.send_bad_message_length_notification();
+                        } else {
+                            self.send_queued_notification();
+                        }
                         // - sets the ConnectRetryTimer to zero,
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
                         // - releases all BGP resources,
                         // - drops the TCP connection,
+                        self.release_bgp_resources();
                         // - increments the ConnectRetryCounter by 1,
+                        self.session_attribute.connect_retry_counter += 1;
                         // - (optionally) performs peer oscillation damping if the
                         //   DampPeerOscillations attribute is TRUE, and
                         // - changes its state to Idle.
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
                     },
                     &Event::NotifMsgVerErr => {
                         // If a NOTIFICATION message is received with a version error
@@ -395,6 +976,7 @@ impl fsm {
                         //   - sets the ConnectRetryCounter to zero,
                         //   - sets the ConnectRetryTimer to zero, and
                         //   - changes its state to Idle.
+                        self.stop_session(loc_rib, CeaseSubcode::AdministrativeShutdown);
                     },
                     &Event::HoldTimerExpires => {
                         //   If the HoldTimer_Expires event (Event 10) occurs before a
@@ -427,12 +1009,17 @@ impl fsm {
                         // from the underlying TCP or a NOTIFICATION message (Event 25), the
                         // local system:
                         //   - sets the ConnectRetryTimer to zero,
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
                         //   - releases all BGP resources,
                         //   - drops the TCP connection,
+                        self.release_bgp_resources();
                         //   - increments the ConnectRetryCounter by 1,
+                        self.session_attribute.connect_retry_counter += 1;
                         //   - (optionally) performs peer oscillation damping if the
                         //     DampPeerOscillations attribute is set to TRUE, and
                         //   - changes its state to Idle.
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
                     },
                     &Event::NotifMsgVerErr => {
                         // If the local system receives a NOTIFICATION message with a version
@@ -462,13 +1049,23 @@ impl fsm {
                         // (Event 21)) or OPEN message checking detects an error (see Section
                         // 6.2) (BGPOpenMsgErr (Event 22)), the local system:
                         //   - sends a NOTIFICATION message with the appropriate error code,
+                        if matches!(event, &Event::BgpHeaderErr) {
+                            self.send_bad_message_length_notification();
+                        } else {
+                            self.send_queued_notification();
+                        }
                         //   - sets the ConnectRetryTimer to zero,
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
                         //   - releases all BGP resources,
                         //   - drops the TCP connection,
+                        self.release_bgp_resources();
                         //   - increments the ConnectRetryCounter by 1,
+                        self.session_attribute.connect_retry_counter += 1;
                         //   - (optionally) performs peer oscillation damping if the
                         //     DampPeerOscillations attribute is set to TRUE, and
                         //   - changes its state to Idle.
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
                     },
                     &Event::KeepAliveMsg => {
                         // If the local system receives a KEEPALIVE message (KeepAliveMsg
@@ -477,13 +1074,21 @@ impl fsm {
                         //      - changes its state to Established.
                         self.session_attribute.hold_timer = SystemTime::now();
                         self.session_attribute.state = State::Established;
-                        let mut routes = lookup_network_route(&self.config.advertisement_network).await.unwrap();
+                        let routes = match fib.list().await {
+                            Ok(routes) => routes.into_iter()
+                                .filter(|route| self.config.advertisement_networks.iter().any(|network| network.does_include(&route.destination)))
+                                .collect(),
+                            Err(e) => {
+                                error!("cannot read the routes in the kernel: {}", e);
+                                vec![]
+                            },
+                        };
                         let origin = PathAttribute::Origin(Origin::Igp);
-                        let as_path = PathAttribute::AsPath(AsPath::AsSequence(vec![]));
+                        let as_path = PathAttribute::AsPath(AsPath::new(vec![]));
                         let next_hop = PathAttribute::NextHop(self.config.my_ip_addr);
                         let path_attributes = vec![origin, as_path, next_hop];
 
-                        loc_rib.add_from_route_message(&mut routes, path_attributes);
+                        loc_rib.add_from_fib_routes(&routes, path_attributes);
                         self.event_queue.push(Event::LocRibChanged);
                     },
                     _ => {
@@ -511,6 +1116,7 @@ impl fsm {
                         //   - drops the TCP connection,
                         //   - sets the ConnectRetryCounter to zero, and
                         //   - changes its state to Idle.
+                        self.stop_session(loc_rib, CeaseSubcode::AdministrativeShutdown);
                     },
                     &Event::HoldTimerExpires => {
                         // If the HoldTimer_Expires event occurs (Event 10), the local
@@ -563,11 +1169,23 @@ impl fsm {
                         // Event 25) or a TcpConnectionFails (Event 18) from the underlying
                         // TCP, the local system:
                         //       - sets the ConnectRetryTimer to zero,
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
                         //       - deletes all routes associated with this connection,
+                        //         (RFC4724: TCPのsessionが切れただけならGraceful Restartとして
+                        //          routeをstaleにして残しておく)
+                        let is_graceful = match event {
+                            &Event::TcpConnectionFails => true,
+                            _ => false,
+                        };
+                        self.delete_routes_or_mark_as_stale(loc_rib, is_graceful);
                         //       - releases all the BGP resources,
                         //       - drops the TCP connection,
+                        self.release_bgp_resources();
                         //       - increments the ConnectRetryCounter by 1,
+                        self.session_attribute.connect_retry_counter += 1;
                         //       - changes its state to Idle.
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
                     },
                     &Event::KeepAliveMsg => {
                         // If the local system receives a KEEPALIVE message (Event 26), the
@@ -587,7 +1205,81 @@ impl fsm {
                             BgpMessage::Update(d) => d,
                             _ => panic!(),
                         };
-                        self.adj_rib_in.add_from_update_message(bgp_update_message, &self.config.as_number);
+                        if let Some(address_family) = bgp_update_message.get_end_of_rib_address_family() {
+                            // peerからの初期のrouteの送信が終わったので残っているstaleなrouteを消す
+                            info!("received end of rib for {:?}", address_family);
+                            if !self.session_attribute.end_of_rib_received.contains(&address_family) {
+                                self.session_attribute.end_of_rib_received.push(address_family);
+                            }
+                            if address_family == (1, 1) {
+                                self.session_attribute.restart_timer = None;
+                                self.remove_stale_routes(loc_rib);
+                            }
+                            return;
+                        }
+                        let withdrawn_routes = self.adj_rib_in.remove_routes_by_nlri(bgp_update_message.get_withdrawn_routes());
+                        if let Some(dampening) = &mut self.dampening {
+                            // withdrawされたprefixにpenaltyを加える
+                            for entry in &withdrawn_routes {
+                                dampening.add_withdraw_penalty(&entry.destnation_address);
+                            }
+                        }
+                        if !withdrawn_routes.is_empty() {
+                            loc_rib.remove_routes(&withdrawn_routes);
+                            self.event_queue.push(Event::LocRibChanged);
+                        }
+                        let source = if self.config.is_internal_peer() && self.config.route_reflector_client {
+                            RouteSource::RouteReflectorClient(self.config.remote_ip_addr)
+                        } else if self.config.is_internal_peer() {
+                            RouteSource::Internal(self.config.remote_ip_addr)
+                        } else if self.config.is_confederation_external_peer() {
+                            RouteSource::ConfederationExternal(self.config.remote_ip_addr)
+                        } else {
+                            RouteSource::External(self.config.remote_ip_addr)
+                        };
+                        let previous_routes = if self.dampening.is_some() {
+                            self.adj_rib_in.0.clone()
+                        } else {
+                            vec![]
+                        };
+                        self.adj_rib_in.add_from_update_message(bgp_update_message, &self.config.as_number, source);
+                        if let Some(dampening) = &mut self.dampening {
+                            // attributeが変わったprefixにpenaltyを加える
+                            for entry in &self.adj_rib_in.0 {
+                                let is_changed = previous_routes.iter().any(|previous| previous.destnation_address == entry.destnation_address
+                                    && previous.path_identifier == entry.path_identifier
+                                    && previous.path_attributes != entry.path_attributes);
+                                if is_changed {
+                                    dampening.add_attribute_change_penalty(&entry.destnation_address);
+                                }
+                            }
+                        }
+                        if let Some((address_family, max_prefix)) = self.find_exceeded_max_prefix() {
+                            // RFC4486: 上限を超えたaddress familyをCeaseのdataに入れて切断し、
+                            // max-prefix-restartが設定されていればその時間だけIdleで待ってから再接続する
+                            warn!("max-prefix of {:?} exceeded for {}, so close the session", address_family, self.config.remote_ip_addr);
+                            let max_prefix: u32 = max_prefix.try_into().unwrap_or(u32::MAX);
+                            let notification_message = BgpNotificationMessage::new_maximum_number_of_prefixes_reached(address_family.0, address_family.1, max_prefix).decode();
+                            self.send_message(&notification_message);
+                            self.delete_routes_or_mark_as_stale(loc_rib, false);
+                            self.session_attribute.connect_retry_timer = SystemTime::now();
+                            self.release_bgp_resources();
+                            self.session_attribute.connect_retry_counter += 1;
+                            self.session_attribute.state = State::Idle;
+                            if self.config.max_prefix_restart_time.is_some() {
+                                self.session_attribute.max_prefix_restart_timer = Some(SystemTime::now());
+                            }
+                            return;
+                        }
+                        // dampeningで抑制されたprefixはbest pathの選択から外す
+                        let suppressed_routes: Vec<RoutingInformationEntry> = self.adj_rib_in.0.iter()
+                            .filter(|entry| self.is_suppressed_by_dampening(&entry.destnation_address))
+                            .cloned()
+                            .collect();
+                        if !suppressed_routes.is_empty() {
+                            loc_rib.remove_routes(&suppressed_routes);
+                            self.event_queue.push(Event::LocRibChanged);
+                        }
                         if self.adj_rib_in.does_have_new_route() {
                             self.event_queue.push(Event::AdjRibInChanged);
                         }
@@ -605,20 +1297,70 @@ impl fsm {
                         //   - (optionally) performs peer oscillation damping if the
                         //     DampPeerOscillations attribute is set to TRUE, and
                         //   - changes its state to Idle.
+                        self.send_queued_notification();
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
+                        self.delete_routes_or_mark_as_stale(loc_rib, false);
+                        self.release_bgp_resources();
+                        self.session_attribute.connect_retry_counter += 1;
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
                     },
                     &Event::AdjRibInChanged => {
                         // Nexthopがいないのをfilterするだけで良い
                         // Adj-Rib-In => LocRib;
+                        // RPKIのvalidation stateはAdj-RIB-Inのrouteに付けておき、
+                        // Invalidなrouteも捨てずにpolicyで扱えるようにする
+                        // ASPAのverificationはAS_PATHが変わるeBGPのpeerから受け取ったrouteだけ行う
+                        let is_external_peer = !self.config.is_internal_peer() && !self.config.is_confederation_external_peer();
+                        let is_downstream = self.config.is_received_from_provider();
+                        for entry in &mut self.adj_rib_in.0 {
+                            entry.rpki_state = self.roa_table.validate(&entry.destnation_address, entry.get_as_path());
+                            if is_external_peer {
+                                entry.aspa_state = self.aspa_table.verify(entry.get_as_path(), is_downstream);
+                            }
+                        }
                         let mut adj_rib_in = vec![];
-                        for entry in self.adj_rib_in.0.clone() {
-                            if !entry.get_as_path().get_seq().contains(&self.config.as_number.0) {
+                        for mut entry in self.adj_rib_in.0.clone() {
+                            if self.is_suppressed_by_dampening(&entry.destnation_address) {
+                                continue;
+                            }
+                            if self.config.is_internal_peer() {
+                                // ORIGINATOR_IDが自分のrouter idのもの、CLUSTER_LISTに
+                                // 自分のcluster idが含まれるものはループしているので捨てる
+                                if entry.get_originator_id() == Some(self.config.get_router_id())
+                                    || entry.get_cluster_list().contains(&self.config.get_cluster_id()) {
+                                    continue;
+                                }
+                                entry.peer_bgp_identifier = self.session_attribute.peer_bgp_identifier;
+                                adj_rib_in.push(entry);
+                            } else if self.config.is_confederation_external_peer() {
+                                if !entry.get_as_path().does_have_the_as_number(&self.config.as_number) {
+                                    adj_rib_in.push(entry);
+                                }
+                            } else if !entry.get_as_path().does_have_the_as_number(&self.config.get_public_as_number()) {
+                                // eBGP peerから受け取ったLOCAL_PREFは無視する
+                                entry.remove_local_pref();
+                                if !self.accept_only_to_customer(&mut entry) {
+                                    warn!("route leak detected by OTC: {:?} from {}", entry.destnation_address, self.config.remote_ip_addr);
+                                    continue;
+                                }
                                 adj_rib_in.push(entry);
                             }
                         }
-                        loc_rib.add(adj_rib_in);
-                        // Routing Table に書き込む処理を追加する
-                        if loc_rib.does_have_should_update_route() {
-                            write_ip_v4_route(loc_rib).await;
+                        if let Some(import_policy) = &self.config.import_policy {
+                            adj_rib_in = adj_rib_in.iter()
+                                .filter_map(|entry| import_policy.apply(entry, &self.config.remote_as_number, self.config.my_ip_addr))
+                                .collect();
+                        }
+                        // 前に受け入れたがpolicy、OTC、dampeningなどで受け入れなくなったこのpeerのrouteはLoc-RIBから消す
+                        let remote_ip_addr = self.config.remote_ip_addr;
+                        let rejected_routes: Vec<RoutingInformationEntry> = loc_rib.0.iter()
+                            .filter(|entry| entry.source.get_peer_ip_addr() == Some(remote_ip_addr) && !adj_rib_in.contains(entry))
+                            .cloned()
+                            .collect();
+                        loc_rib.remove_routes(&rejected_routes);
+                        // カーネルへの書き込みはFibManagerがLocRibとの差分を取って行う
+                        if loc_rib.add(adj_rib_in) || !rejected_routes.is_empty() {
                             self.event_queue.push(Event::LocRibChanged);
                         }
                     },
@@ -627,14 +1369,69 @@ impl fsm {
                         self.phase3_disseminate_route(loc_rib);
                         if self.adj_rib_out.does_have_new_route() {
                             self.event_queue.push(Event::AdjRibOutChanged);
+                        } else {
+                            // 広告するrouteが無くてもEnd-of-RIBは送る
+                            self.send_end_of_rib_if_needed();
                         }
                     },
                     &Event::AdjRibOutChanged => {
-                        let bgp_update_message = BgpUpdateMessage::is_created_from_adj_rib_out(&self.adj_rib_out, &self.config);
-                        let bgp_update_message = bgp_update_message.decode();
-                        self.tcp_connection.as_ref().unwrap().write(&bgp_update_message[..]).expect("cannot send open message");
                         self.send_update_message();
-                    }
+                    },
+                    &Event::RouteRefreshMsg => {
+                        let route_refresh_message = match self.packet_queue.pop().unwrap() {
+                            BgpMessage::RouteRefresh(d) => d,
+                            _ => panic!(),
+                        };
+                        // BoRRとEoRRはEnhanced Route Refresh(RFC7313)がnegotiateされていなければ送られてこない
+                        let capability = match route_refresh_message.get_subtype() {
+                            RouteRefreshSubtype::Request => Capability::RouteRefresh,
+                            _ => Capability::EnhancedRouteRefresh,
+                        };
+                        // IPv4 unicast以外のaddress familyはまだ扱っていないので無視する
+                        if route_refresh_message.get_address_family() != (1, 1) || !self.is_capability_negotiated(&capability) {
+                            warn!("ignore the route refresh message for {:?} from {}",
+                                route_refresh_message.get_address_family(), self.config.remote_ip_addr);
+                            return;
+                        }
+                        match route_refresh_message.get_subtype() {
+                            RouteRefreshSubtype::Request => self.replay_adj_rib_out(),
+                            RouteRefreshSubtype::BeginningOfRouteRefresh => {
+                                // peerから再送されなかったrouteはEoRRで消す
+                                self.adj_rib_in.mark_all_routes_as_stale();
+                            },
+                            RouteRefreshSubtype::EndOfRouteRefresh => {
+                                self.remove_stale_routes(loc_rib);
+                            },
+                        }
+                    },
+                    &Event::SoftResetIn => {
+                        // peerにAdj-RIB-Outの再送を依頼する。
+                        // Route Refreshに対応していないpeerなら、残しているAdj-RIB-Inからpolicyを適用し直す
+                        if self.is_capability_negotiated(&Capability::RouteRefresh) {
+                            self.send_route_refresh_message(RouteRefreshSubtype::Request);
+                        } else {
+                            self.event_queue.push(Event::ImportPolicyChanged);
+                        }
+                    },
+                    &Event::SoftResetOut => {
+                        self.replay_adj_rib_out();
+                    },
+                    &Event::RpkiTableChanged | &Event::ImportPolicyChanged => {
+                        // validation stateやimport policyが変わったので、Adj-RIB-Inから入れ直す。
+                        // rejectされるようになったrouteはAdjRibInChangedでLoc-RIBから消える
+                        self.event_queue.push(Event::AdjRibInChanged);
+                    },
+                    &Event::BgpHeaderErr => {
+                        // Event 21はany other eventとして扱われるが、
+                        // FSM Errorではなくheaderのerrorを通知する
+                        self.send_bad_message_length_notification();
+                        self.delete_routes_or_mark_as_stale(loc_rib, false);
+                        self.session_attribute.connect_retry_timer = SystemTime::now();
+                        self.release_bgp_resources();
+                        self.session_attribute.connect_retry_counter += 1;
+                        self.session_attribute.state = State::Idle;
+                        self.event_queue.push(Event::ManualStart);
+                    },
                     _ => {
                         // In response to any other event (Events 9, 12-13, 20-22), the local
                         // system:
@@ -666,6 +1463,14 @@ impl SessionAttribute {
             hold_time: Duration::from_secs(90),
             keepalive_timer: SystemTime::now(),
             keepalive_time: Duration::from_secs(30),
+            peer_bgp_identifier: None,
+            peer_capabilities: vec![],
+            end_of_rib_sent: vec![],
+            end_of_rib_received: vec![],
+            restart_timer: None,
+            restart_time: Duration::from_secs(120),
+            max_prefix_warned: vec![],
+            max_prefix_restart_timer: None,
         }
     }
 
@@ -700,6 +1505,12 @@ pub enum Event {
     AdjRibInChanged,
     LocRibChanged,
     AdjRibOutChanged,
+    RouteRefreshMsg, // RFC2918
+    SoftResetIn,
+    SoftResetOut,
+    GracefulRestartTimerExpires, // RFC4724
+    RpkiTableChanged, // ROAかASPAのtableが変わった
+    ImportPolicyChanged, // 設定のreloadでimport policyが変わった
 }
 #[derive(Debug)]
 pub enum State {
@@ -710,3 +1521,210 @@ pub enum State {
     OpenSent,
     Established,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use crate::bgp::{AsPathSegment, Nlri};
+    use crate::fib::MemoryFib;
+    use crate::policy::{Policy, parse_community};
+
+    fn connect_established_peer(options: &str) -> (fsm, TcpStream) {
+        let mut args = vec!["peer", "64512", "127.0.0.1", "64513", "127.0.0.1", "active", "10.0.0.0/24"];
+        args.extend(options.split_whitespace());
+        let config = Config::parse_args(args).unwrap();
+        let mut peer = fsm::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        peer.tcp_connection = Some(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        peer.session_attribute.state = State::Established;
+        (peer, listener.accept().unwrap().0)
+    }
+
+    fn update_message(path_attributes: Vec<PathAttribute>) -> BgpMessage {
+        let destination = IpPrefix::new(net::Ipv4Addr::new(10, 100, 0, 0), 24);
+        BgpMessage::Update(BgpUpdateMessage::new(vec![], path_attributes, vec![Nlri::new(destination, None)]))
+    }
+
+    #[tokio::test]
+    async fn test_update_removes_route_rejected_by_import_policy() {
+        let mut import_policy = Policy::new("import");
+        import_policy.add_term_from_args(&["blackhole", "community=64513:666", "then", "reject"], &vec![]).unwrap();
+        import_policy.add_term_from_args(&["others", "then", "accept"], &vec![]).unwrap();
+        let (mut peer, _remote) = connect_established_peer("import-policy=import");
+        peer.config.import_policy = Some(import_policy);
+        let mut loc_rib = LocRib::new(vec![]);
+        let fib = MemoryFib::new();
+        let mut path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64513])])),
+            PathAttribute::NextHop(net::Ipv4Addr::new(127, 0, 0, 1)),
+        ];
+
+        peer.packet_queue.push(update_message(path_attributes.clone()));
+        peer.handle_event(&Event::UpdateMsg, &mut loc_rib, &fib).await;
+        peer.handle_event(&Event::AdjRibInChanged, &mut loc_rib, &fib).await;
+        assert_eq!(loc_rib.0.len(), 1);
+
+        // 同じrouteにrejectされるcommunityが付いたら、前に受け入れたrouteも消す
+        path_attributes.push(PathAttribute::Communities(vec![parse_community("64513:666").unwrap()]));
+        peer.packet_queue.push(update_message(path_attributes));
+        peer.handle_event(&Event::UpdateMsg, &mut loc_rib, &fib).await;
+        peer.handle_event(&Event::AdjRibInChanged, &mut loc_rib, &fib).await;
+        assert!(loc_rib.0.is_empty());
+        assert_eq!(peer.adj_rib_in.0.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_prefix_sends_cease_with_address_family() {
+        let (mut peer, mut remote) = connect_established_peer("max-prefix=1");
+        assert_eq!(peer.config.max_prefix, vec![((1, 1), 1)]);
+        let mut loc_rib = LocRib::new(vec![]);
+        let fib = MemoryFib::new();
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64513])])),
+            PathAttribute::NextHop(net::Ipv4Addr::new(127, 0, 0, 1)),
+        ];
+        let nlri = vec![
+            Nlri::new(IpPrefix::new(net::Ipv4Addr::new(10, 100, 0, 0), 24), None),
+            Nlri::new(IpPrefix::new(net::Ipv4Addr::new(10, 101, 0, 0), 24), None),
+        ];
+        peer.packet_queue.push(BgpMessage::Update(BgpUpdateMessage::new(vec![], path_attributes, nlri)));
+        peer.handle_event(&Event::UpdateMsg, &mut loc_rib, &fib).await;
+        assert!(matches!(peer.get_state(), State::Idle));
+
+        // 上限を超えたaddress familyをCeaseのdataに入れる
+        let mut received = vec![];
+        remote.read_to_end(&mut received).unwrap();
+        assert_eq!(received, BgpNotificationMessage::new_maximum_number_of_prefixes_reached(1, 1, 1).decode());
+    }
+
+    #[tokio::test]
+    async fn test_originator_id_is_attached_only_when_reflecting() {
+        let (mut peer, _remote) = connect_established_peer("");
+        peer.config.remote_as_number = peer.config.as_number;
+        peer.session_attribute.peer_bgp_identifier = Some(net::Ipv4Addr::new(10, 0, 0, 2));
+        let mut loc_rib = LocRib::new(vec![]);
+        let fib = MemoryFib::new();
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64513])])),
+            PathAttribute::NextHop(net::Ipv4Addr::new(127, 0, 0, 1)),
+            PathAttribute::LocalPref(100),
+        ];
+        peer.packet_queue.push(update_message(path_attributes));
+        peer.handle_event(&Event::UpdateMsg, &mut loc_rib, &fib).await;
+        peer.handle_event(&Event::AdjRibInChanged, &mut loc_rib, &fib).await;
+        assert_eq!(loc_rib.0.len(), 1);
+        assert_eq!(loc_rib.0[0].get_originator_id(), None);
+
+        // non-clientから学習したrouteをclientへreflectするときに付ける
+        let (mut client, _remote) = connect_established_peer("route-reflector-client");
+        client.config.remote_as_number = client.config.as_number;
+        client.config.remote_ip_addr = net::Ipv4Addr::new(127, 0, 0, 2);
+        client.phase3_disseminate_route(&loc_rib);
+        assert_eq!(client.adj_rib_out.0.len(), 1);
+        assert_eq!(client.adj_rib_out.0[0].get_originator_id(), Some(net::Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[test]
+    fn test_export_policy_matches_attributes_before_ebgp_rewrite() {
+        let mut export_policy = Policy::new("export");
+        export_policy.add_term_from_args(&["from-64514", "as-path=^64514$", "next-hop=10.0.0.2", "then", "reject"], &vec![]).unwrap();
+        export_policy.add_term_from_args(&["others", "then", "med=200", "accept"], &vec![]).unwrap();
+        let (mut peer, _remote) = connect_established_peer("");
+        peer.config.export_policy = Some(export_policy);
+        let route = |destination: &str, as_number: u16| {
+            let path_attributes = vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![as_number])])),
+                PathAttribute::NextHop(net::Ipv4Addr::new(10, 0, 0, 2)),
+                PathAttribute::MultiExitDisc(100),
+            ];
+            RoutingInformationEntry::new(net::Ipv4Addr::new(10, 0, 0, 2), destination.parse().unwrap(), RoutingInformationStatus::Updated, path_attributes)
+        };
+        let loc_rib = LocRib::new(vec![route("10.100.0.0/24", 64514), route("10.200.0.0/24", 64515)]);
+
+        peer.phase3_disseminate_route(&loc_rib);
+        // AS_PATHとnexthopは自分のものに書き換える前の値でmatchし、MEDはpolicyで付けたものが残る
+        assert_eq!(peer.adj_rib_out.0.len(), 1);
+        let advertised = &peer.adj_rib_out.0[0];
+        assert_eq!(advertised.destnation_address, "10.200.0.0/24".parse().unwrap());
+        assert!(advertised.path_attributes.contains(&PathAttribute::MultiExitDisc(200)));
+        assert!(!advertised.path_attributes.contains(&PathAttribute::MultiExitDisc(100)));
+    }
+
+    #[tokio::test]
+    async fn test_replay_adj_rib_out_to_full_socket() {
+        use std::os::unix::io::AsRawFd;
+        let (mut peer, mut remote) = connect_established_peer("");
+        // 送信バッファを小さくして、Adj-RIB-Outの再送の途中でsocketを一杯にする
+        let buffer_size: libc::c_int = 4096;
+        unsafe {
+            let tcp_connection = peer.tcp_connection.as_ref().unwrap();
+            libc::setsockopt(tcp_connection.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF,
+                &buffer_size as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t);
+        }
+        peer.tcp_connection.as_ref().unwrap().set_nonblocking(true).unwrap();
+        remote.set_nonblocking(true).unwrap();
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64512])])),
+            PathAttribute::NextHop(net::Ipv4Addr::new(127, 0, 0, 1)),
+        ];
+        let number_of_routes = 50000;
+        peer.adj_rib_out = AdjRibOut::new((0..number_of_routes).map(|i| {
+            let destination = IpPrefix::new(net::Ipv4Addr::new(10, (i >> 8) as u8, i as u8, 0), 24);
+            RoutingInformationEntry::new(net::Ipv4Addr::new(127, 0, 0, 1), destination, RoutingInformationStatus::UnChanged, path_attributes.clone())
+        }).collect());
+        let mut loc_rib = LocRib::new(vec![]);
+        let fib = MemoryFib::new();
+
+        peer.handle_event(&Event::SoftResetOut, &mut loc_rib, &fib).await;
+        assert!(!peer.send_buffer.is_empty());
+        let mut received = DataBuffer::new();
+        while !peer.send_buffer.is_empty() {
+            let mut buf = vec![];
+            let _ = remote.read_to_end(&mut buf);
+            received.buf.append(&mut buf);
+            peer.flush_send_buffer();
+        }
+        // socketに残っている分を読みきる
+        peer.tcp_connection.as_ref().unwrap().shutdown(net::Shutdown::Write).unwrap();
+        remote.set_nonblocking(false).unwrap();
+        remote.read_to_end(&mut received.buf).unwrap();
+        // 受け取ったbyte列が壊れておらず、全てのrouteの後にEnd-of-RIBが届いている
+        let mut update_messages = vec![];
+        while let Some(raw_data) = received.retrive_one_bgp_message(4096).unwrap() {
+            update_messages.push(BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default()).unwrap());
+        }
+        assert!(received.buf.is_empty());
+        let end_of_rib = update_messages.pop().unwrap();
+        assert!(end_of_rib.network_layer_reachability_information.is_empty() && end_of_rib.path_attributes.is_empty());
+        let number_of_received_routes: usize = update_messages.iter().map(|update_message| update_message.network_layer_reachability_information.len()).sum();
+        assert_eq!(number_of_received_routes, number_of_routes);
+    }
+
+    #[test]
+    fn test_shutdown_without_notification_if_graceful_restart_is_negotiated() {
+        let graceful_restart = Capability::GracefulRestart { restart_state: false, restart_time: 120, forwarding_state: true };
+        let mut loc_rib = LocRib::new(vec![]);
+
+        let (mut peer, mut remote) = connect_established_peer("graceful-restart");
+        peer.session_attribute.peer_capabilities = vec![graceful_restart];
+        peer.shutdown(&mut loc_rib);
+        let mut received = vec![];
+        remote.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+        assert!(matches!(peer.get_state(), State::Idle));
+
+        // peerがGraceful Restartを広告していなければCease(Administrative Shutdown)を送る
+        let (mut peer, mut remote) = connect_established_peer("graceful-restart");
+        peer.shutdown(&mut loc_rib);
+        let mut received = vec![];
+        remote.read_to_end(&mut received).unwrap();
+        assert_eq!(received, BgpNotificationMessage::new_cease(CeaseSubcode::AdministrativeShutdown).decode());
+    }
+}
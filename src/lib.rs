@@ -1,27 +1,69 @@
 #![feature(str_split_as_str)]
 #![feature(exclusive_range_pattern)]
 
+#[macro_use]
+pub mod log;
 pub mod bgp;
 pub mod finite_state_machine;
 pub mod routing;
 pub mod rib;
 pub mod peer;
+pub mod policy;
+pub mod dampening;
+pub mod rpki;
+pub mod aspa;
+pub mod aggregation;
+pub mod network;
+pub mod redistribute;
+pub mod fib;
+pub mod nexthop;
+pub mod toml_config;
+pub mod control;
+pub mod cli;
 
-use std::{net::Ipv4Addr, str::FromStr, string::ParseError};
-use crate::bgp::AutonomousSystemNumber;
+use std::{fmt, fs, net::Ipv4Addr, path::Path, str::FromStr};
+use crate::bgp::{AutonomousSystemNumber, Role};
 use crate::routing::IpPrefix;
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use crate::policy::{Policy, PrefixList};
+use crate::dampening::DampeningConfig;
+use crate::aggregation::AggregateAddress;
+use crate::network::NetworkStatement;
+use crate::redistribute::RedistributeConfig;
+use crate::fib::FibConfig;
+use crate::aspa::AspaTable;
+use std::time::Duration;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
     as_number: AutonomousSystemNumber,
     my_ip_addr: Ipv4Addr,
     remote_as_number: AutonomousSystemNumber,
     remote_ip_addr: Ipv4Addr,
     mode: Mode,
-    advertisement_network: IpPrefix,
+    // 設定しなければmy_ip_addrをrouter idにする
+    router_id: Option<Ipv4Addr>,
+    // peerとsessionを張ったらカーネルのrouting tableから広告するprefix
+    advertisement_networks: Vec<IpPrefix>,
+    hold_time: u16, // OPEN messageで提案するHold Time (秒)
+    connect_retry_time: u64,
+    route_reflector_client: bool,
+    cluster_id: Option<Ipv4Addr>,
+    confederation_id: Option<AutonomousSystemNumber>,
+    confederation_members: Vec<AutonomousSystemNumber>,
+    graceful_restart: bool,
+    restart_time: u16,
+    add_path_receive: bool,
+    add_path_send: bool,
+    add_path_best: Option<usize>,
+    import_policy: Option<Policy>,
+    export_policy: Option<Policy>,
+    // address family (AFI, SAFI)ごとにpeerから受け取るprefixの上限。max-prefix=<数>はIPv4 unicastの上限
+    max_prefix: Vec<((u16, u8), usize)>,
+    max_prefix_warning_threshold: usize, // 上限の何%で警告するか
+    max_prefix_restart_time: Option<u64>, // 上限を超えて切断したあと再接続するまでの秒数
+    dampening: Option<DampeningConfig>,
+    local_role: Option<Role>,
+    pub role_strict: bool,
 }
 
 impl FromStr for Mode {
@@ -37,49 +79,390 @@ impl FromStr for Mode {
         }
     }
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Mode {
     Active,
     Passive,
 }
 
 impl Config {
-    pub fn parse_args(args: Vec<&str>) -> Config {
-        let as_number = AutonomousSystemNumber::new(
-            args[1].parse().expect("cannot parse arg 1"));
-        let my_ip_addr: Ipv4Addr = args[2].parse().expect("cannot parse arg 2");
-        let remote_as_number = AutonomousSystemNumber::new(
-            args[3].parse().expect("cannot parse arg 3"));
-        let remote_ip_addr: Ipv4Addr = args[4].parse().expect("cannot parse arg 4");
-        let mode: Mode = args[5].parse().expect("cannot parse arg 5");
-        let advertisement_network = args[6].parse().expect("cannot parse arg6");
+    pub fn parse_args(args: Vec<&str>) -> Result<Config, String> {
+        // <name> <as> <my_ip> <remote_as> <remote_ip> <active|passive> <network>[,<network>...] [option...]
+        if args.len() < 7 {
+            return Err(format!("peer needs <name> <as> <my_ip> <remote_as> <remote_ip> <active|passive> <network>: {}", args.join(" ")));
+        }
+        let as_number = AutonomousSystemNumber::new(parse_option_value("as", Some(args[1]))?);
+        let my_ip_addr = parse_option_value("my_ip", Some(args[2]))?;
+        let remote_as_number = AutonomousSystemNumber::new(parse_option_value("remote_as", Some(args[3]))?);
+        let remote_ip_addr = parse_option_value("remote_ip", Some(args[4]))?;
+        let mode = args[5].parse().map_err(|_| format!("mode must be active or passive: {}", args[5]))?;
+        let advertisement_networks = args[6].split(',')
+            .map(|network| parse_option_value("network", Some(network)))
+            .collect::<Result<_, _>>()?;
+
+        let mut config = Config::new(as_number, my_ip_addr, remote_as_number, remote_ip_addr, mode, advertisement_networks);
+        // 7番目以降の引数は省略可能なオプション (`key` or `key=value`)
+        for option in &args[7..] {
+            config.parse_option(option)?;
+        }
+        Ok(config)
+    }
 
+    fn new(as_number: AutonomousSystemNumber, my_ip_addr: Ipv4Addr, remote_as_number: AutonomousSystemNumber,
+           remote_ip_addr: Ipv4Addr, mode: Mode, advertisement_networks: Vec<IpPrefix>) -> Config {
         Config {
             as_number,
             my_ip_addr,
             remote_as_number,
             remote_ip_addr,
             mode,
-            advertisement_network,
+            router_id: None,
+            advertisement_networks,
+            hold_time: 90,
+            connect_retry_time: 120,
+            route_reflector_client: false,
+            cluster_id: None,
+            confederation_id: None,
+            confederation_members: vec![],
+            graceful_restart: false,
+            restart_time: 120,
+            add_path_receive: false,
+            add_path_send: false,
+            add_path_best: None,
+            import_policy: None,
+            export_policy: None,
+            max_prefix: vec![],
+            max_prefix_warning_threshold: 75,
+            max_prefix_restart_time: None,
+            dampening: None,
+            local_role: None,
+            role_strict: false,
         }
     }
 
-    pub fn parse_from_file(filename: &str) -> Vec<Config> {
-        let mut result = vec![];
-        if let Ok(lines) = read_lines(filename) {
-            for line in lines {
-                if let Ok(ip) = line {
-                    let config_args: Vec<&str> = ip.split(" ").collect();
-                    result.push(Config::parse_args(config_args));
+    fn parse_option(&mut self, option: &str) -> Result<(), String> {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+        match key {
+            "router-id" => self.router_id = Some(parse_option_value(key, value)?),
+            "hold-time" => {
+                // RFC4271 4.2: Hold Timeは0か3秒以上
+                self.hold_time = parse_option_value(key, value)?;
+                if self.hold_time == 1 || self.hold_time == 2 {
+                    return Err(format!("hold-time must be 0 or at least 3: {}", option));
                 }
+            },
+            "connect-retry-time" => self.connect_retry_time = parse_option_value(key, value)?,
+            "route-reflector-client" => self.route_reflector_client = true,
+            "cluster-id" => self.cluster_id = Some(parse_option_value(key, value)?),
+            "confederation-id" => {
+                self.confederation_id = Some(AutonomousSystemNumber::new(parse_option_value(key, value)?));
+            },
+            "confederation-members" => {
+                // confederation-members=65001,65002 のようにカンマ区切りで書く
+                for member in value.ok_or("confederation-members needs value")?.split(',') {
+                    let member = parse_option_value(key, Some(member))?;
+                    self.confederation_members.push(AutonomousSystemNumber::new(member));
+                }
+            },
+            "graceful-restart" => self.graceful_restart = true,
+            "restart-time" => {
+                // RFC4724 3: Restart Timeは12bitで表す
+                self.restart_time = parse_option_value(key, value)?;
+                if self.restart_time > 4095 {
+                    return Err(format!("restart-time must be at most 4095: {}", option));
+                }
+            },
+            "add-path" => {
+                match value.ok_or("add-path needs value")? {
+                    "receive" => self.add_path_receive = true,
+                    "send" => self.add_path_send = true,
+                    "both" => {
+                        self.add_path_receive = true;
+                        self.add_path_send = true;
+                    },
+                    _ => return Err(format!("add-path must be receive, send or both: {}", option)),
+                }
+            },
+            "add-path-best" => {
+                // 指定しなければ全てのpathを送る
+                self.add_path_best = Some(parse_option_value(key, value)?);
+            },
+            "import-policy" => {
+                // policyの中身はresolve_policiesで入れる
+                self.import_policy = Some(Policy::new(value.ok_or("import-policy needs value")?));
+            },
+            "export-policy" => {
+                self.export_policy = Some(Policy::new(value.ok_or("export-policy needs value")?));
+            },
+            "max-prefix" => {
+                let max_prefix = parse_option_value(key, value)?;
+                self.max_prefix.retain(|(address_family, _)| *address_family != (1, 1));
+                self.max_prefix.push(((1, 1), max_prefix));
+            },
+            "max-prefix-warning" => self.max_prefix_warning_threshold = parse_option_value(key, value)?,
+            "max-prefix-restart" => {
+                // 指定しなければ切断したままにする
+                self.max_prefix_restart_time = Some(parse_option_value(key, value)?);
+            },
+            "role" => {
+                // peerに対する自分の役割
+                self.local_role = Some(match value.ok_or("role needs value")? {
+                    "provider" => Role::Provider,
+                    "rs" => Role::RouteServer,
+                    "rs-client" => Role::RouteServerClient,
+                    "customer" => Role::Customer,
+                    "peer" => Role::Peer,
+                    _ => return Err(format!("role must be provider, rs, rs-client, customer or peer: {}", option)),
+                });
+            },
+            "role-strict" => {
+                // peerがRole capabilityを広告しなければsessionを張らない
+                self.role_strict = true;
+            },
+            "dampening" => {
+                self.dampening.get_or_insert_with(DampeningConfig::default);
+            },
+            // dampening-*を指定するとdampeningも有効になる
+            "dampening-half-life" => {
+                let half_life = parse_option_value(key, value)?;
+                self.dampening.get_or_insert_with(DampeningConfig::default).half_life = Duration::from_secs(half_life);
+            },
+            "dampening-reuse" => {
+                self.dampening.get_or_insert_with(DampeningConfig::default).reuse_threshold = parse_option_value(key, value)?;
+            },
+            "dampening-suppress" => {
+                self.dampening.get_or_insert_with(DampeningConfig::default).suppress_threshold = parse_option_value(key, value)?;
+            },
+            "dampening-max-suppress-time" => {
+                let max_suppress_time = parse_option_value(key, value)?;
+                self.dampening.get_or_insert_with(DampeningConfig::default).max_suppress_time = Duration::from_secs(max_suppress_time);
+            },
+            _ => return Err(format!("unknown option: {}", option)),
+        }
+        Ok(())
+    }
+
+    fn resolve_policies(&mut self, policies: &Vec<Policy>) -> Result<(), String> {
+        let resolve = |policy: &mut Option<Policy>| {
+            if let Some(p) = policy {
+                *p = policies.iter().find(|defined| defined.name == p.name)
+                    .ok_or_else(|| format!("unknown policy: {}", p.name))?
+                    .clone();
             }
+            Ok::<(), String>(())
+        };
+        resolve(&mut self.import_policy)?;
+        resolve(&mut self.export_policy)
+    }
+
+    pub fn get_router_id(&self) -> Ipv4Addr {
+        self.router_id.unwrap_or(self.my_ip_addr)
+    }
+
+    pub fn get_hold_time(&self) -> u16 {
+        self.hold_time
+    }
+
+    pub fn requires_session_reset(&self, other: &Config) -> bool {
+        // OPEN messageの内容(AS番号、router id、Hold Time、capability)や
+        // TCP connectionが変わる設定を変えたらsessionを張り直す
+        self.as_number != other.as_number
+            || self.my_ip_addr != other.my_ip_addr
+            || self.remote_as_number != other.remote_as_number
+            || self.mode != other.mode
+            || self.get_router_id() != other.get_router_id()
+            || self.hold_time != other.hold_time
+            || self.get_local_as_number_for_peer() != other.get_local_as_number_for_peer()
+            || self.is_confederation_external_peer() != other.is_confederation_external_peer()
+            || self.graceful_restart != other.graceful_restart
+            || self.restart_time != other.restart_time
+            || self.add_path_receive != other.add_path_receive
+            || self.add_path_send != other.add_path_send
+            || self.local_role != other.local_role
+            || self.role_strict != other.role_strict
+    }
+
+    pub fn is_received_from_provider(&self) -> bool {
+        // ASPAのdownstream verificationをつかうか。
+        // roleが設定されていなければupstream verificationで誤ってInvalidにしないようにprovider扱いにする
+        match self.local_role {
+            Some(Role::Customer) | None => true,
+            _ => false,
+        }
+    }
+
+    pub fn get_local_role(&self) -> Option<Role> {
+        // BGP RoleはeBGPのsessionでだけつかう
+        if self.is_internal_peer() || self.is_confederation_external_peer() {
+            None
+        } else {
+            self.local_role
+        }
+    }
+
+    pub fn get_cluster_id(&self) -> Ipv4Addr {
+        // cluster-idが設定されていなければrouter idをつかう
+        self.cluster_id.unwrap_or(self.get_router_id())
+    }
+
+    pub fn is_internal_peer(&self) -> bool {
+        // peerのAS番号が自分のAS番号と同じならiBGP
+        self.as_number == self.remote_as_number
+    }
+
+    pub fn is_confederation_external_peer(&self) -> bool {
+        // peerのAS番号が同じconfederationの別のmember ASならconfederation内のeBGP
+        !self.is_internal_peer() && self.confederation_members.contains(&self.remote_as_number)
+    }
+
+    pub fn get_public_as_number(&self) -> AutonomousSystemNumber {
+        // confederationの外から見た自分のAS番号
+        self.confederation_id.unwrap_or(self.as_number)
+    }
+
+    pub fn get_local_as_number_for_peer(&self) -> AutonomousSystemNumber {
+        // OPEN messageでpeerに名乗るAS番号
+        if self.is_internal_peer() || self.is_confederation_external_peer() {
+            self.as_number
+        } else {
+            self.get_public_as_number()
+        }
+    }
+}
+
+pub fn parse_option_value<T: FromStr>(key: &str, value: Option<&str>) -> Result<T, String> {
+    // `key=value`のvalueをparseする。設定ファイルのエラーメッセージに使う
+    let value = value.ok_or_else(|| format!("{} needs value", key))?;
+    value.parse().map_err(|_| format!("cannot parse {}: {}", key, value))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub filename: String,
+    pub line: usize, // 0ならファイル全体のエラー
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(filename: &str, line: usize, message: String) -> Self {
+        Self { filename: filename.to_string(), line, message }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.filename, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.filename, self.line, self.message)
         }
-        result
     }
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+// 設定ファイル全体。ファイル名が.tomlで終わればTOML (toml_config.rsを参照)、
+// それ以外は1行に1つの設定を書く形式として読む
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DaemonConfig {
+    pub peers: Vec<Config>,
+    pub rpki_cache: Option<String>,
+    pub aspa_file: Option<String>,
+    // aspa-fileから読み込んだASPA
+    pub aspa_table: AspaTable,
+    // reloadなどを受け付けるunix domain socketのpath (control.rsを参照)
+    pub control_socket: Option<String>,
+    pub redistribute: Option<RedistributeConfig>,
+    pub network_statements: Vec<NetworkStatement>,
+    pub aggregate_addresses: Vec<AggregateAddress>,
+    pub fib: FibConfig,
+}
+
+impl DaemonConfig {
+    pub fn load(filename: &str) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(filename)
+            .map_err(|e| ConfigError::new(filename, 0, format!("cannot read config file: {}", e)))?;
+        let mut config = if filename.ends_with(".toml") {
+            toml_config::parse(&content).map_err(|e| ConfigError::new(filename, e.line, e.message))?
+        } else {
+            Self::parse_lines(&content).map_err(|(line, message)| ConfigError::new(filename, line, message))?
+        };
+        // aspa-fileとcontrol-socketの相対pathは設定ファイルのdirectoryからのpathとして扱う。
+        // daemonはchdir("/")するので、起動したdirectoryによらないようにする
+        let directory = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+        let resolve = |path: &mut Option<String>| {
+            if let Some(path) = path.as_mut().filter(|path| Path::new(path.as_str()).is_relative()) {
+                *path = directory.join(path.as_str()).to_string_lossy().into_owned();
+            }
+        };
+        resolve(&mut config.aspa_file);
+        resolve(&mut config.control_socket);
+        // aspa-fileの誤りも--checkやreloadの時に設定ファイルの誤りと同じように扱う
+        if let Some(aspa_file) = &config.aspa_file {
+            config.aspa_table = AspaTable::load_from_json_file(aspa_file)?;
+        }
+        Ok(config)
+    }
+
+    fn parse_lines(content: &str) -> Result<Self, (usize, String)> {
+        // peerの行の他に、prefix-listとpolicyの行 (policy.rsを参照) と、
+        // rpki-cacheの行 (`rpki-cache <address>:<port>`) とaspa-fileの行 (`aspa-file <path>`)、
+        // control-socketの行 (`control-socket <path>`)、
+        // aggregate-addressの行 (aggregation.rsを参照)、networkの行 (network.rsを参照)、
+        // redistributeの行 (redistribute.rsを参照)、fibの行 (fib.rsを参照) を書ける。
+        // エラーはその行番号と一緒に返す
+        let lines: Vec<(usize, Vec<&str>)> = content.lines().enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(i, line)| (i + 1, line.split_whitespace().collect()))
+            .collect();
+        let mut prefix_lists: Vec<PrefixList> = vec![];
+        for (line, args) in lines.iter().filter(|(_, args)| args[0] == "prefix-list") {
+            let name = args.get(1).ok_or((*line, "prefix-list needs name".to_string()))?;
+            if !prefix_lists.iter().any(|p| p.name == *name) {
+                prefix_lists.push(PrefixList::new(name));
+            }
+            let prefix_list = prefix_lists.iter_mut().find(|p| p.name == *name).unwrap();
+            prefix_list.add_entry_from_args(&args[2..]).map_err(|e| (*line, e))?;
+        }
+        let mut policies: Vec<Policy> = vec![];
+        for (line, args) in lines.iter().filter(|(_, args)| args[0] == "policy") {
+            let name = args.get(1).ok_or((*line, "policy needs name".to_string()))?;
+            if !policies.iter().any(|p| p.name == *name) {
+                policies.push(Policy::new(name));
+            }
+            let policy = policies.iter_mut().find(|p| p.name == *name).unwrap();
+            policy.add_term_from_args(&args[2..], &prefix_lists).map_err(|e| (*line, e))?;
+        }
+        let mut config = Self::default();
+        for (line, args) in &lines {
+            let line = *line;
+            let needs_value = |key: &str| -> Result<String, (usize, String)> {
+                match args.as_slice() {
+                    [_, value] => Ok(value.to_string()),
+                    _ => Err((line, format!("{} needs exactly one value", key))),
+                }
+            };
+            match args[0] {
+                "prefix-list" | "policy" => (),
+                "rpki-cache" => config.rpki_cache = Some(needs_value("rpki-cache")?),
+                "aspa-file" => config.aspa_file = Some(needs_value("aspa-file")?),
+                "control-socket" => config.control_socket = Some(needs_value("control-socket")?),
+                "aggregate-address" => {
+                    config.aggregate_addresses.push(AggregateAddress::parse_from_args(&args[1..]).map_err(|e| (line, e))?);
+                },
+                "network" => {
+                    config.network_statements.push(NetworkStatement::parse_from_args(&args[1..]).map_err(|e| (line, e))?);
+                },
+                "redistribute" => config.redistribute = Some(RedistributeConfig::parse_from_args(&args[1..]).map_err(|e| (line, e))?),
+                "fib" => config.fib = FibConfig::parse_from_args(&args[1..]).map_err(|e| (line, e))?,
+                _ => {
+                    let mut peer = Config::parse_args(args.clone()).map_err(|e| (line, e))?;
+                    peer.resolve_policies(&policies).map_err(|e| (line, e))?;
+                    config.peers.push(peer);
+                },
+            }
+        }
+        Ok(config)
+    }
 }
@@ -1,51 +1,238 @@
 use mrbgpd::{bgp, finite_state_machine::{fsm, Event}};
-use mrbgpd::Config;
+use mrbgpd::{ConfigError, DaemonConfig};
+use mrbgpd::{debug, error, info};
+use mrbgpd::cli::{CommandLineOptions, USAGE};
+use mrbgpd::control::ControlServer;
+use mrbgpd::log::set_log_level;
 use std::{convert::TryInto, io, net::{TcpListener, TcpStream}};
 use std::{thread, time};
-use std::io::Read;
+use std::io::{Read, Write};
 use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::process;
 use mrbgpd::peer::BgpPeers;
+use mrbgpd::fib::{Fib, FibError, FibManager, MemoryFib, NetlinkFib};
 use bgp::bgp_packet_handler;
 use tokio;
+use tokio::signal::unix::{signal, SignalKind};
+use futures::FutureExt;
 
 
-#[tokio::main]
-async fn main() {
-    let filename: Vec<String> = env::args().collect();
-    println!("{:?}", filename[1]);
-    let configs = Config::parse_from_file(&filename[1]);
-    println!("{:?}", &configs);
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = match CommandLineOptions::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        },
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+    set_log_level(options.log_level);
+    // daemonはchdir("/")するので、reloadで読み直す設定ファイルと終了時に消すpid fileは絶対pathにしておく
+    if options.daemonize {
+        let current_dir = match env::current_dir() {
+            Ok(current_dir) => current_dir,
+            Err(e) => {
+                eprintln!("cannot get the current directory: {}", e);
+                process::exit(1);
+            },
+        };
+        let absolute_path = |path: &str| current_dir.join(path).to_string_lossy().into_owned();
+        options.config = absolute_path(&options.config);
+        options.pid_file = options.pid_file.as_deref().map(absolute_path);
+    }
+    // --checkはdeployの前に設定ファイルを確かめるために使うので、log levelに関係なく結果を出す
+    let config = match DaemonConfig::load(&options.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    if options.check {
+        println!("{}: ok", options.config);
+        return;
+    }
+    debug!("{:?}", &config);
+    // pid fileを書けないときは端末から切り離す前にエラーを出せるように、forkの前に開いておく
+    let pid_file = options.pid_file.as_ref().map(|pid_file| match File::create(pid_file) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("cannot create the pid file {}: {}", pid_file, e);
+            process::exit(1);
+        },
+    });
+    // tokioのruntimeはthreadを作るので、forkはruntimeを作る前にする
+    if options.daemonize {
+        daemonize();
+    }
+    if let Some(mut pid_file) = pid_file {
+        if let Err(e) = writeln!(pid_file, "{}", process::id()) {
+            error!("cannot write the pid file: {}", e);
+            process::exit(1);
+        }
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start the tokio runtime");
+    let result = runtime.block_on(run(&options, config));
+    if let Some(pid_file) = &options.pid_file {
+        let _ = fs::remove_file(pid_file);
+    }
+    if let Err(e) = result {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn daemonize() {
+    // forkした子プロセスを新しいsessionのleaderにして端末から切り離す。
+    // 端末を閉じたあとに書き込むと失敗するので、端末につながっている標準入出力は/dev/nullにつなぎ直す
+    let dev_null = OpenOptions::new().read(true).write(true).open("/dev/null").expect("cannot open /dev/null");
+    unsafe {
+        match libc::fork() {
+            -1 => panic!("cannot fork: {}", io::Error::last_os_error()),
+            0 => (),
+            _ => process::exit(0),
+        }
+        if libc::setsid() == -1 {
+            panic!("cannot create a new session: {}", io::Error::last_os_error());
+        }
+        // 起動したdirectoryをunmountできるように、rootに移る
+        if libc::chdir(b"/\0".as_ptr() as *const libc::c_char) == -1 {
+            panic!("cannot change the directory to /: {}", io::Error::last_os_error());
+        }
+        for fd in 0..3 {
+            if libc::isatty(fd) == 1 {
+                libc::dup2(dev_null.as_raw_fd(), fd);
+            }
+        }
+    }
+}
+
+async fn run(options: &CommandLineOptions, config: DaemonConfig) -> Result<(), FibError> {
+    // --no-fibならカーネルのrouting tableには触らない
+    let no_fib = options.no_fib;
+    let fib_backend: Box<dyn Fib> = if no_fib {
+        Box::new(MemoryFib::new())
+    } else {
+        Box::new(NetlinkFib::new()?)
+    };
     // ToDo: Data BufferをFSMに持たせる
-    let mut bgp_peers = BgpPeers::new(configs);
+    let mut bgp_peers = BgpPeers::new(config.peers, FibManager::new(fib_backend, config.fib));
+    bgp_peers.reconcile_fib().await;
+    // --no-fibならnexthopを解決するカーネルのrouteが無いので、全てのnexthopを到達できるものとして扱う
+    if !no_fib {
+        bgp_peers.start_nexthop_tracking().await;
+    }
+    if let Some(rpki_cache_address) = config.rpki_cache {
+        bgp_peers.start_rpki_validation(&rpki_cache_address);
+    }
+    if let Some(redistribute_config) = config.redistribute {
+        bgp_peers.start_redistribution(redistribute_config).await;
+    }
+    bgp_peers.set_network_statements(config.network_statements);
+    bgp_peers.set_aggregate_addresses(config.aggregate_addresses);
+    if config.aspa_file.is_some() {
+        bgp_peers.set_local_aspa_table(config.aspa_table);
+    }
+    // SIGHUPかcontrol socketのreloadで設定ファイルを読み直す。
+    // control-socketのpathの変更は再起動するまで反映しない
+    let mut hangup = signal(SignalKind::hangup()).expect("cannot handle SIGHUP");
+    // SIGTERMで全てのpeerにCeaseを送ってから終了する
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot handle SIGTERM");
+    let control_server = config.control_socket.as_deref().map(ControlServer::new);
     for fsm in &mut bgp_peers.peers {
         fsm.event_queue.push(Event::ManualStart);
     }
     loop {
+        bgp_peers.accept_connections();
         for fsm in &mut bgp_peers.peers {
-            println!("{:?}", fsm.get_state());
+            debug!("{:?}", fsm.get_state());
+            fsm.check_timers();
             match fsm.event_queue.pop() {
-                Some(event) => fsm.handle_event(&event, &mut bgp_peers.loc_rib).await,
+                Some(event) => fsm.handle_event(&event, &mut bgp_peers.loc_rib, bgp_peers.fib.get_backend()).await,
                 None => (),
             }
         }
         for fsm in &mut bgp_peers.peers {
+            if fsm.tcp_connection.is_none() {
+                continue;
+            }
+            fsm.flush_send_buffer();
             let mut buf = vec![];
             match fsm.tcp_connection.as_ref().unwrap().read_to_end(&mut buf) {
                 Ok(_) => {
                  // Tcp connection is closed.
+                    fsm.data_buffer.buf.append(&mut buf);
+                    fsm.event_queue.push(Event::TcpConnectionFails);
                 },
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     fsm.data_buffer.buf.append(&mut buf);
                     // Tcp connection is still open and there no data in socket.
                 },
                 Err(e) => {
-                    println!("other error happen: {:?}, : {:?}", e, buf);
+                    error!("other error happen: {:?}, : {:?}", e, buf);
                 }
             }
             if fsm.data_buffer.buf.len() > 0 {
-                bgp_packet_handler(&fsm.data_buffer.retrive_one_bgp_message(), &mut fsm.event_queue, &mut fsm.packet_queue);
+                let negotiated_capabilities = fsm.get_negotiated_capabilities();
+                match fsm.data_buffer.retrive_one_bgp_message(negotiated_capabilities.get_max_message_length()) {
+                    Ok(Some(bgp_message)) => bgp_packet_handler(&bgp_message, &mut fsm.event_queue, &mut fsm.packet_queue, &negotiated_capabilities),
+                    Ok(None) => (), // messageがまだ全部届いていない
+                    Err(e) => {
+                        error!("{}", e);
+                        fsm.event_queue.push(Event::BgpHeaderErr);
+                    },
+                }
+            }
+        }
+        bgp_peers.stop_retaining_routes_if_needed();
+        bgp_peers.poll_kernel_route_monitor();
+        bgp_peers.track_nexthops();
+        bgp_peers.update_originated_routes().await;
+        bgp_peers.sync_fib().await;
+        bgp_peers.poll_rtr_client();
+        if hangup.recv().now_or_never().is_some() {
+            info!("reload {} by SIGHUP", options.config);
+            if let Err(e) = reload(&options.config, &mut bgp_peers).await {
+                error!("{}", e);
+            }
+        }
+        if terminate.recv().now_or_never().is_some() {
+            info!("shut down by SIGTERM");
+            bgp_peers.shutdown().await;
+            return Ok(());
+        }
+        for request in control_server.iter().flat_map(|control_server| control_server.poll()) {
+            let command: Vec<&str> = request.command.split_whitespace().collect();
+            match command[..] {
+                ["reload"] => match reload(&options.config, &mut bgp_peers).await {
+                    Ok(()) => request.reply("ok"),
+                    Err(e) => request.reply(&format!("error: {}", e)),
+                },
+                ["soft-reset", direction, peer] => match bgp_peers.soft_reset(direction, peer) {
+                    Ok(()) => request.reply("ok"),
+                    Err(e) => request.reply(&format!("error: {}", e)),
+                },
+                ["show", "dampening"] => request.reply(&bgp_peers.show_dampening()),
+                ["show", "aspa"] => request.reply(&bgp_peers.show_aspa()),
+                _ => {
+                    let message = format!("error: unknown command: {}", request.command);
+                    request.reply(&message);
+                },
             }
         }
         thread::sleep(time::Duration::from_secs(1));
     }
 }
+
+async fn reload(filename: &str, bgp_peers: &mut BgpPeers) -> Result<(), ConfigError> {
+    // 設定ファイルに誤りがあれば、今の設定のまま動かし続ける
+    let config = DaemonConfig::load(filename)?;
+    bgp_peers.reload(config).await;
+    Ok(())
+}
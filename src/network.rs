@@ -0,0 +1,135 @@
+use std::net::Ipv4Addr;
+use futures::channel::mpsc::UnboundedReceiver;
+use crate::bgp::{AsPath, Origin, PathAttribute};
+use crate::fib::{Fib, FibChange, FibError, FibRoute};
+use crate::policy::parse_community;
+use crate::rib::{RouteSource, RoutingInformationEntry, RoutingInformationStatus};
+use crate::routing::IpPrefix;
+use crate::parse_option_value;
+
+// カーネルのrouting tableとは関係なくoriginateするprefix
+// 設定ファイルには以下のように書く。
+//   network <prefix> [origin=igp|egp|incomplete] [community=<as>:<value>,...] [med=<value>] [kernel-route]
+// kernel-routeを指定すると、prefixに含まれるrouteがカーネルにあるときだけoriginateする。
+// カーネルのrouteは起動時に一度だけ読み、以降は追加/削除の通知で追いかける。
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkStatement {
+    pub prefix: IpPrefix,
+    origin: Origin,
+    communities: Vec<u32>,
+    multi_exit_disc: Option<u32>,
+    pub requires_kernel_route: bool,
+}
+
+impl NetworkStatement {
+    pub fn parse_from_args(args: &[&str]) -> Result<Self, String> {
+        let prefix = parse_option_value("prefix of network", args.first().copied())?;
+        let mut network = Self { prefix, origin: Origin::Igp, communities: vec![], multi_exit_disc: None, requires_kernel_route: false };
+        for option in &args[1..] {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (*option, None),
+            };
+            match key {
+                "origin" => network.origin = match value.ok_or("origin needs value")? {
+                    "igp" => Origin::Igp,
+                    "egp" => Origin::Egp,
+                    "incomplete" => Origin::Incompleted,
+                    _ => return Err(format!("origin must be igp, egp or incomplete: {}", option)),
+                },
+                "community" => {
+                    network.communities = value.ok_or("community needs value")?.split(',').map(parse_community).collect::<Result<_, _>>()?;
+                },
+                "med" => network.multi_exit_disc = Some(parse_option_value(key, value)?),
+                "kernel-route" => network.requires_kernel_route = true,
+                _ => return Err(format!("unknown network option: {}", option)),
+            }
+        }
+        Ok(network)
+    }
+
+    pub fn create_route(&self, router_id: Ipv4Addr) -> RoutingInformationEntry {
+        let mut path_attributes = vec![
+            PathAttribute::Origin(self.origin.clone()),
+            PathAttribute::AsPath(AsPath::new(vec![])),
+            PathAttribute::NextHop(router_id),
+        ];
+        if let Some(multi_exit_disc) = self.multi_exit_disc {
+            path_attributes.push(PathAttribute::MultiExitDisc(multi_exit_disc));
+        }
+        if !self.communities.is_empty() {
+            path_attributes.push(PathAttribute::Communities(self.communities.clone()));
+        }
+        // カーネルのrouteを元にしていないのでカーネルには書き込まない
+        let mut entry = RoutingInformationEntry::new(
+            Ipv4Addr::new(0, 0, 0, 0), self.prefix, RoutingInformationStatus::Updated, path_attributes);
+        entry.source = RouteSource::Network;
+        entry
+    }
+}
+
+pub struct KernelRouteWatcher {
+    changes: UnboundedReceiver<FibChange>,
+    kernel_routes: Vec<FibRoute>,
+}
+
+impl KernelRouteWatcher {
+    pub async fn new(fib: &dyn Fib) -> Result<Self, FibError> {
+        // 購読してから今のrouting tableを読むことで、その間の変更を取りこぼさない
+        let changes = fib.subscribe()?;
+        let kernel_routes = fib.list().await?;
+        Ok(Self { changes, kernel_routes })
+    }
+
+    pub fn poll(&mut self) {
+        while let Ok(change) = self.changes.try_recv() {
+            match change {
+                FibChange::Added(route) => {
+                    self.kernel_routes.retain(|kernel_route| !kernel_route.has_same_key(&route));
+                    self.kernel_routes.push(route);
+                },
+                FibChange::Removed(route) => {
+                    self.kernel_routes.retain(|kernel_route| !kernel_route.has_same_key(&route));
+                },
+            }
+        }
+    }
+
+    pub fn has_route_in(&self, prefix: &IpPrefix) -> bool {
+        self.kernel_routes.iter().any(|route| prefix.does_include(&route.destination))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fib::{MemoryFib, RouteType};
+
+    #[test]
+    fn test_create_network_route() {
+        let network = NetworkStatement::parse_from_args(&["192.0.2.0/24", "origin=incomplete", "community=64512:100,64512:200", "med=10"]).unwrap();
+        assert!(!network.requires_kernel_route);
+        let route = network.create_route(Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(route.destnation_address, "192.0.2.0/24".parse().unwrap());
+        assert_eq!(route.get_origin(), Origin::Incompleted.value());
+        assert_eq!(route.get_communities(), vec![parse_community("64512:100").unwrap(), parse_community("64512:200").unwrap()]);
+        assert!(route.path_attributes.contains(&PathAttribute::MultiExitDisc(10)));
+        assert_eq!(route.get_next_hop(), Some(Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[tokio::test]
+    async fn test_kernel_route_watcher() {
+        let fib = MemoryFib::new();
+        let kernel_route = FibRoute { destination: "192.0.2.128/25".parse().unwrap(), gateways: vec![Ipv4Addr::new(10, 0, 0, 1)], protocol: 4, table: 254, metric: 0, route_type: RouteType::Unicast };
+        fib.install(kernel_route.clone()).await.unwrap();
+        let mut kernel_route_watcher = KernelRouteWatcher::new(&fib).await.unwrap();
+        assert!(kernel_route_watcher.has_route_in(&"192.0.2.0/24".parse().unwrap()));
+        assert!(!kernel_route_watcher.has_route_in(&"198.51.100.0/24".parse().unwrap()));
+
+        // 読み直さなくてもカーネルのrouteの削除に追従する
+        fib.remove(kernel_route).await.unwrap();
+        kernel_route_watcher.poll();
+        assert!(!kernel_route_watcher.has_route_in(&"192.0.2.0/24".parse().unwrap()));
+    }
+}
@@ -0,0 +1,154 @@
+use std::net::Ipv4Addr;
+use futures::channel::mpsc::UnboundedReceiver;
+use crate::fib::{Fib, FibChange, FibConfig, FibError, FibRoute, RT_TABLE_MAIN, RouteType};
+use crate::rib::{LocRib, NexthopState, RoutingInformationEntry, RoutingInformationStatus};
+use crate::routing::IpPrefix;
+
+// Next Hop Tracking
+// peerから学習したpathのnexthopをカーネルのmain tableのrouteで解決し、
+// 解決できないpathはUnreachableにしてbest pathの選択から外す。
+//  - 解決したrouteのmetricをIGP costとしてbest pathの選択に使う
+//  - multihopのiBGPのnexthopはカーネルで解決できなければLocRibのrouteでも解決する
+//  - カーネルのrouteの追加/削除を購読し、変わったら解決し直す
+// 自分で書き込んだBGPのrouteとdefault routeではnexthopを解決しない。
+
+pub struct NexthopTracker {
+    changes: UnboundedReceiver<FibChange>,
+    kernel_routes: Vec<FibRoute>,
+    // 自分で書き込んだrouteのprotocol
+    bgp_protocol: u8,
+}
+
+impl NexthopTracker {
+    pub async fn new(fib: &dyn Fib, fib_config: &FibConfig) -> Result<Self, FibError> {
+        // 購読してから今のrouting tableを読むことで、その間の変更を取りこぼさない
+        let changes = fib.subscribe()?;
+        let kernel_routes = fib.list().await?;
+        let mut nexthop_tracker = Self { changes, kernel_routes: vec![], bgp_protocol: fib_config.protocol };
+        nexthop_tracker.kernel_routes = kernel_routes.into_iter()
+            .filter(|route| nexthop_tracker.can_resolve_with(route))
+            .collect();
+        Ok(nexthop_tracker)
+    }
+
+    fn can_resolve_with(&self, route: &FibRoute) -> bool {
+        route.table == RT_TABLE_MAIN
+            && route.route_type == RouteType::Unicast
+            && route.protocol != self.bgp_protocol
+            && route.destination.get_prefix_length() > 0
+    }
+
+    fn poll_kernel_routes(&mut self) -> bool {
+        let mut is_changed = false;
+        while let Ok(change) = self.changes.try_recv() {
+            match change {
+                FibChange::Added(route) if self.can_resolve_with(&route) => {
+                    self.kernel_routes.retain(|kernel_route| !kernel_route.has_same_key(&route));
+                    self.kernel_routes.push(route);
+                    is_changed = true;
+                },
+                FibChange::Removed(route) if self.can_resolve_with(&route) => {
+                    self.kernel_routes.retain(|kernel_route| !kernel_route.has_same_key(&route));
+                    is_changed = true;
+                },
+                _ => (),
+            }
+        }
+        is_changed
+    }
+
+    pub fn update(&mut self, loc_rib: &mut LocRib) -> bool {
+        // LocRibのpathのnexthopを解決し直し、状態が変わったpathがあればtrueを返す
+        if self.poll_kernel_routes() {
+            info!("the kernel routes are changed, so resolve the nexthops again");
+        }
+        let rib_routes = loc_rib.0.clone();
+        let mut is_changed = false;
+        for entry in &mut loc_rib.0 {
+            if entry.source.get_peer_ip_addr().is_none() || entry.status == RoutingInformationStatus::Withdrawn {
+                continue;
+            }
+            let nexthop_state = self.resolve(entry.nexthop, entry.source.is_internal(), &rib_routes);
+            if entry.nexthop_state != nexthop_state {
+                info!("nexthop {} of {:?} is {:?}", entry.nexthop, entry.destnation_address, nexthop_state);
+                entry.nexthop_state = nexthop_state;
+                is_changed = true;
+            }
+        }
+        is_changed
+    }
+
+    fn resolve(&self, nexthop: Ipv4Addr, resolves_with_rib: bool, rib_routes: &Vec<RoutingInformationEntry>) -> NexthopState {
+        let host = IpPrefix::new(nexthop, 32);
+        let kernel_route = self.kernel_routes.iter()
+            .filter(|route| route.destination.does_include(&host))
+            .max_by_key(|route| (route.destination.get_prefix_length(), std::cmp::Reverse(route.metric)));
+        if let Some(route) = kernel_route {
+            return NexthopState::Reachable(route.metric);
+        }
+        if !resolves_with_rib {
+            return NexthopState::Unreachable;
+        }
+        // LocRibのpathで解決する場合は、そのpathのIGP costを引き継ぐ。
+        // 自分自身のnexthopを経由するpathでは解決しない
+        let rib_route = rib_routes.iter()
+            .filter(|entry| entry.source.get_peer_ip_addr().is_some() && entry.status != RoutingInformationStatus::Withdrawn)
+            .filter(|entry| entry.nexthop != nexthop && entry.destnation_address.does_include(&host))
+            .filter(|entry| entry.nexthop_state != NexthopState::Unreachable)
+            .max_by_key(|entry| entry.destnation_address.get_prefix_length());
+        match rib_route {
+            Some(entry) => entry.nexthop_state,
+            None => NexthopState::Unreachable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::{AsPath, Origin, PathAttribute};
+    use crate::fib::MemoryFib;
+    use crate::rib::{RouteSource, Rib};
+
+    fn entry(destination: &str, nexthop: [u8; 4], source: RouteSource) -> RoutingInformationEntry {
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![])),
+            PathAttribute::NextHop(Ipv4Addr::from(nexthop)),
+        ];
+        let mut entry = RoutingInformationEntry::new(
+            Ipv4Addr::from(nexthop), destination.parse().unwrap(), RoutingInformationStatus::Updated, path_attributes);
+        entry.source = source;
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_nexthop_tracker() {
+        let fib = MemoryFib::new();
+        let route = |destination: &str, metric: u32| FibRoute {
+            destination: destination.parse().unwrap(), gateways: vec![], protocol: 2, table: RT_TABLE_MAIN, metric, route_type: RouteType::Unicast,
+        };
+        fib.install(route("10.0.0.0/24", 0)).await.unwrap();
+        fib.install(route("10.1.0.0/16", 10)).await.unwrap();
+        let mut nexthop_tracker = NexthopTracker::new(&fib, &FibConfig::default()).await.unwrap();
+        let external = RouteSource::External(Ipv4Addr::new(10, 0, 0, 1));
+        let internal = RouteSource::Internal(Ipv4Addr::new(10, 1, 0, 1));
+        let mut loc_rib = Rib::new(vec![
+            entry("192.168.1.0/24", [10, 0, 0, 1], external),
+            entry("192.168.2.0/24", [10, 1, 0, 1], internal),
+            entry("192.168.3.0/24", [10, 2, 0, 1], external),
+            // iBGPのnexthopはeBGPで学習した192.168.1.0/24で解決する
+            entry("192.168.4.0/24", [192, 168, 1, 1], internal),
+        ]);
+        assert!(nexthop_tracker.update(&mut loc_rib));
+        let states: Vec<NexthopState> = loc_rib.0.iter().map(|entry| entry.nexthop_state).collect();
+        assert_eq!(states, vec![NexthopState::Reachable(0), NexthopState::Reachable(10), NexthopState::Unreachable, NexthopState::Reachable(0)]);
+        assert_eq!(loc_rib.select_best_paths().len(), 3);
+
+        // カーネルのrouteが消えたら解決し直す
+        fib.remove(route("10.1.0.0/16", 10)).await.unwrap();
+        assert!(nexthop_tracker.update(&mut loc_rib));
+        assert_eq!(loc_rib.0[1].nexthop_state, NexthopState::Unreachable);
+        assert!(!nexthop_tracker.update(&mut loc_rib));
+    }
+}
@@ -1,21 +1,493 @@
-use std::net::TcpListener;
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
 use std::net::SocketAddr;
-use crate::{Config, finite_state_machine::fsm};
+use std::io;
+use std::time::{Duration, SystemTime};
+use crate::{Config, DaemonConfig, finite_state_machine::fsm};
+use crate::bgp::CeaseSubcode;
 use crate::rib::{LocRib, AdjRibIn, AdjRibOut};
+use crate::rpki::{RoaTable, RtrClient};
+use crate::aspa::AspaTable;
+use crate::aggregation::AggregateAddress;
+use crate::finite_state_machine::Event;
+use crate::rib::{RouteSource, RoutingInformationEntry};
+use crate::network::{KernelRouteWatcher, NetworkStatement};
+use crate::redistribute::{KernelRouteChange, KernelRouteMonitor, RedistributeConfig};
+use crate::routing::IpPrefix;
+use crate::fib::FibManager;
+use crate::nexthop::NexthopTracker;
+use std::rc::Rc;
 pub struct BgpPeers {
     pub peers: Vec<fsm>,
+    // local addressごとに1つだけbindし、acceptしたconnectionはremote addressでpeerに渡す
+    listeners: Vec<TcpListener>,
     pub loc_rib: LocRib,
+    pub fib: FibManager,
+    // Graceful Restart中は前回の起動時に書き込んだrouteをFIBに残す
+    is_retaining_routes: bool,
+    retained_routes_timer: SystemTime,
+    retained_routes_time: Duration,
+    rtr_client: Option<RtrClient>,
+    rpki_cache_address: Option<String>,
+    // ローカルのjsonファイルから読み込んだASPA
+    local_aspa_table: AspaTable,
+    aggregate_addresses: Vec<AggregateAddress>,
+    network_statements: Vec<NetworkStatement>,
+    // kernel-routeを指定したnetworkのためにカーネルのrouteを追いかける
+    network_kernel_routes: Option<KernelRouteWatcher>,
+    kernel_route_monitor: Option<KernelRouteMonitor>,
+    redistribute_config: Option<RedistributeConfig>,
+    nexthop_tracker: Option<NexthopTracker>,
 }
 
 impl BgpPeers {
-    pub fn new(configs: Vec<Config>) -> Self {
+    pub fn new(configs: Vec<Config>, fib: FibManager) -> Self {
+        let mut listeners = vec![];
         let mut peers = vec![];
         for config in configs {
-            let tcp_listener = TcpListener::bind((config.my_ip_addr, 179)).expect("port 179が使用できません。");
-            let fsm = fsm::new(config, tcp_listener);
+            if !listeners.iter().any(|listener| is_listening_on(listener, config.my_ip_addr)) {
+                listeners.push(bind_listener(config.my_ip_addr).expect("port 179が使用できません。"));
+            }
+            let fsm = fsm::new(config);
             peers.push(fsm);
         }
         let loc_rib = LocRib::new(vec![]);
-        Self { peers, loc_rib }
+        Self {
+            peers,
+            listeners,
+            loc_rib,
+            fib,
+            is_retaining_routes: false,
+            retained_routes_timer: SystemTime::now(),
+            retained_routes_time: Duration::from_secs(0),
+            rtr_client: None,
+            rpki_cache_address: None,
+            local_aspa_table: AspaTable::default(),
+            aggregate_addresses: vec![],
+            network_statements: vec![],
+            network_kernel_routes: None,
+            kernel_route_monitor: None,
+            redistribute_config: None,
+            nexthop_tracker: None,
+        }
+    }
+
+    fn add_peer(&mut self, config: Config) {
+        // reloadで増えたpeer。同じlocal addressのlistenerがあればそれを使う。
+        // bindできなくても他のpeerは動かし続ける
+        if !self.listeners.iter().any(|listener| is_listening_on(listener, config.my_ip_addr)) {
+            match bind_listener(config.my_ip_addr) {
+                Ok(tcp_listener) => self.listeners.push(tcp_listener),
+                Err(e) => {
+                    error!("cannot add the peer {}: port 179 is not available: {}", config.remote_ip_addr, e);
+                    return;
+                },
+            };
+        }
+        let mut peer = fsm::new(config);
+        peer.event_queue.push(Event::ManualStart);
+        let (roa_table, aspa_table) = self.get_rpki_tables();
+        peer.update_rpki_tables(roa_table, aspa_table);
+        self.peers.push(peer);
+    }
+
+    pub fn accept_connections(&mut self) {
+        // peerから来たconnectionを、local addressとremote addressが一致するpeerに渡す
+        for listener in &self.listeners {
+            loop {
+                let (tcp_connection, remote_addr) = match listener.accept() {
+                    Ok(connection) => connection,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        error!("cannot accept the connection: {}", e);
+                        break;
+                    },
+                };
+                let local_addr = tcp_connection.local_addr().map(|addr| addr.ip()).ok();
+                let peer = self.peers.iter_mut().find(|peer| {
+                    let config = peer.get_config();
+                    remote_addr.ip() == IpAddr::V4(config.remote_ip_addr) && local_addr == Some(IpAddr::V4(config.my_ip_addr))
+                });
+                match peer {
+                    Some(peer) => peer.set_incoming_connection(tcp_connection),
+                    None => info!("reject the connection from the unknown peer {}", remote_addr),
+                }
+            }
+        }
+    }
+
+    pub async fn reload(&mut self, config: DaemonConfig) {
+        // 動いている設定との差分だけを反映する。
+        //  - 消えたpeerはCease(Peer De-configured)で切断する
+        //  - OPENに関わる設定が変わったpeerはCease(Administrative Reset)で切断して張り直す
+        //  - それ以外の設定が変わったpeerはsessionを保ったまま反映する (import policyはsoft refresh)
+        //  - 増えたpeerはsessionを張り始める
+        // fibの設定はカーネルに書き込んだrouteの扱いが変わるので、再起動するまで反映しない
+        let peer_configs = config.peers;
+        let (mut old_peers, removed_peers): (Vec<fsm>, Vec<fsm>) = std::mem::take(&mut self.peers).into_iter()
+            .partition(|peer| peer_configs.iter().any(|c| c.remote_ip_addr == peer.get_config().remote_ip_addr));
+        for mut peer in removed_peers {
+            info!("remove the peer {}", peer.get_config().remote_ip_addr);
+            peer.stop(&mut self.loc_rib, CeaseSubcode::PeerDeconfigured);
+        }
+        for peer_config in peer_configs {
+            let position = old_peers.iter().position(|peer| peer.get_config().remote_ip_addr == peer_config.remote_ip_addr);
+            match position.map(|i| old_peers.remove(i)) {
+                Some(mut peer) if peer.get_config().requires_session_reset(&peer_config) => {
+                    info!("reset the session with {} to apply the new config", peer_config.remote_ip_addr);
+                    peer.stop(&mut self.loc_rib, CeaseSubcode::AdministrativeReset);
+                    self.add_peer(peer_config);
+                },
+                Some(mut peer) => {
+                    peer.update_config(peer_config);
+                    self.peers.push(peer);
+                },
+                None => {
+                    info!("add the peer {}", peer_config.remote_ip_addr);
+                    self.add_peer(peer_config);
+                },
+            }
+        }
+
+        // どのpeerも使わなくなったlocal addressのlistenerは閉じる
+        let peers = &self.peers;
+        self.listeners.retain(|listener| peers.iter().any(|peer| is_listening_on(listener, peer.get_config().my_ip_addr)));
+
+        if config.rpki_cache != self.rpki_cache_address {
+            self.rtr_client = None;
+            self.rpki_cache_address = None;
+            if let Some(rpki_cache_address) = &config.rpki_cache {
+                self.start_rpki_validation(rpki_cache_address);
+            }
+            self.update_rpki_tables();
+        }
+        if config.aspa_table != self.local_aspa_table {
+            info!("reload {} aspas", config.aspa_table.0.len());
+            self.local_aspa_table = config.aspa_table;
+            self.update_rpki_tables();
+        }
+
+        // 消えたnetworkとaggregate-addressのrouteを消す。残りは次のupdate_originated_routesで作り直す
+        let network_statements = config.network_statements;
+        let aggregate_addresses = config.aggregate_addresses;
+        for network in self.network_statements.iter().filter(|network| !network_statements.contains(network)) {
+            replace_originated_route(&mut self.loc_rib, &network.prefix, RouteSource::Network, None);
+        }
+        for aggregate_address in self.aggregate_addresses.iter().filter(|aggregate_address| !aggregate_addresses.contains(aggregate_address)) {
+            let source = RouteSource::Aggregate { summary_only: aggregate_address.summary_only };
+            replace_originated_route(&mut self.loc_rib, &aggregate_address.prefix, source, None);
+        }
+        self.network_statements = network_statements;
+        self.aggregate_addresses = aggregate_addresses;
+
+        if config.redistribute != self.redistribute_config {
+            // 再配布していたrouteを消して、新しい設定でカーネルのrouteを読み直す
+            let redistributed_routes: Vec<RoutingInformationEntry> = self.loc_rib.0.iter()
+                .filter(|entry| entry.source == RouteSource::Redistributed)
+                .cloned()
+                .collect();
+            self.loc_rib.remove_routes(&redistributed_routes);
+            self.kernel_route_monitor = None;
+            self.redistribute_config = None;
+            if let Some(redistribute_config) = config.redistribute {
+                self.start_redistribution(redistribute_config).await;
+            }
+        }
+        if config.fib != *self.fib.get_config() {
+            warn!("the fib config is changed, but it is applied after restart");
+        }
+        for peer in &mut self.peers {
+            peer.event_queue.push(Event::LocRibChanged);
+        }
+    }
+
+    pub fn soft_reset(&mut self, direction: &str, peer: &str) -> Result<(), String> {
+        // control socketのsoft-resetで、sessionを張り直さずにrouteを交換し直す。
+        //  - in: peerにROUTE-REFRESHを送ってAdj-RIB-Outを再送してもらう
+        //  - out: Adj-RIB-Outをpeerに再送する
+        let is_inbound = match direction {
+            "in" => true,
+            "out" => false,
+            _ => return Err(format!("soft-reset direction must be in or out: {}", direction)),
+        };
+        let remote_ip_addr = match peer {
+            "all" => None,
+            _ => Some(peer.parse::<Ipv4Addr>().map_err(|_| format!("invalid peer address: {}", peer))?),
+        };
+        let peers: Vec<&mut fsm> = self.peers.iter_mut()
+            .filter(|peer| remote_ip_addr.map_or(true, |remote_ip_addr| peer.get_config().remote_ip_addr == remote_ip_addr))
+            .collect();
+        if peers.is_empty() {
+            return Err(format!("no such peer: {}", peer));
+        }
+        for peer in peers {
+            info!("soft reset {} the session with {}", direction, peer.get_config().remote_ip_addr);
+            peer.event_queue.push(if is_inbound { Event::SoftResetIn } else { Event::SoftResetOut });
+        }
+        Ok(())
+    }
+
+    pub fn show_dampening(&self) -> String {
+        // control socketのshow dampeningで、dampeningの対象になっているprefixを1行に1つずつ返す
+        let lines: Vec<String> = self.peers.iter()
+            .flat_map(|peer| {
+                let remote_ip_addr = peer.get_config().remote_ip_addr;
+                peer.get_dampening_states().into_iter().map(move |state| {
+                    let suppressed = if state.is_suppressed() { " suppressed" } else { "" };
+                    format!("{} {} penalty {:.0}{}", remote_ip_addr, state.prefix, state.penalty, suppressed)
+                })
+            })
+            .collect();
+        if lines.is_empty() {
+            return "no dampened prefixes".to_string();
+        }
+        lines.join("\n")
+    }
+
+    pub fn show_aspa(&self) -> String {
+        // control socketのshow aspaで、ASPAの数とpeerから受け取ったrouteのverificationの結果を1行に1つずつ返す
+        let (_, aspa_table) = self.get_rpki_tables();
+        let mut lines = vec![format!("{} aspas", aspa_table.0.len())];
+        for peer in &self.peers {
+            let remote_ip_addr = peer.get_config().remote_ip_addr;
+            for entry in &peer.get_adj_rib_in().0 {
+                lines.push(format!("{} {} [{}] {:?}", remote_ip_addr, entry.destnation_address, entry.get_as_path(), entry.aspa_state));
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn set_aggregate_addresses(&mut self, aggregate_addresses: Vec<AggregateAddress>) {
+        self.aggregate_addresses = aggregate_addresses;
+    }
+
+    pub fn set_network_statements(&mut self, network_statements: Vec<NetworkStatement>) {
+        self.network_statements = network_statements;
+    }
+
+    pub async fn start_redistribution(&mut self, redistribute_config: RedistributeConfig) {
+        let router_id = match self.peers.first() {
+            Some(peer) => peer.get_config().get_router_id(),
+            None => return,
+        };
+        self.redistribute_config = Some(redistribute_config.clone());
+        match KernelRouteMonitor::new(redistribute_config, router_id, self.fib.get_backend(), self.fib.get_config()).await {
+            Ok(kernel_route_monitor) => self.kernel_route_monitor = Some(kernel_route_monitor),
+            Err(e) => error!("cannot redistribute the kernel routes: {}", e),
+        }
+    }
+
+    pub fn poll_kernel_route_monitor(&mut self) {
+        // カーネルのrouteの追加/削除をLocRibに反映し、変わったら全てのpeerに広告し直す
+        let changes = match self.kernel_route_monitor.as_mut() {
+            Some(kernel_route_monitor) => kernel_route_monitor.poll(),
+            None => return,
+        };
+        if changes.is_empty() {
+            return;
+        }
+        for change in changes {
+            match change {
+                KernelRouteChange::Added(entry) => {
+                    info!("redistribute the kernel route: {:?}", entry.destnation_address);
+                    self.loc_rib.add_one_entry(entry);
+                },
+                KernelRouteChange::Deleted(entry) => {
+                    info!("withdraw the redistributed route: {:?}", entry.destnation_address);
+                    self.loc_rib.remove_routes(&vec![entry]);
+                },
+            }
+        }
+        for peer in &mut self.peers {
+            peer.event_queue.push(Event::LocRibChanged);
+        }
+    }
+
+    pub async fn start_nexthop_tracking(&mut self) {
+        match NexthopTracker::new(self.fib.get_backend(), self.fib.get_config()).await {
+            Ok(nexthop_tracker) => self.nexthop_tracker = Some(nexthop_tracker),
+            Err(e) => error!("cannot track the nexthops: {}", e),
+        }
+    }
+
+    pub fn track_nexthops(&mut self) {
+        // nexthopに到達できるかが変わったpathがあれば、best pathを選び直して全てのpeerに広告し直す
+        let nexthop_tracker = match self.nexthop_tracker.as_mut() {
+            Some(nexthop_tracker) => nexthop_tracker,
+            None => return,
+        };
+        if !nexthop_tracker.update(&mut self.loc_rib) {
+            return;
+        }
+        for peer in &mut self.peers {
+            peer.event_queue.push(Event::LocRibChanged);
+        }
+    }
+
+    pub async fn update_originated_routes(&mut self) {
+        // networkと、LocRibの細かいrouteに合わせた集約routeを作り直し、変わったら全てのpeerに広告し直す
+        let (as_number, router_id) = match self.peers.first() {
+            Some(peer) => (peer.get_config().get_public_as_number(), peer.get_config().get_router_id()),
+            None => return,
+        };
+        if !self.network_statements.iter().any(|network| network.requires_kernel_route) {
+            self.network_kernel_routes = None;
+        } else if self.network_kernel_routes.is_none() {
+            match KernelRouteWatcher::new(self.fib.get_backend()).await {
+                Ok(kernel_route_watcher) => self.network_kernel_routes = Some(kernel_route_watcher),
+                Err(e) => {
+                    error!("cannot read the routes in the kernel: {}", e);
+                    return;
+                },
+            }
+        }
+        if let Some(network_kernel_routes) = self.network_kernel_routes.as_mut() {
+            network_kernel_routes.poll();
+        }
+        let mut is_changed = false;
+        for network in &self.network_statements {
+            let has_kernel_route = self.network_kernel_routes.as_ref().map_or(false, |kernel_routes| kernel_routes.has_route_in(&network.prefix));
+            let route = if network.requires_kernel_route && !has_kernel_route {
+                None
+            } else {
+                Some(network.create_route(router_id))
+            };
+            is_changed |= replace_originated_route(&mut self.loc_rib, &network.prefix, RouteSource::Network, route);
+        }
+        for aggregate_address in &self.aggregate_addresses {
+            let route = aggregate_address.create_route(&self.loc_rib, as_number, router_id);
+            let source = RouteSource::Aggregate { summary_only: aggregate_address.summary_only };
+            is_changed |= replace_originated_route(&mut self.loc_rib, &aggregate_address.prefix, source, route);
+        }
+        if is_changed {
+            for peer in &mut self.peers {
+                peer.event_queue.push(Event::LocRibChanged);
+            }
+        }
+    }
+
+    pub fn start_rpki_validation(&mut self, rpki_cache_address: &str) {
+        self.rtr_client = Some(RtrClient::new(rpki_cache_address));
+        self.rpki_cache_address = Some(rpki_cache_address.to_string());
+    }
+
+    pub fn set_local_aspa_table(&mut self, aspa_table: AspaTable) {
+        info!("load {} aspas", aspa_table.0.len());
+        self.local_aspa_table = aspa_table;
+        self.update_rpki_tables();
+    }
+
+    pub fn poll_rtr_client(&mut self) {
+        // ROAかASPAのtableが変わったら全てのpeerのrouteをvalidateし直す
+        let rtr_client = match self.rtr_client.as_mut() {
+            Some(rtr_client) => rtr_client,
+            None => return,
+        };
+        if !rtr_client.poll() {
+            return;
+        }
+        self.update_rpki_tables();
+    }
+
+    fn get_rpki_tables(&self) -> (Rc<RoaTable>, Rc<AspaTable>) {
+        let (roa_table, aspa_table) = match &self.rtr_client {
+            Some(rtr_client) => (rtr_client.get_roa_table().clone(), self.local_aspa_table.merge(rtr_client.get_aspa_table())),
+            None => (RoaTable::default(), self.local_aspa_table.clone()),
+        };
+        (Rc::new(roa_table), Rc::new(aspa_table))
+    }
+
+    fn update_rpki_tables(&mut self) {
+        let (roa_table, aspa_table) = self.get_rpki_tables();
+        for peer in &mut self.peers {
+            peer.update_rpki_tables(roa_table.clone(), aspa_table.clone());
+        }
+    }
+
+    pub async fn reconcile_fib(&mut self) {
+        // 前回の起動時に書き込んだカーネルのrouteを読み込み、LocRibとの差分を取れるようにする。
+        // Graceful Restart(RFC4724)が有効ならそれらのrouteを残したまま起動し、
+        // 全てのpeerからEnd-of-RIBを受け取るかrestart timerが切れたら消す。無効なら最初のsync_fibで消す
+        self.fib.load_installed_routes().await;
+        if !self.peers.iter().any(|peer| peer.is_graceful_restart_enabled()) {
+            return;
+        }
+        if self.fib.get_installed_routes().is_empty() {
+            return;
+        }
+        info!("retain {} routes for graceful restart", self.fib.get_installed_routes().len());
+        self.is_retaining_routes = true;
+        self.retained_routes_timer = SystemTime::now();
+        for peer in &mut self.peers {
+            if peer.is_graceful_restart_enabled() {
+                self.retained_routes_time = self.retained_routes_time.max(peer.get_restart_time());
+                peer.set_graceful_restart_state(true);
+            }
+        }
+    }
+
+    pub fn stop_retaining_routes_if_needed(&mut self) {
+        if !self.is_retaining_routes {
+            return;
+        }
+        let is_synchronized = self.peers.iter()
+            .filter(|peer| peer.is_graceful_restart_enabled())
+            .all(|peer| peer.is_end_of_rib_received());
+        let is_expired = self.retained_routes_timer.elapsed().unwrap_or_default() >= self.retained_routes_time;
+        if !is_synchronized && !is_expired {
+            return;
+        }
+        // 再起動後に学習し直さなかったrouteは次のsync_fibで消える
+        self.is_retaining_routes = false;
+        for peer in &mut self.peers {
+            peer.set_graceful_restart_state(false);
+        }
+    }
+
+    pub async fn shutdown(&mut self) {
+        // 全てのpeerとのsessionを閉じる (Graceful Restartがnegotiateされていないpeerにはpeer.shutdownがCeaseを送る)。
+        // Graceful Restartが有効なpeerがあれば、再起動するまでの転送のためにカーネルのrouteを残す
+        let is_retaining_routes = self.peers.iter().any(|peer| peer.is_graceful_restart_enabled());
+        for peer in &mut self.peers {
+            peer.shutdown(&mut self.loc_rib);
+        }
+        if !is_retaining_routes {
+            self.sync_fib().await;
+        }
+    }
+
+    pub async fn sync_fib(&mut self) {
+        self.fib.sync(&self.loc_rib, self.is_retaining_routes).await;
+    }
+}
+
+fn bind_listener(my_ip_addr: Ipv4Addr) -> io::Result<TcpListener> {
+    let tcp_listener = TcpListener::bind((my_ip_addr, 179))?;
+    // main loopを止めないようにacceptはnon-blockingで行う
+    tcp_listener.set_nonblocking(true)?;
+    Ok(tcp_listener)
+}
+
+fn is_listening_on(tcp_listener: &TcpListener, my_ip_addr: Ipv4Addr) -> bool {
+    tcp_listener.local_addr().is_ok_and(|addr| addr.ip() == my_ip_addr)
+}
+
+fn replace_originated_route(loc_rib: &mut LocRib, prefix: &IpPrefix, source: RouteSource, route: Option<RoutingInformationEntry>) -> bool {
+    // LocRibの自分でoriginateしたrouteをrouteで置き換え、変わったらtrueを返す
+    let current = loc_rib.0.iter()
+        .find(|entry| entry.source == source && entry.destnation_address == *prefix)
+        .cloned();
+    match (route, current) {
+        (Some(route), Some(current)) if route.path_attributes == current.path_attributes => false,
+        (Some(route), _) => {
+            info!("originate the route: {:?}", route);
+            loc_rib.add_one_entry(route);
+            true
+        },
+        (None, Some(current)) => {
+            info!("remove the originated route: {:?}", current.destnation_address);
+            loc_rib.remove_routes(&vec![current]);
+            true
+        },
+        (None, None) => false,
     }
 }
@@ -0,0 +1,323 @@
+use std::net::Ipv4Addr;
+use regex::Regex;
+use crate::bgp::{AutonomousSystemNumber, Origin};
+use crate::rib::{AspaValidationState, RoutingInformationEntry, RpkiValidationState};
+use crate::routing::IpPrefix;
+use crate::parse_option_value;
+
+// peerごと、方向(import/export)ごとに設定するrouting policy。
+// 設定ファイルには以下のように書く。
+//   prefix-list <name> <prefix> [ge <length>] [le <length>]
+//   policy <name> <term> [<match condition>...] then <action>...
+// policyはtermを上から順に評価し、全てのmatch conditionにmatchしたtermのactionを実行する。
+// acceptかrejectが実行されたらそこで終わり、どのtermでもacceptされなかったrouteは捨てる。
+
+#[derive(Debug, Clone, PartialEq)]
+struct PrefixListEntry {
+    prefix: IpPrefix,
+    ge: Option<u8>,
+    le: Option<u8>,
+}
+
+impl PrefixListEntry {
+    fn matches(&self, destnation: &IpPrefix) -> bool {
+        // ge/leが無ければprefix長まで完全一致、あればprefixに含まれて長さが範囲内のもの
+        if !self.prefix.does_include(destnation) {
+            return false;
+        }
+        let prefix_length = destnation.get_prefix_length();
+        match (self.ge, self.le) {
+            (None, None) => prefix_length == self.prefix.get_prefix_length(),
+            (ge, le) => {
+                prefix_length >= ge.unwrap_or(self.prefix.get_prefix_length())
+                    && prefix_length <= le.unwrap_or(32)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixList {
+    pub name: String,
+    entries: Vec<PrefixListEntry>,
+}
+
+impl PrefixList {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), entries: vec![] }
+    }
+
+    pub fn add_entry_from_args(&mut self, args: &[&str]) -> Result<(), String> {
+        // <prefix> [ge <length>] [le <length>]
+        let prefix = parse_option_value("prefix of prefix-list", args.first().copied())?;
+        let mut entry = PrefixListEntry { prefix, ge: None, le: None };
+        let mut i = 1;
+        while i + 1 < args.len() {
+            let length = parse_option_value("length of prefix-list", Some(args[i+1]))?;
+            match args[i] {
+                "ge" => entry.ge = Some(length),
+                "le" => entry.le = Some(length),
+                _ => return Err(format!("unknown prefix-list option: {}", args[i])),
+            }
+            i += 2;
+        }
+        if i != args.len() {
+            return Err(format!("prefix-list option needs value: {}", args[i]));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn matches(&self, destnation: &IpPrefix) -> bool {
+        self.entries.iter().any(|entry| entry.matches(destnation))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MatchCondition {
+    PrefixList(PrefixList),
+    AsPath(Regex), // AS_PATHの文字列表現(bgp::AsPathのDisplay)に対する正規表現
+    Community(u32),
+    NextHop(Ipv4Addr),
+    Origin(Origin),
+    RpkiState(RpkiValidationState),
+    AspaState(AspaValidationState),
+}
+
+impl PartialEq for MatchCondition {
+    // Regexは比較できないので、設定ファイルに書いた正規表現で比べる
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (MatchCondition::PrefixList(a), MatchCondition::PrefixList(b)) => a == b,
+            (MatchCondition::AsPath(a), MatchCondition::AsPath(b)) => a.as_str() == b.as_str(),
+            (MatchCondition::Community(a), MatchCondition::Community(b)) => a == b,
+            (MatchCondition::NextHop(a), MatchCondition::NextHop(b)) => a == b,
+            (MatchCondition::Origin(a), MatchCondition::Origin(b)) => a == b,
+            (MatchCondition::RpkiState(a), MatchCondition::RpkiState(b)) => a == b,
+            (MatchCondition::AspaState(a), MatchCondition::AspaState(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl MatchCondition {
+    fn parse(condition: &str, prefix_lists: &Vec<PrefixList>) -> Result<Self, String> {
+        let (key, value) = condition.split_once('=').ok_or_else(|| format!("match condition needs value: {}", condition))?;
+        let condition = match key {
+            "prefix-list" => {
+                let prefix_list = prefix_lists.iter().find(|p| p.name == value)
+                    .ok_or_else(|| format!("unknown prefix-list: {}", value))?;
+                MatchCondition::PrefixList(prefix_list.clone())
+            },
+            "as-path" => {
+                // 設定ファイルは空白で区切るので、正規表現の中の`_`をAS番号の区切り(空白か先頭か末尾)として扱う
+                let regex = Regex::new(&value.replace('_', "(^| |$)")).map_err(|e| format!("cannot parse as-path regex {}: {}", value, e))?;
+                MatchCondition::AsPath(regex)
+            },
+            "community" => MatchCondition::Community(parse_community(value)?),
+            "next-hop" => MatchCondition::NextHop(parse_option_value(key, Some(value))?),
+            "origin" => MatchCondition::Origin(match value {
+                "igp" => Origin::Igp,
+                "egp" => Origin::Egp,
+                "incomplete" => Origin::Incompleted,
+                _ => return Err(format!("origin must be igp, egp or incomplete: {}", value)),
+            }),
+            "rpki" => MatchCondition::RpkiState(match value {
+                "valid" => RpkiValidationState::Valid,
+                "invalid" => RpkiValidationState::Invalid,
+                "not-found" => RpkiValidationState::NotFound,
+                _ => return Err(format!("rpki must be valid, invalid or not-found: {}", value)),
+            }),
+            "aspa" => MatchCondition::AspaState(match value {
+                "valid" => AspaValidationState::Valid,
+                "invalid" => AspaValidationState::Invalid,
+                "unknown" => AspaValidationState::Unknown,
+                _ => return Err(format!("aspa must be valid, invalid or unknown: {}", value)),
+            }),
+            _ => return Err(format!("unknown match condition: {}", condition)),
+        };
+        Ok(condition)
+    }
+
+    fn matches(&self, entry: &RoutingInformationEntry) -> bool {
+        match self {
+            MatchCondition::PrefixList(prefix_list) => prefix_list.matches(&entry.destnation_address),
+            MatchCondition::AsPath(regex) => regex.is_match(&entry.get_as_path().to_string()),
+            MatchCondition::Community(community) => entry.get_communities().contains(community),
+            MatchCondition::NextHop(next_hop) => entry.get_next_hop() == Some(*next_hop),
+            MatchCondition::Origin(origin) => entry.get_origin() == origin.value(),
+            MatchCondition::RpkiState(rpki_state) => entry.rpki_state == *rpki_state,
+            MatchCondition::AspaState(aspa_state) => entry.aspa_state == *aspa_state,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Accept,
+    Reject,
+    SetLocalPref(u32),
+    SetMultiExitDisc(u32),
+    SetCommunities(Vec<u32>),
+    Prepend(usize),
+    NextHopSelf,
+}
+
+impl Action {
+    fn parse(action: &str) -> Result<Self, String> {
+        let (key, value) = match action.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (action, None),
+        };
+        let action = match key {
+            "accept" => Action::Accept,
+            "reject" => Action::Reject,
+            "local-pref" => Action::SetLocalPref(parse_option_value(key, value)?),
+            "med" => Action::SetMultiExitDisc(parse_option_value(key, value)?),
+            "community" => {
+                // community=65000:100,65000:200 のようにカンマ区切りで書く
+                let communities = value.ok_or("community needs value")?.split(',').map(parse_community).collect::<Result<_, _>>()?;
+                Action::SetCommunities(communities)
+            },
+            "prepend" => Action::Prepend(parse_option_value(key, value)?),
+            "next-hop-self" => Action::NextHopSelf,
+            _ => return Err(format!("unknown action: {}", action)),
+        };
+        Ok(action)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PolicyTerm {
+    name: String,
+    conditions: Vec<MatchCondition>,
+    actions: Vec<Action>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub name: String,
+    terms: Vec<PolicyTerm>,
+}
+
+impl Policy {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), terms: vec![] }
+    }
+
+    pub fn add_term_from_args(&mut self, args: &[&str], prefix_lists: &Vec<PrefixList>) -> Result<(), String> {
+        // <term> [<match condition>...] then <action>...
+        let name = args.first().ok_or("policy needs term")?.to_string();
+        let then = args.iter().position(|arg| *arg == "then").ok_or_else(|| format!("policy term {} needs then", name))?;
+        if then == 0 {
+            return Err("policy needs term name before then".to_string());
+        }
+        let conditions = args[1..then].iter().map(|c| MatchCondition::parse(c, prefix_lists)).collect::<Result<_, _>>()?;
+        let actions = args[then+1..].iter().map(|a| Action::parse(a)).collect::<Result<_, _>>()?;
+        self.terms.push(PolicyTerm { name, conditions, actions });
+        Ok(())
+    }
+
+    pub fn apply(&self, entry: &RoutingInformationEntry, prepend_as_number: &AutonomousSystemNumber, next_hop_self: Ipv4Addr) -> Option<RoutingInformationEntry> {
+        // acceptされたらactionで書き換えたrouteを、rejectされたらNoneを返す
+        let mut entry = entry.clone();
+        for term in &self.terms {
+            if !term.conditions.iter().all(|condition| condition.matches(&entry)) {
+                continue;
+            }
+            for action in &term.actions {
+                match action {
+                    Action::Accept => return Some(entry),
+                    Action::Reject => {
+                        debug!("policy {} term {} rejects {:?}", self.name, term.name, entry.destnation_address);
+                        return None;
+                    },
+                    Action::SetLocalPref(local_pref) => entry.set_local_pref(*local_pref),
+                    Action::SetMultiExitDisc(multi_exit_disc) => entry.set_multi_exit_disc(*multi_exit_disc),
+                    Action::SetCommunities(communities) => entry.set_communities(communities.clone()),
+                    Action::Prepend(count) => {
+                        for _ in 0..*count {
+                            entry.add_as_path(prepend_as_number.0);
+                        }
+                    },
+                    Action::NextHopSelf => entry.change_nexthop(next_hop_self),
+                }
+            }
+        }
+        None
+    }
+}
+
+pub fn parse_community(community: &str) -> Result<u32, String> {
+    // "65000:100"のような形式
+    let (as_number, value) = community.split_once(':').ok_or_else(|| format!("community must be <as>:<value>: {}", community))?;
+    let as_number: u16 = as_number.parse().map_err(|_| format!("cannot parse community: {}", community))?;
+    let value: u16 = value.parse().map_err(|_| format!("cannot parse community: {}", community))?;
+    Ok((u32::from(as_number) << 16) | u32::from(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::{AsPath, AsPathSegment, BgpUpdateMessage, NegotiatedCapabilities, Nlri, PathAttribute};
+    use crate::rib::{AdjRibIn, RouteSource, RoutingInformationStatus};
+
+    fn entry(destnation: &str, as_path: Vec<u16>) -> RoutingInformationEntry {
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(as_path)])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+        RoutingInformationEntry::new(
+            Ipv4Addr::new(10, 0, 0, 1), destnation.parse().unwrap(), RoutingInformationStatus::Updated, path_attributes)
+    }
+
+    #[test]
+    fn test_policy_apply() {
+        let mut prefix_list = PrefixList::new("private");
+        prefix_list.add_entry_from_args(&["192.168.0.0/16", "le", "24"]).unwrap();
+        let prefix_lists = vec![prefix_list];
+        let mut policy = Policy::new("from-transit");
+        policy.add_term_from_args(&["private", "prefix-list=private", "then", "reject"], &prefix_lists).unwrap();
+        policy.add_term_from_args(&["customer", "as-path=_64513$", "then", "local-pref=200", "community=64512:100", "accept"], &prefix_lists).unwrap();
+        policy.add_term_from_args(&["others", "then", "prepend=2", "accept"], &prefix_lists).unwrap();
+        let local_as = AutonomousSystemNumber::new(64512);
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+        assert!(policy.apply(&entry("192.168.1.0/24", vec![64513]), &local_as, local_ip).is_none());
+        // le 24より長いのでprivateにはmatchしない
+        let accepted = policy.apply(&entry("192.168.1.0/25", vec![64514, 64513]), &local_as, local_ip).unwrap();
+        assert_eq!(accepted.get_local_pref(), 200);
+        assert_eq!(accepted.get_communities(), vec![parse_community("64512:100").unwrap()]);
+        let accepted = policy.apply(&entry("10.1.0.0/16", vec![64514]), &local_as, local_ip).unwrap();
+        assert_eq!(accepted.get_as_path().to_string(), "64512 64512 64514");
+    }
+
+    #[test]
+    fn test_policy_matches_origin_of_received_update() {
+        let mut policy = Policy::new("origin");
+        policy.add_term_from_args(&["incomplete", "origin=incomplete", "then", "reject"], &vec![]).unwrap();
+        policy.add_term_from_args(&["igp", "origin=igp", "then", "local-pref=200", "accept"], &vec![]).unwrap();
+        let local_as = AutonomousSystemNumber::new(64512);
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let received_route = |origin: Origin| {
+            let path_attributes = vec![
+                PathAttribute::Origin(origin),
+                PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(vec![64513])])),
+                PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+            ];
+            let nlri = vec![Nlri::new("10.100.0.0/24".parse().unwrap(), None)];
+            let raw_data = BgpUpdateMessage::new(vec![], path_attributes, nlri).decode();
+            let update_message = BgpUpdateMessage::encode(&raw_data, &NegotiatedCapabilities::default()).unwrap();
+            let mut adj_rib_in = AdjRibIn::new(vec![]);
+            adj_rib_in.add_from_update_message(update_message, &local_as, RouteSource::External(Ipv4Addr::new(10, 0, 0, 1)));
+            adj_rib_in.0[0].clone()
+        };
+
+        let accepted = policy.apply(&received_route(Origin::Igp), &local_as, local_ip).unwrap();
+        assert_eq!(accepted.get_local_pref(), 200);
+        assert!(policy.apply(&received_route(Origin::Incompleted), &local_as, local_ip).is_none());
+        // どのtermにもmatchしなければreject
+        assert!(policy.apply(&received_route(Origin::Egp), &local_as, local_ip).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use std::net::Ipv4Addr;
+use futures::channel::mpsc::UnboundedReceiver;
+use crate::bgp::{AsPath, Origin, PathAttribute};
+use crate::rib::{RouteSource, RoutingInformationEntry, RoutingInformationStatus};
+use crate::routing::IpPrefix;
+use crate::parse_option_value;
+use crate::fib::{Fib, FibChange, FibConfig, FibError, FibRoute, RT_TABLE_MAIN};
+
+// カーネルのrouteの再配布
+// 設定ファイルには以下のように書く。
+//   redistribute [protocol=kernel|static|dhcp|<番号>,...] [table=<番号>] [prefix=<prefix>,...]
+// FIBのrouteの追加/削除を購読し、条件にmatchするrouteをLocRibに追加/削除する。
+// protocolを指定しなければkernel(直結)とstaticのroute、tableを指定しなければmain tableが対象。
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedistributeConfig {
+    protocols: Vec<u8>,
+    table: u32,
+    prefixes: Vec<IpPrefix>,
+}
+
+impl Default for RedistributeConfig {
+    fn default() -> Self {
+        Self { protocols: vec![2, 4], table: RT_TABLE_MAIN, prefixes: vec![] }
+    }
+}
+
+impl RedistributeConfig {
+    pub fn parse_from_args(args: &[&str]) -> Result<Self, String> {
+        let mut config = Self::default();
+        for option in args {
+            let (key, value) = option.split_once('=').ok_or_else(|| format!("redistribute option needs value: {}", option))?;
+            match key {
+                "protocol" => {
+                    config.protocols = value.split(',').map(|protocol| match protocol {
+                        "kernel" => Ok(2),
+                        "static" => Ok(4),
+                        "dhcp" => Ok(16),
+                        _ => parse_option_value("protocol of redistribute", Some(protocol)),
+                    }).collect::<Result<_, _>>()?;
+                },
+                "table" => config.table = parse_option_value("table of redistribute", Some(value))?,
+                "prefix" => {
+                    config.prefixes = value.split(',').map(|prefix| parse_option_value("prefix of redistribute", Some(prefix))).collect::<Result<_, _>>()?;
+                },
+                _ => return Err(format!("unknown redistribute option: {}", option)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn matches(&self, route: &FibRoute, fib_config: &FibConfig) -> bool {
+        // 自分で書き込んだrouteを再配布するとループするので、FIBに設定したtableとprotocolのrouteは除く
+        let is_own_route = route.protocol == fib_config.protocol && route.table == fib_config.table;
+        !is_own_route
+            && self.protocols.contains(&route.protocol)
+            && route.table == self.table
+            && (self.prefixes.is_empty() || self.prefixes.iter().any(|p| p.does_include(&route.destination)))
+    }
+}
+
+pub enum KernelRouteChange {
+    Added(RoutingInformationEntry),
+    Deleted(RoutingInformationEntry),
+}
+
+pub struct KernelRouteMonitor {
+    config: RedistributeConfig,
+    fib_config: FibConfig,
+    router_id: Ipv4Addr,
+    changes: UnboundedReceiver<FibChange>,
+    initial_routes: Vec<FibRoute>,
+}
+
+impl KernelRouteMonitor {
+    pub async fn new(config: RedistributeConfig, router_id: Ipv4Addr, fib: &dyn Fib, fib_config: &FibConfig) -> Result<Self, FibError> {
+        // 購読してから今のrouting tableを読むことで、その間の変更を取りこぼさない
+        let changes = fib.subscribe()?;
+        let initial_routes = fib.list().await?;
+        Ok(Self { config, fib_config: *fib_config, router_id, changes, initial_routes })
+    }
+
+    pub fn poll(&mut self) -> Vec<KernelRouteChange> {
+        let mut result = vec![];
+        for route in std::mem::take(&mut self.initial_routes) {
+            if let Some(entry) = self.create_entry(&route) {
+                result.push(KernelRouteChange::Added(entry));
+            }
+        }
+        while let Ok(change) = self.changes.try_recv() {
+            match change {
+                FibChange::Added(route) => {
+                    if let Some(entry) = self.create_entry(&route) {
+                        result.push(KernelRouteChange::Added(entry));
+                    }
+                },
+                FibChange::Removed(route) => {
+                    if let Some(entry) = self.create_entry(&route) {
+                        result.push(KernelRouteChange::Deleted(entry));
+                    }
+                },
+            }
+        }
+        result
+    }
+
+    fn create_entry(&self, route: &FibRoute) -> Option<RoutingInformationEntry> {
+        if !self.config.matches(route, &self.fib_config) {
+            return None;
+        }
+        let gateway = route.gateways.first().copied().unwrap_or(Ipv4Addr::new(0, 0, 0, 0));
+        // BGP以外から学習したrouteなのでORIGINはINCOMPLETEにする
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Incompleted),
+            PathAttribute::AsPath(AsPath::new(vec![])),
+            PathAttribute::NextHop(self.router_id),
+        ];
+        let mut entry = RoutingInformationEntry::new(gateway, route.destination, RoutingInformationStatus::Updated, path_attributes);
+        entry.source = RouteSource::Redistributed;
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fib::RouteType;
+
+    #[test]
+    fn test_redistribute_config_matches() {
+        let config = RedistributeConfig::parse_from_args(&["protocol=static,kernel", "prefix=10.0.0.0/8"]).unwrap();
+        let fib_config = FibConfig::default();
+        let mut route = FibRoute { destination: "10.1.0.0/24".parse().unwrap(), gateways: vec![], protocol: 4, table: 254, metric: 0, route_type: RouteType::Unicast };
+        assert!(config.matches(&route, &fib_config));
+        route.table = 100;
+        assert!(!config.matches(&route, &fib_config));
+        route.table = 254;
+        route.protocol = fib_config.protocol;
+        assert!(!config.matches(&route, &fib_config));
+        route.protocol = 4;
+        route.destination = "192.168.0.0/24".parse().unwrap();
+        assert!(!config.matches(&route, &fib_config));
+
+        // FIBのprotocolを変えたら、そのprotocolで書き込んだrouteだけを除く
+        let config = RedistributeConfig::parse_from_args(&["protocol=3,186"]).unwrap();
+        let fib_config = FibConfig { protocol: 186, ..FibConfig::default() };
+        let mut route = FibRoute { destination: "10.1.0.0/24".parse().unwrap(), gateways: vec![], protocol: 186, table: 254, metric: 0, route_type: RouteType::Unicast };
+        assert!(!config.matches(&route, &fib_config));
+        route.protocol = 3;
+        assert!(config.matches(&route, &fib_config));
+    }
+}
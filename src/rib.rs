@@ -1,9 +1,8 @@
-use rtnetlink::packet::RouteMessage;
 use std::net::Ipv4Addr;
-use std::net::IpAddr;
-use crate::{bgp::{AutonomousSystemNumber, BgpUpdateMessage, Origin, PathAttribute}, routing::{self, IpPrefix}};
-use std::cmp::PartialEq;
+use crate::{bgp::{AutonomousSystemNumber, BgpUpdateMessage, Nlri, Origin, PathAttribute}, routing::{self, IpPrefix}};
+use std::cmp::{Ordering, PartialEq};
 use crate::bgp::AsPath;
+use crate::fib::FibRoute;
 
 #[derive(Clone, Debug)]
 pub struct Rib(pub Vec<RoutingInformationEntry>);
@@ -13,52 +12,105 @@ impl Rib {
         Rib(routing_table)
     }
 
-    pub fn add_from_route_message(&mut self, routing_information: &mut Vec<RouteMessage>, path_attributes: Vec<PathAttribute>) {
-        println!("now in Rib.add_from_route_message {:?}", routing_information);
-        for rm in routing_information {
-            println!("the route gateway: {:?}", rm.gateway());
-            if let Some((ip, prefix)) = rm.destination_prefix() {
-                let destination_address = if let IpAddr::V4(ip) = ip {
-                    IpPrefix::new(ip, prefix)
-                } else {
-                    panic!();
-                };
-                let gateway =  match rm.gateway() {
-                    Some(IpAddr::V4(gateway)) => gateway,
-                    _ => Ipv4Addr::new(0, 0, 0, 0),
-                };
-                let routing_information_entry = RoutingInformationEntry::new(
-                    gateway,
-                    destination_address,
-                    RoutingInformationStatus::Updated,
-                    path_attributes.clone(),
-                );
-                println!("Add from route message. Try to add route: {:?}", routing_information_entry);
-                self.add_if_needed(routing_information_entry)
-            }
+    pub fn add_from_fib_routes(&mut self, routing_information: &Vec<FibRoute>, path_attributes: Vec<PathAttribute>) {
+        debug!("now in Rib.add_from_fib_routes {:?}", routing_information);
+        for route in routing_information {
+            debug!("the route gateways: {:?}", route.gateways);
+            let routing_information_entry = RoutingInformationEntry::new(
+                route.gateways.first().copied().unwrap_or(Ipv4Addr::new(0, 0, 0, 0)),
+                route.destination,
+                RoutingInformationStatus::Updated,
+                path_attributes.clone(),
+            );
+            debug!("Add from fib route. Try to add route: {:?}", routing_information_entry);
+            self.add_if_needed(routing_information_entry);
         }
     }
 
 
-    pub fn add(&mut self, routing_information: Vec<RoutingInformationEntry>) {
+    pub fn add(&mut self, routing_information: Vec<RoutingInformationEntry>) -> bool {
+        // 追加したか置き換えたrouteがあればtrueを返す
+        let mut is_changed = false;
         for routing in routing_information {
-            self.add_if_needed(routing);
+            is_changed |= self.add_if_needed(routing);
         }
+        is_changed
     }
 
     pub fn add_one_entry(&mut self, one_route: RoutingInformationEntry) {
         self.add_if_needed(one_route);
     }
 
-    fn add_if_needed(&mut self, one_route: RoutingInformationEntry) {
-        if !self.0.contains(&one_route) {
-            self.0.push(one_route);
-        } else {
-            println!("the rib already have had the route, {:?}.", one_route);
-            println!("and now rib is {:?}", self.0);
+    fn add_if_needed(&mut self, one_route: RoutingInformationEntry) -> bool {
+        match self.0.iter_mut().find(|entry| **entry == one_route) {
+            None => {
+                // ADD-PATHで広告するときのpath identifierを同じdestinationの中で重ならないように振る
+                let mut one_route = one_route;
+                one_route.local_path_identifier = self.0.iter()
+                    .filter(|entry| entry.destnation_address == one_route.destnation_address)
+                    .map(|entry| entry.local_path_identifier)
+                    .max()
+                    .unwrap_or(0) + 1;
+                self.0.push(one_route);
+                true
+            },
+            Some(entry) if entry.stale || entry.nexthop != one_route.nexthop || entry.path_attributes != one_route.path_attributes || entry.rpki_state != one_route.rpki_state || entry.aspa_state != one_route.aspa_state => {
+                // RFC4271 3.1: 同じpeerから同じprefixとpath identifierのrouteが届いたらimplicit withdrawで置き換える
+                debug!("the rib already have had the route, so replace it with {:?}.", one_route);
+                if entry.nexthop != one_route.nexthop {
+                    entry.nexthop = one_route.nexthop;
+                    entry.nexthop_state = one_route.nexthop_state;
+                }
+                entry.path_attributes = one_route.path_attributes;
+                entry.source = one_route.source;
+                entry.rpki_state = one_route.rpki_state;
+                entry.aspa_state = one_route.aspa_state;
+                entry.peer_bgp_identifier = one_route.peer_bgp_identifier;
+                entry.status = RoutingInformationStatus::Updated;
+                entry.stale = false;
+                true
+            },
+            Some(_) => {
+                debug!("the rib already have had the route, {:?}.", one_route);
+                debug!("and now rib is {:?}", self.0);
+                false
+            },
+        }
+    }
+
+    pub fn remove_routes(&mut self, routes: &Vec<RoutingInformationEntry>) {
+        // 同じpeerから学習したrouteだけを消す
+        self.0.retain(|entry| !routes.iter().any(|route| route == entry && route.source == entry.source));
+    }
+
+    pub fn remove_routes_by_nlri(&mut self, nlri: &Vec<Nlri>) -> Vec<RoutingInformationEntry> {
+        let is_withdrawn = |entry: &RoutingInformationEntry| nlri.iter().any(|n| {
+            n.ip_prefix == entry.destnation_address && n.path_identifier.unwrap_or(0) == entry.path_identifier
+        });
+        let removed: Vec<RoutingInformationEntry> = self.0.iter()
+            .filter(|entry| is_withdrawn(entry))
+            .cloned()
+            .collect();
+        self.0.retain(|entry| !is_withdrawn(entry));
+        removed
+    }
+
+    pub fn remove_withdrawn_routes(&mut self) {
+        self.0.retain(|entry| entry.status != RoutingInformationStatus::Withdrawn);
+    }
+
+    pub fn mark_all_routes_as_stale(&mut self) {
+        for entry in &mut self.0 {
+            entry.stale = true;
         }
     }
 
+    pub fn remove_stale_routes(&mut self) -> Vec<RoutingInformationEntry> {
+        let removed: Vec<RoutingInformationEntry> = self.0.iter().filter(|entry| entry.stale).cloned().collect();
+        self.0.retain(|entry| !entry.stale);
+        removed
+    }
+
     pub fn does_have_new_route(&self) -> bool {
         for route in &self.0 {
             if !(route.status == RoutingInformationStatus::UnChanged) {
@@ -68,28 +120,21 @@ impl Rib {
         return false
     }
 
-    pub fn does_have_should_update_route(&self) -> bool {
-        for route in &self.0 {
-            if route.update_status == UpdateStatus::ShouldUpdate {
-                return true
-            }
-        }
-        return false
-    }
-
     pub fn change_state_of_all_routing_information_to_unchanged(&mut self) {
         for entry in &mut self.0 {
             entry.status = RoutingInformationStatus::UnChanged;
         }
     }
 
-    pub fn change_update_state_of_all_routing_information_to_updated(&mut self) {
+    pub fn change_state_of_all_routing_information_to_updated(&mut self) {
         for entry in &mut self.0 {
-            entry.update_status = UpdateStatus::Updated;
+            if entry.status != RoutingInformationStatus::Withdrawn {
+                entry.status = RoutingInformationStatus::Updated;
+            }
         }
     }
 
-    pub fn add_from_update_message(&mut self, mut update_message: BgpUpdateMessage, my_as_number: &AutonomousSystemNumber) {
+    pub fn add_from_update_message(&mut self, update_message: BgpUpdateMessage, my_as_number: &AutonomousSystemNumber, source: RouteSource) {
         let mut nexthop = Ipv4Addr::new(0, 0, 0, 0);
         for path_attribute in &update_message.path_attributes {
             match &path_attribute {
@@ -99,17 +144,65 @@ impl Rib {
                 _ => (),
             }
         }
-        for p in &mut update_message.path_attributes {
-            match p {
-                PathAttribute::Origin(origin) => *origin = Origin::Egp,
-                _ => (),
-            }
-        }
         let routing_information: Vec<RoutingInformationEntry> = update_message.network_layer_reachability_information.iter().map(
-            |dest| RoutingInformationEntry::new(nexthop, *dest, RoutingInformationStatus::Updated, update_message.path_attributes.clone())).collect();
+            |nlri| {
+                let mut entry = RoutingInformationEntry::new(nexthop, nlri.ip_prefix, RoutingInformationStatus::Updated, update_message.path_attributes.clone());
+                entry.source = source;
+                entry.path_identifier = nlri.path_identifier.unwrap_or(0);
+                entry
+            }).collect();
         self.add(routing_information);
     }
 
+    pub fn select_best_paths(&self) -> Vec<RoutingInformationEntry> {
+        // 同じdestinationを持つpathの中から一番良いものだけを返す
+        self.select_best_n_paths(Some(1))
+    }
+
+    pub fn select_best_n_paths(&self, n: Option<usize>) -> Vec<RoutingInformationEntry> {
+        // 同じdestinationを持つpathを良い順に並べて、nが指定されていれば上位n個だけを返す
+        // nexthopに到達できないpathは選ばない
+        let mut paths_per_destination: Vec<Vec<RoutingInformationEntry>> = vec![];
+        for entry in self.0.iter().filter(|entry| entry.nexthop_state != NexthopState::Unreachable) {
+            match paths_per_destination.iter_mut().find(|paths| paths[0].destnation_address == entry.destnation_address) {
+                Some(paths) => paths.push(entry.clone()),
+                None => paths_per_destination.push(vec![entry.clone()]),
+            }
+        }
+        let mut best_paths = vec![];
+        for mut paths in paths_per_destination {
+            paths.sort_by(|a, b| {
+                if a.is_preferable_to(b) {
+                    Ordering::Less
+                } else if b.is_preferable_to(a) {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            });
+            if let Some(n) = n {
+                paths.truncate(n);
+            }
+            best_paths.append(&mut paths);
+        }
+        best_paths
+    }
+
+    pub fn select_multipaths(&self, maximum_paths: usize, relaxes_neighbor_as: bool) -> Vec<Vec<RoutingInformationEntry>> {
+        // destinationごとにbest pathと、best pathとequal-costなpathを合わせて最大maximum_paths個返す
+        let mut result: Vec<Vec<RoutingInformationEntry>> = vec![];
+        for entry in self.select_best_n_paths(None) {
+            match result.last_mut() {
+                Some(paths) if paths[0].destnation_address == entry.destnation_address => {
+                    if paths.len() < maximum_paths && paths[0].is_equal_cost_to(&entry, relaxes_neighbor_as) {
+                        paths.push(entry);
+                    }
+                },
+                _ => result.push(vec![entry]),
+            }
+        }
+        result
+    }
 
     pub fn get_new_route(&self) -> Vec<RoutingInformationEntry> {
         self.0.clone()
@@ -125,13 +218,25 @@ pub struct RoutingInformationEntry {
     pub destnation_address: IpPrefix,
     pub status: RoutingInformationStatus,
     pub path_attributes: Vec<PathAttribute>,
-    pub update_status: UpdateStatus,
+    pub source: RouteSource,
+    pub stale: bool,
+    // ADD-PATH(RFC7911)でpeerから受け取った/peerへ広告するpath identifier
+    pub path_identifier: u32,
+    // ADD-PATHで広告するときに使う、このRibの中でのpath identifier
+    pub local_path_identifier: u32,
+    pub rpki_state: RpkiValidationState,
+    pub aspa_state: AspaValidationState,
+    pub nexthop_state: NexthopState,
+    // iBGP peerから学習したrouteをreflectするときにORIGINATOR_IDにする、そのpeerのBGP Identifier
+    pub peer_bgp_identifier: Option<Ipv4Addr>,
 }
 
 impl PartialEq for RoutingInformationEntry {
     fn eq(&self, other: &RoutingInformationEntry) -> bool {
-        self.nexthop == other.nexthop
+        // pathは学習元のpeer、prefix、path identifierで決まる。nexthopが変わってもimplicit withdrawで置き換える
+        self.source.get_peer_ip_addr() == other.source.get_peer_ip_addr()
         && self.destnation_address == other.destnation_address
+        && self.path_identifier == other.path_identifier
     }
 }
 
@@ -143,15 +248,291 @@ pub enum RoutingInformationStatus {
 }
 
 #[derive(Clone, Copy, std::cmp::PartialEq, Debug)]
-pub enum UpdateStatus {
-    ShouldUpdate,
-    Updated
+pub enum RouteSource {
+    // 自分でoriginateしたroute
+    Local,
+    // eBGP peerから学習したroute
+    External(Ipv4Addr),
+    // iBGP peerから学習したroute
+    Internal(Ipv4Addr),
+    // Route Reflector ClientであるiBGP peerから学習したroute
+    RouteReflectorClient(Ipv4Addr),
+    // 同じconfederationの別のmember ASのpeerから学習したroute
+    ConfederationExternal(Ipv4Addr),
+    // aggregate-addressで自分でoriginateした集約route
+    Aggregate { summary_only: bool },
+    // networkで自分でoriginateしたroute
+    Network,
+    // redistributeでカーネルから再配布したroute
+    Redistributed,
+}
+
+impl RouteSource {
+    pub fn is_internal(&self) -> bool {
+        match self {
+            RouteSource::Internal(_) | RouteSource::RouteReflectorClient(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        match self {
+            RouteSource::Local | RouteSource::Aggregate { .. } | RouteSource::Network | RouteSource::Redistributed => true,
+            _ => false,
+        }
+    }
+
+    pub fn get_peer_ip_addr(&self) -> Option<Ipv4Addr> {
+        match self {
+            RouteSource::Local | RouteSource::Aggregate { .. } | RouteSource::Network | RouteSource::Redistributed => None,
+            RouteSource::External(peer) | RouteSource::Internal(peer)
+                | RouteSource::RouteReflectorClient(peer) | RouteSource::ConfederationExternal(peer) => Some(*peer),
+        }
+    }
+}
+
+#[derive(Clone, Copy, std::cmp::PartialEq, Debug)]
+pub enum RpkiValidationState {
+    // RFC6811のRoute Origin Validationの結果
+    Valid,
+    Invalid,
+    NotFound,
+}
+
+#[derive(Clone, Copy, std::cmp::PartialEq, Debug)]
+pub enum AspaValidationState {
+    // ASPAによるAS_PATHのverificationの結果
+    Valid,
+    Invalid,
+    Unknown,
 }
 
+#[derive(Clone, Copy, std::cmp::PartialEq, Debug)]
+pub enum NexthopState {
+    // nexthopまでのIGP cost(nexthopを解決したカーネルのrouteのmetric)
+    Reachable(u32),
+    // best pathの選択から外す
+    Unreachable,
+}
+
+pub const DEFAULT_LOCAL_PREF: u32 = 100;
+// AS_PATHが無いrouteは空のAS_PATHを持つものとして扱う
+static EMPTY_AS_PATH: AsPath = AsPath(Vec::new());
+
 impl RoutingInformationEntry {
 
     pub fn new(nexthop: Ipv4Addr, destnation_address: IpPrefix, status: RoutingInformationStatus, path_attributes: Vec<PathAttribute>) -> Self {
-        Self {nexthop, destnation_address, status, path_attributes, update_status: UpdateStatus::ShouldUpdate}
+        Self {nexthop, destnation_address, status, path_attributes, source: RouteSource::Local, stale: false, path_identifier: 0, local_path_identifier: 0, rpki_state: RpkiValidationState::NotFound, aspa_state: AspaValidationState::Unknown, nexthop_state: NexthopState::Reachable(0), peer_bgp_identifier: None}
+    }
+
+    pub fn get_local_pref(&self) -> u32 {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::LocalPref(local_pref) => return *local_pref,
+                _ => (),
+            }
+        }
+        DEFAULT_LOCAL_PREF
+    }
+
+    pub fn set_local_pref(&mut self, local_pref_v: u32) {
+        for p in &mut self.path_attributes {
+            match p {
+                PathAttribute::LocalPref(local_pref) => {
+                    *local_pref = local_pref_v;
+                    return;
+                },
+                _ => (),
+            }
+        }
+        self.path_attributes.push(PathAttribute::LocalPref(local_pref_v));
+    }
+
+    pub fn remove_local_pref(&mut self) {
+        self.path_attributes.retain(|p| match p {
+            PathAttribute::LocalPref(_) => false,
+            _ => true,
+        });
+    }
+
+    pub fn set_multi_exit_disc(&mut self, multi_exit_disc_v: u32) {
+        for p in &mut self.path_attributes {
+            match p {
+                PathAttribute::MultiExitDisc(multi_exit_disc) => {
+                    *multi_exit_disc = multi_exit_disc_v;
+                    return;
+                },
+                _ => (),
+            }
+        }
+        self.path_attributes.push(PathAttribute::MultiExitDisc(multi_exit_disc_v));
+    }
+
+    pub fn remove_multi_exit_disc(&mut self) {
+        self.path_attributes.retain(|p| match p {
+            PathAttribute::MultiExitDisc(_) => false,
+            _ => true,
+        });
+    }
+
+    pub fn get_communities(&self) -> Vec<u32> {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::Communities(communities) => return communities.clone(),
+                _ => (),
+            }
+        }
+        vec![]
+    }
+
+    pub fn set_communities(&mut self, communities: Vec<u32>) {
+        self.path_attributes.retain(|p| match p {
+            PathAttribute::Communities(_) => false,
+            _ => true,
+        });
+        if !communities.is_empty() {
+            self.path_attributes.push(PathAttribute::Communities(communities));
+        }
+    }
+
+    pub fn get_only_to_customer(&self) -> Option<u32> {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::OnlyToCustomer(as_number) => return Some(*as_number),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn set_only_to_customer_if_absent(&mut self, as_number: u32) {
+        if self.get_only_to_customer().is_none() {
+            self.path_attributes.push(PathAttribute::OnlyToCustomer(as_number));
+        }
+    }
+
+    pub fn get_next_hop(&self) -> Option<Ipv4Addr> {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::NextHop(next_hop) => return Some(*next_hop),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn get_originator_id(&self) -> Option<Ipv4Addr> {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::OriginatorId(originator_id) => return Some(*originator_id),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn set_originator_id_if_absent(&mut self, originator_id: Ipv4Addr) {
+        if self.get_originator_id().is_none() {
+            self.path_attributes.push(PathAttribute::OriginatorId(originator_id));
+        }
+    }
+
+    pub fn get_cluster_list(&self) -> Vec<Ipv4Addr> {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::ClusterList(cluster_list) => return cluster_list.clone(),
+                _ => (),
+            }
+        }
+        vec![]
+    }
+
+    pub fn prepend_cluster_id(&mut self, cluster_id: Ipv4Addr) {
+        for p in &mut self.path_attributes {
+            match p {
+                PathAttribute::ClusterList(cluster_list) => {
+                    cluster_list.insert(0, cluster_id);
+                    return;
+                },
+                _ => (),
+            }
+        }
+        self.path_attributes.push(PathAttribute::ClusterList(vec![cluster_id]));
+    }
+
+    pub fn remove_route_reflector_attributes(&mut self) {
+        self.path_attributes.retain(|p| match p {
+            PathAttribute::OriginatorId(_) | PathAttribute::ClusterList(_) => false,
+            _ => true,
+        });
+    }
+
+    pub fn get_origin(&self) -> u8 {
+        for path in &self.path_attributes {
+            match &path {
+                &PathAttribute::Origin(origin) => return origin.value(),
+                _ => (),
+            }
+        }
+        Origin::Incompleted.value()
+    }
+
+    pub fn is_preferable_to(&self, other: &RoutingInformationEntry) -> bool {
+        // RFC4271 9.1.2.2の簡易版
+        // 1. LOCAL_PREFが大きい方
+        // 2. 自分でoriginateしたroute
+        // 3. AS_PATHが短い方
+        // 4. ORIGINが小さい方
+        // 5. iBGPよりeBGP
+        // 6. nexthopまでのIGP costが小さい方
+        // 7. CLUSTER_LISTが短い方
+        // 8. nexthopのアドレスが小さい方
+        if self.get_local_pref() != other.get_local_pref() {
+            return self.get_local_pref() > other.get_local_pref();
+        }
+        if self.source.is_local() != other.source.is_local() {
+            return self.source.is_local();
+        }
+        if self.get_as_path().path_length() != other.get_as_path().path_length() {
+            return self.get_as_path().path_length() < other.get_as_path().path_length();
+        }
+        if self.get_origin() != other.get_origin() {
+            return self.get_origin() < other.get_origin();
+        }
+        if self.source.is_internal() != other.source.is_internal() {
+            return !self.source.is_internal();
+        }
+        if self.get_igp_cost() != other.get_igp_cost() {
+            return self.get_igp_cost() < other.get_igp_cost();
+        }
+        if self.get_cluster_list().len() != other.get_cluster_list().len() {
+            return self.get_cluster_list().len() < other.get_cluster_list().len();
+        }
+        self.nexthop < other.nexthop
+    }
+
+    pub fn is_equal_cost_to(&self, other: &RoutingInformationEntry, relaxes_neighbor_as: bool) -> bool {
+        // BGP multipath: is_preferable_toの1.から7.までが同じで、nexthopが違うpath
+        // relaxes_neighbor_asがfalseなら隣のASも同じでなければならない
+        if self.source.is_local() || other.source.is_local() || self.nexthop == other.nexthop {
+            return false;
+        }
+        if !relaxes_neighbor_as && self.get_as_path().get_neighbor_as_number() != other.get_as_path().get_neighbor_as_number() {
+            return false;
+        }
+        self.get_local_pref() == other.get_local_pref()
+            && self.get_as_path().path_length() == other.get_as_path().path_length()
+            && self.get_origin() == other.get_origin()
+            && self.source.is_internal() == other.source.is_internal()
+            && self.get_igp_cost() == other.get_igp_cost()
+            && self.get_cluster_list().len() == other.get_cluster_list().len()
+    }
+
+    pub fn get_igp_cost(&self) -> u32 {
+        match self.nexthop_state {
+            NexthopState::Reachable(igp_cost) => igp_cost,
+            NexthopState::Unreachable => u32::MAX,
+        }
     }
 
     pub fn get_as_path(&self) -> &AsPath {
@@ -161,22 +542,31 @@ impl RoutingInformationEntry {
                 _ => (),
             }
         }
-        panic!();
+        &EMPTY_AS_PATH
     }
 
     pub fn add_as_path(&mut self, as_path_v: u16) {
         for p in &mut self.path_attributes {
             match p {
-                PathAttribute::AsPath(as_path) => {
-                    match as_path {
-                        AsPath::AsSequence(as_path) => {
-                            as_path.push(as_path_v);
-                        },
-                        AsPath::AsSet(as_path) => {
-                            as_path.push(as_path_v);
-                        }
-                   };
-                },
+                PathAttribute::AsPath(as_path) => as_path.prepend(as_path_v),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn add_confederation_as_path(&mut self, as_path_v: u16) {
+        for p in &mut self.path_attributes {
+            match p {
+                PathAttribute::AsPath(as_path) => as_path.prepend_confederation(as_path_v),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn remove_confederation_as_path(&mut self) {
+        for p in &mut self.path_attributes {
+            match p {
+                PathAttribute::AsPath(as_path) => as_path.remove_confederation_segments(),
                 _ => (),
             }
         }
@@ -215,3 +605,103 @@ enum Protocol {
 pub type LocRib = Rib;
 pub type AdjRibOut = Rib;
 pub type AdjRibIn = Rib;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgp::AsPathSegment;
+
+    fn path_attributes(as_path: Vec<u16>) -> Vec<PathAttribute> {
+        vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::new(vec![AsPathSegment::AsSequence(as_path)])),
+            PathAttribute::NextHop(Ipv4Addr::new(10, 0, 0, 1)),
+        ]
+    }
+
+    #[test]
+    fn test_select_best_paths_prefers_higher_local_pref() {
+        let destination = IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24);
+        let mut short_path = RoutingInformationEntry::new(
+            Ipv4Addr::new(10, 0, 0, 1), destination, RoutingInformationStatus::Updated, path_attributes(vec![64513]));
+        short_path.source = RouteSource::External(Ipv4Addr::new(10, 0, 0, 1));
+        let mut long_path = RoutingInformationEntry::new(
+            Ipv4Addr::new(10, 0, 0, 2), destination, RoutingInformationStatus::Updated, path_attributes(vec![64514, 64515]));
+        long_path.source = RouteSource::Internal(Ipv4Addr::new(10, 0, 0, 2));
+        let rib = Rib::new(vec![short_path.clone(), long_path.clone()]);
+        assert_eq!(rib.select_best_paths()[0].nexthop, short_path.nexthop);
+
+        long_path.set_local_pref(200);
+        let rib = Rib::new(vec![short_path, long_path.clone()]);
+        let best_paths = rib.select_best_paths();
+        assert_eq!(best_paths.len(), 1);
+        assert_eq!(best_paths[0].nexthop, long_path.nexthop);
+    }
+
+    #[test]
+    fn test_select_multipaths() {
+        let destination = IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24);
+        let path = |nexthop: [u8; 4], as_path: Vec<u16>| {
+            let mut entry = RoutingInformationEntry::new(
+                Ipv4Addr::from(nexthop), destination, RoutingInformationStatus::Updated, path_attributes(as_path));
+            entry.source = RouteSource::External(Ipv4Addr::from(nexthop));
+            entry
+        };
+        let rib = Rib::new(vec![
+            path([10, 0, 0, 1], vec![64513, 64600]),
+            path([10, 0, 0, 2], vec![64513, 64601]),
+            path([10, 0, 0, 3], vec![64514, 64600]),
+            path([10, 0, 0, 4], vec![64513, 64514, 64600]),
+        ]);
+        let nexthops = |multipaths: Vec<Vec<RoutingInformationEntry>>| -> Vec<Ipv4Addr> {
+            multipaths[0].iter().map(|entry| entry.nexthop).collect()
+        };
+        assert_eq!(nexthops(rib.select_multipaths(4, false)), vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+        assert_eq!(nexthops(rib.select_multipaths(4, true)), vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3)]);
+        assert_eq!(nexthops(rib.select_multipaths(1, true)), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn test_remove_routes_with_same_nexthop_from_two_peers() {
+        // Route Reflectorから同じnexthopのrouteが2つ届いても別のpathとして扱い、両方のwithdrawで消える
+        let destination = IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24);
+        let path = |peer: [u8; 4]| {
+            let mut entry = RoutingInformationEntry::new(
+                Ipv4Addr::new(10, 0, 0, 1), destination, RoutingInformationStatus::Updated, path_attributes(vec![64513]));
+            entry.source = RouteSource::Internal(Ipv4Addr::from(peer));
+            entry
+        };
+        let mut loc_rib = Rib::new(vec![]);
+        loc_rib.add(vec![path([10, 0, 1, 1]), path([10, 0, 1, 2])]);
+        assert_eq!(loc_rib.0.len(), 2);
+        assert_eq!(loc_rib.select_best_n_paths(None).len(), 2);
+
+        loc_rib.remove_routes(&vec![path([10, 0, 1, 1])]);
+        assert_eq!(loc_rib.0.len(), 1);
+        assert_eq!(loc_rib.0[0].source, RouteSource::Internal(Ipv4Addr::new(10, 0, 1, 2)));
+        loc_rib.remove_routes(&vec![path([10, 0, 1, 2])]);
+        assert!(loc_rib.0.is_empty());
+    }
+
+    #[test]
+    fn test_implicit_withdraw_with_different_nexthop() {
+        // 同じpeerが同じprefixをnexthopを変えて広告し直したら、古いpathを置き換える
+        let destination = IpPrefix::new(Ipv4Addr::new(10, 100, 0, 0), 24);
+        let path = |nexthop: [u8; 4]| {
+            let mut entry = RoutingInformationEntry::new(
+                Ipv4Addr::from(nexthop), destination, RoutingInformationStatus::Updated, path_attributes(vec![64513]));
+            entry.source = RouteSource::External(Ipv4Addr::new(10, 0, 0, 1));
+            entry
+        };
+        let mut adj_rib_in = Rib::new(vec![]);
+        adj_rib_in.add(vec![path([10, 0, 0, 1])]);
+        adj_rib_in.change_state_of_all_routing_information_to_unchanged();
+        assert!(adj_rib_in.add(vec![path([10, 0, 0, 2])]));
+        assert_eq!(adj_rib_in.0.len(), 1);
+        assert_eq!(adj_rib_in.0[0].nexthop, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(adj_rib_in.0[0].status, RoutingInformationStatus::Updated);
+
+        adj_rib_in.remove_routes(&vec![path([10, 0, 0, 2])]);
+        assert!(adj_rib_in.0.is_empty());
+    }
+}
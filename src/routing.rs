@@ -1,17 +1,19 @@
-use rtnetlink::{Error, Handle, IpVersion, RouteAddRequest, new_connection};
-use rtnetlink::packet::rtnl::RouteMessage;
-use futures::stream::{self, TryStreamExt};
-use std::{os::raw, str::FromStr};
-use std::net::{Ipv4Addr, IpAddr};
-use std::net::AddrParseError;
-use crate::rib::{LocRib, RoutingInformationStatus, UpdateStatus};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use std::str::FromStr;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct IpPrefix {
     network_address: Ipv4Addr, // ToDo: 正確にはネットワークアドレス的なやつなのでipv4addrを使うのは不適切
     prefix_length: u8,
 }
 
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network_address, self.prefix_length)
+    }
+}
+
 impl IpPrefix {
     pub fn new(network_address: Ipv4Addr, prefix_length: u8) -> Self {
         Self {
@@ -65,6 +67,19 @@ impl IpPrefix {
         }
     }
 
+    pub fn is_less_specific_than(&self, other: &Self) -> bool {
+        // otherを含んでいて、otherより短いprefixか
+        self.prefix_length < other.prefix_length && self.does_include(other)
+    }
+
+    pub fn get_network_address(&self) -> Ipv4Addr {
+        self.network_address
+    }
+
+    pub fn get_prefix_length(&self) -> u8 {
+        self.prefix_length
+    }
+
     pub fn does_include(&self, other: &Self) -> bool {
         // 192.168.0.0 / 16
         // same.same.0.0
@@ -94,105 +109,24 @@ impl IpPrefix {
 }
 
 impl FromStr for IpPrefix {
-    type Err = AddrParseError;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split('/').collect::<Vec<_>>();
-        let network_address: Ipv4Addr = split[0].parse().unwrap();
-        let prefix_length: u8 = split[1].parse().unwrap();
-        Ok(Self {network_address, prefix_length,})
-    }
-}
-
-async fn routing_table_example() -> Result<(), ()> {
-    let (connection, handle, _) = new_connection().unwrap();
-    tokio::spawn(connection);
-
-    println!("dumping routes for IPv4");
-    if let Err(e) = dump_addresses(handle.clone(), IpVersion::V4).await {
-        eprintln!("{}", e);
-    }
-    println!();
-
-    println!("dumping routes for IPv6");
-    if let Err(e) = dump_addresses(handle.clone(), IpVersion::V6).await {
-        eprintln!("{}", e);
-    }
-    println!();
-
-    Ok(())
-}
-
-fn print_typename<T>(_: &T) {
-    println!("{}", std::any::type_name::<T>());
-}
-
-pub async fn get_all_ip_v4_routes() -> Result<Vec<RouteMessage>, Error> {
-    let (connection, handle, _) = new_connection().unwrap();
-    tokio::spawn(connection);
-    let mut routes = handle.route().get(IpVersion::V4).execute();
-    let mut result = vec![];
-    while let Some(route) = routes.try_next().await? {
-        result.push(route);
-    }
-    Ok(result)
-}
-
-pub async fn write_ip_v4_route(loc_rib: &mut LocRib) {
-    let (connection, handle, _) = new_connection().unwrap();
-    tokio::spawn(connection);
-    for entry in &mut loc_rib.0 {
-        if entry.update_status == UpdateStatus::ShouldUpdate && entry.status == RoutingInformationStatus::Updated {
-            let mut add_request = handle.route().add().v4();
-            let destnation = &entry.destnation_address;
-            let gateway = &entry.nexthop;
-            let ret = add_request.protocol(3).destination_prefix(destnation.network_address.clone(), destnation.prefix_length.clone()).gateway(gateway.clone()).execute().await;
-            ret.unwrap();
-            entry.update_status = UpdateStatus::Updated;
+        // "10.0.0.0/24"のような形式
+        let (network_address, prefix_length) = s.split_once('/').ok_or_else(|| format!("prefix must be <address>/<length>: {}", s))?;
+        let network_address: Ipv4Addr = network_address.parse().map_err(|_| format!("cannot parse address of prefix: {}", s))?;
+        let prefix_length: u8 = prefix_length.parse().map_err(|_| format!("cannot parse length of prefix: {}", s))?;
+        if prefix_length > 32 {
+            return Err(format!("length of prefix must be at most 32: {}", s));
         }
+        Ok(Self {network_address, prefix_length,})
     }
-    ()
-}
-
-pub async fn lookup_network_route(ip_prefix: &IpPrefix) -> Result<Vec<RouteMessage>, Error> {
-    let all_routes = get_all_ip_v4_routes().await.unwrap();
-    let mut result = vec![];
-    for route in all_routes {
-        if let Some((network_address, prefix_length)) = route.destination_prefix() {
-            let network_address = match network_address {
-                IpAddr::V4(addr) => addr,
-                IpAddr::V6(_) => panic!(),
-            };
-            let prefix = IpPrefix {
-                network_address, prefix_length,
-            };
-            if ip_prefix.does_include(&prefix) {
-                result.push(route);
-            }
-        }
-    }
-    Ok(result)
-}
-
-async fn dump_addresses(handle: Handle, ip_version: IpVersion) -> Result<(), Error> {
-    let mut routes = handle.route().get(ip_version).execute();
-    while let Some(route) = routes.try_next().await? {
-        print_typename(&route);
-        println!("{:?}", route);
-    }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[tokio::test]
-    async fn it_works() {
-        routing_table_example().await;
-        assert_eq!(2 + 2, 4);
-    }
-
     #[tokio::test]
     async fn test_does_ip_prefix_include() {
         let bigger_ip_prefix = IpPrefix {
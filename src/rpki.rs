@@ -0,0 +1,513 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime};
+use crate::bgp::AsPath;
+use crate::aspa::{Aspa, AspaTable};
+use crate::rib::RpkiValidationState;
+use crate::routing::IpPrefix;
+
+// RPKIのRoute Origin Validation (RFC6811) と、
+// validated ROAをcacheから受け取るRPKI to Router Protocol (RFC8210) のclient。
+// ASPAはversion 2 (draft-ietf-sidrops-8210bis) でだけ受け取れる。
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Roa {
+    pub prefix: IpPrefix,
+    pub max_length: u8,
+    pub origin_as: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RoaTable(pub Vec<Roa>);
+
+impl RoaTable {
+    pub fn validate(&self, destnation: &IpPrefix, as_path: &AsPath) -> RpkiValidationState {
+        // RFC6811 2.
+        // - routeのprefixを含むROAが1つも無ければNotFound
+        // - 含むROAのうち、origin ASが一致してprefix長がmax length以下のものがあればValid
+        // - それ以外はInvalid
+        let origin_as = as_path.get_origin_as();
+        let mut is_covered = false;
+        for roa in self.0.iter().filter(|roa| roa.prefix.does_include(destnation)) {
+            is_covered = true;
+            let is_matched = match origin_as {
+                Some(origin_as) => roa.origin_as != 0 && roa.origin_as == u32::from(origin_as),
+                None => false,
+            };
+            if is_matched && destnation.get_prefix_length() <= roa.max_length {
+                return RpkiValidationState::Valid;
+            }
+        }
+        if is_covered {
+            RpkiValidationState::Invalid
+        } else {
+            RpkiValidationState::NotFound
+        }
+    }
+}
+
+// RTRのPDUのバイト列の表現は以下の通り
+// (<protocol version>: u8, <PDU type>: u8, <session id or zero>: u16, <length>: u32, <PDU固有のfield>)
+// cacheが対応していなければversionを下げて繋ぎ直す
+const RTR_PROTOCOL_VERSION: u8 = 2;
+const RTR_HEADER_LENGTH: usize = 8;
+// cacheに繋がらないときにBGPのsessionを止めないよう、connectは短い時間で諦める
+const RTR_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// RFC8210 12: Corrupt Data
+const RTR_ERROR_CORRUPT_DATA: u16 = 0;
+
+#[derive(Debug)]
+struct MalformedRtrPduError {
+    pdu_type: u8,
+    length: usize,
+}
+impl fmt::Display for MalformedRtrPduError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed rtr pdu: type {} length {}", self.pdu_type, self.length)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RtrPdu {
+    SerialNotify { session_id: u16, serial: u32 }, // PDU type 0
+    CacheResponse { session_id: u16 }, // PDU type 3
+    Ipv4Prefix { announce: bool, roa: Roa }, // PDU type 4
+    EndOfData { session_id: u16, serial: u32, refresh_interval: Option<u32> }, // PDU type 7
+    CacheReset, // PDU type 8
+    ErrorReport { error_code: u16 }, // PDU type 10
+    Aspa { announce: bool, aspa: Aspa }, // PDU type 11
+    DontKnow(u8), // IPv6 PrefixやRouter Keyなど、つかわないもの
+}
+
+impl RtrPdu {
+    fn check_length(pdu_type: u8, length: usize) -> Result<(), MalformedRtrPduError> {
+        // RFC8210 5: PDU typeごとに決まった長さ。End of Dataはversion 0だけ12
+        let is_valid = match pdu_type {
+            0 => length == 12,
+            3 | 8 => length == RTR_HEADER_LENGTH,
+            4 => length == 20,
+            7 => length == 12 || length == 24,
+            10 => length >= 16,
+            11 => length >= 12 && (length - 12) % 4 == 0,
+            _ => length >= RTR_HEADER_LENGTH,
+        };
+        if is_valid {
+            Ok(())
+        } else {
+            Err(MalformedRtrPduError { pdu_type, length })
+        }
+    }
+
+    fn encode(raw_data: &[u8]) -> Result<Self, MalformedRtrPduError> {
+        // PDU 1つ分だけを渡す。headerが無ければPDU typeも読めない
+        if raw_data.len() < RTR_HEADER_LENGTH {
+            let pdu_type = raw_data.get(1).copied().unwrap_or(0);
+            return Err(MalformedRtrPduError { pdu_type, length: raw_data.len() });
+        }
+        let pdu_type = raw_data[1];
+        RtrPdu::check_length(pdu_type, raw_data.len())?;
+        let session_id = u16::from_be_bytes(raw_data[2..4].try_into().unwrap());
+        let pdu = match pdu_type {
+            0 => RtrPdu::SerialNotify {
+                session_id,
+                serial: u32::from_be_bytes(raw_data[8..12].try_into().unwrap()),
+            },
+            3 => RtrPdu::CacheResponse { session_id },
+            4 => {
+                let prefix = IpPrefix::new(
+                    Ipv4Addr::new(raw_data[12], raw_data[13], raw_data[14], raw_data[15]),
+                    raw_data[9]);
+                let roa = Roa {
+                    prefix,
+                    max_length: raw_data[10],
+                    origin_as: u32::from_be_bytes(raw_data[16..20].try_into().unwrap()),
+                };
+                RtrPdu::Ipv4Prefix { announce: raw_data[8] & 1 == 1, roa }
+            },
+            7 => {
+                // version 0のEnd of Dataにはrefresh intervalなどが無い
+                let refresh_interval = if raw_data.len() >= 24 {
+                    Some(u32::from_be_bytes(raw_data[12..16].try_into().unwrap()))
+                } else {
+                    None
+                };
+                RtrPdu::EndOfData {
+                    session_id,
+                    serial: u32::from_be_bytes(raw_data[8..12].try_into().unwrap()),
+                    refresh_interval,
+                }
+            },
+            8 => RtrPdu::CacheReset,
+            10 => RtrPdu::ErrorReport { error_code: session_id },
+            11 => {
+                // (<header>: flags(u8)とzero(u8)がsession idの位置にある, <customer AS>: u32, <provider AS>: u32 * n)
+                let mut providers = vec![];
+                let mut i = 12;
+                while i + 4 <= raw_data.len() {
+                    providers.push(u32::from_be_bytes(raw_data[i..i+4].try_into().unwrap()));
+                    i += 4;
+                }
+                let aspa = Aspa {
+                    customer_as: u32::from_be_bytes(raw_data[8..12].try_into().unwrap()),
+                    providers,
+                };
+                RtrPdu::Aspa { announce: raw_data[2] & 1 == 1, aspa }
+            },
+            _ => RtrPdu::DontKnow(pdu_type),
+        };
+        Ok(pdu)
+    }
+
+    fn decode_error_report(protocol_version: u8, error_code: u16) -> Vec<u8> {
+        // encapsulated PDUもerror textも付けない
+        let mut result = vec![protocol_version, 10];
+        result.append(&mut error_code.to_be_bytes().to_vec());
+        result.append(&mut 16u32.to_be_bytes().to_vec());
+        result.append(&mut 0u32.to_be_bytes().to_vec());
+        result.append(&mut 0u32.to_be_bytes().to_vec());
+        result
+    }
+
+    fn decode_reset_query(protocol_version: u8) -> Vec<u8> {
+        vec![protocol_version, 2, 0, 0, 0, 0, 0, 8]
+    }
+
+    fn decode_serial_query(protocol_version: u8, session_id: u16, serial: u32) -> Vec<u8> {
+        let mut result = vec![protocol_version, 1];
+        result.append(&mut session_id.to_be_bytes().to_vec());
+        result.append(&mut 12u32.to_be_bytes().to_vec());
+        result.append(&mut serial.to_be_bytes().to_vec());
+        result
+    }
+}
+
+pub struct RtrClient {
+    address: String,
+    tcp_connection: Option<TcpStream>,
+    buf: Vec<u8>,
+    session_id: Option<u16>,
+    serial: Option<u32>,
+    // Cache Responseを受け取ってからEnd of Dataまでに受け取ったROAを反映していく途中のtable
+    pending_roa_table: Option<RoaTable>,
+    pending_aspa_table: Option<AspaTable>,
+    is_resetting: bool,
+    roa_table: RoaTable,
+    aspa_table: AspaTable,
+    protocol_version: u8,
+    refresh_timer: SystemTime,
+    refresh_interval: Duration,
+    connect_retry_timer: Option<SystemTime>,
+    connect_retry_interval: Duration,
+}
+
+impl RtrClient {
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            tcp_connection: None,
+            buf: vec![],
+            session_id: None,
+            serial: None,
+            pending_roa_table: None,
+            pending_aspa_table: None,
+            is_resetting: false,
+            roa_table: RoaTable::default(),
+            aspa_table: AspaTable::default(),
+            protocol_version: RTR_PROTOCOL_VERSION,
+            refresh_timer: SystemTime::now(),
+            refresh_interval: Duration::from_secs(3600),
+            connect_retry_timer: None,
+            connect_retry_interval: Duration::from_secs(600),
+        }
+    }
+
+    pub fn get_roa_table(&self) -> &RoaTable {
+        &self.roa_table
+    }
+
+    pub fn get_aspa_table(&self) -> &AspaTable {
+        &self.aspa_table
+    }
+
+    pub fn poll(&mut self) -> bool {
+        // cacheとやりとりして、ROAかASPAのtableが変わったらtrueを返す
+        if self.tcp_connection.is_none() && !self.connect() {
+            return false;
+        }
+        if self.refresh_timer.elapsed().unwrap_or_default() >= self.refresh_interval {
+            self.send_serial_query_or_reset_query();
+        }
+        let mut buf = vec![];
+        match self.tcp_connection.as_ref().unwrap().read_to_end(&mut buf) {
+            Ok(_) => {
+                // cacheとのconnectionが切れた。今のtableはそのまま使い続けて後で再接続する
+                warn!("rtr connection to {} is closed", self.address);
+                self.buf.append(&mut buf);
+                self.tcp_connection = None;
+                self.connect_retry_timer = Some(SystemTime::now());
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.buf.append(&mut buf);
+            },
+            Err(e) => {
+                warn!("rtr other error happen: {:?}", e);
+            },
+        }
+        self.handle_received_pdus()
+    }
+
+    fn handle_received_pdus(&mut self) -> bool {
+        let mut is_changed = false;
+        loop {
+            match self.retrieve_one_pdu() {
+                Ok(Some(pdu)) => is_changed |= self.handle_pdu(pdu),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("rtr: {} from {}", e, self.address);
+                    self.reset_session();
+                    break;
+                },
+            }
+        }
+        is_changed
+    }
+
+    fn reset_session(&mut self) {
+        // 壊れたPDUを受け取ったらCorrupt Dataを返して切断し、すぐに繋ぎ直して全てのROAを要求し直す。
+        // 今のtableはEnd of Dataを受け取るまでそのまま使う
+        if let Some(tcp_connection) = self.tcp_connection.as_mut() {
+            let _ = tcp_connection.write(&RtrPdu::decode_error_report(self.protocol_version, RTR_ERROR_CORRUPT_DATA));
+        }
+        self.tcp_connection = None;
+        self.buf = vec![];
+        self.session_id = None;
+        self.serial = None;
+        self.pending_roa_table = None;
+        self.pending_aspa_table = None;
+        self.connect_retry_timer = None;
+    }
+
+    fn connect(&mut self) -> bool {
+        if let Some(connect_retry_timer) = self.connect_retry_timer {
+            if connect_retry_timer.elapsed().unwrap_or_default() < self.connect_retry_interval {
+                return false;
+            }
+        }
+        match self.connect_with_timeout() {
+            Ok(tcp_connection) => {
+                self.tcp_connection = Some(tcp_connection);
+                self.buf = vec![];
+                self.connect_retry_timer = None;
+                self.send_serial_query_or_reset_query();
+                true
+            },
+            Err(e) => {
+                warn!("cannot connect to rtr cache {}: {:?}", self.address, e);
+                self.connect_retry_timer = Some(SystemTime::now());
+                false
+            },
+        }
+    }
+
+    fn connect_with_timeout(&self) -> io::Result<TcpStream> {
+        // main loopは1つのthreadで全てのpeerを扱うので、cacheに繋がらなくてもblockし続けないようにする
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address");
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, RTR_CONNECT_TIMEOUT) {
+                Ok(tcp_connection) => {
+                    tcp_connection.set_nonblocking(true)?;
+                    return Ok(tcp_connection);
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn send_serial_query_or_reset_query(&mut self) {
+        // session idとserialを知っていれば差分だけを、知らなければ全てのROAを要求する
+        let query = match (self.session_id, self.serial) {
+            (Some(session_id), Some(serial)) => RtrPdu::decode_serial_query(self.protocol_version, session_id, serial),
+            _ => {
+                self.is_resetting = true;
+                RtrPdu::decode_reset_query(self.protocol_version)
+            },
+        };
+        self.refresh_timer = SystemTime::now();
+        if let Some(tcp_connection) = self.tcp_connection.as_mut() {
+            let _ = tcp_connection.write(&query[..]);
+        }
+    }
+
+    fn retrieve_one_pdu(&mut self) -> Result<Option<RtrPdu>, MalformedRtrPduError> {
+        if self.buf.len() < RTR_HEADER_LENGTH {
+            return Ok(None);
+        }
+        let length: usize = u32::from_be_bytes(self.buf[4..8].try_into().unwrap()).try_into().unwrap();
+        if length < RTR_HEADER_LENGTH {
+            return Err(MalformedRtrPduError { pdu_type: self.buf[1], length });
+        }
+        if self.buf.len() < length {
+            return Ok(None);
+        }
+        let pdu = RtrPdu::encode(&self.buf[..length]);
+        self.buf = self.buf[length..].to_vec();
+        pdu.map(Some)
+    }
+
+    fn handle_pdu(&mut self, pdu: RtrPdu) -> bool {
+        match pdu {
+            RtrPdu::SerialNotify { .. } => {
+                self.send_serial_query_or_reset_query();
+            },
+            RtrPdu::CacheResponse { session_id } => {
+                if self.session_id != Some(session_id) {
+                    self.is_resetting = true;
+                }
+                self.session_id = Some(session_id);
+                if self.is_resetting {
+                    self.pending_roa_table = Some(RoaTable::default());
+                    self.pending_aspa_table = Some(AspaTable::default());
+                } else {
+                    self.pending_roa_table = Some(self.roa_table.clone());
+                    self.pending_aspa_table = Some(self.aspa_table.clone());
+                }
+            },
+            RtrPdu::Ipv4Prefix { announce, roa } => {
+                if let Some(pending_roa_table) = self.pending_roa_table.as_mut() {
+                    if announce {
+                        if !pending_roa_table.0.contains(&roa) {
+                            pending_roa_table.0.push(roa);
+                        }
+                    } else {
+                        pending_roa_table.0.retain(|r| *r != roa);
+                    }
+                }
+            },
+            RtrPdu::EndOfData { session_id, serial, refresh_interval } => {
+                self.session_id = Some(session_id);
+                self.serial = Some(serial);
+                if let Some(refresh_interval) = refresh_interval {
+                    self.refresh_interval = Duration::from_secs(refresh_interval.into());
+                }
+                self.is_resetting = false;
+                if let (Some(pending_roa_table), Some(pending_aspa_table)) = (self.pending_roa_table.take(), self.pending_aspa_table.take()) {
+                    info!("rtr: {} roas and {} aspas (serial {})", pending_roa_table.0.len(), pending_aspa_table.0.len(), serial);
+                    let is_changed = pending_roa_table.0 != self.roa_table.0 || pending_aspa_table.0 != self.aspa_table.0;
+                    self.roa_table = pending_roa_table;
+                    self.aspa_table = pending_aspa_table;
+                    return is_changed;
+                }
+            },
+            RtrPdu::CacheReset => {
+                self.session_id = None;
+                self.serial = None;
+                self.send_serial_query_or_reset_query();
+            },
+            RtrPdu::Aspa { announce, aspa } => {
+                if let Some(pending_aspa_table) = self.pending_aspa_table.as_mut() {
+                    // 同じcustomer ASのASPAは置き換える
+                    pending_aspa_table.0.retain(|a| a.customer_as != aspa.customer_as);
+                    if announce {
+                        pending_aspa_table.0.push(aspa);
+                    }
+                }
+            },
+            RtrPdu::ErrorReport { error_code } => {
+                warn!("rtr: error report from cache: {}", error_code);
+                // Unsupported Protocol Version (error code 4) ならversionを下げて繋ぎ直す
+                if error_code == 4 && self.protocol_version > 0 {
+                    self.protocol_version -= 1;
+                    self.session_id = None;
+                    self.serial = None;
+                    self.tcp_connection = None;
+                    self.connect_retry_timer = None;
+                }
+            },
+            RtrPdu::DontKnow(_) => (),
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::bgp::AsPathSegment;
+
+    fn ipv4_prefix_pdu(prefix: [u8; 4], prefix_length: u8, max_length: u8, origin_as: u32) -> Vec<u8> {
+        let mut pdu = vec![RTR_PROTOCOL_VERSION, 4, 0, 0, 0, 0, 0, 20, 1, prefix_length, max_length, 0];
+        pdu.append(&mut prefix.to_vec());
+        pdu.append(&mut origin_as.to_be_bytes().to_vec());
+        pdu
+    }
+
+    #[test]
+    fn test_malformed_rtr_pdu() {
+        assert!(RtrPdu::encode(&ipv4_prefix_pdu([10, 0, 0, 0], 8, 16, 64513)).is_ok());
+        // lengthが足りないIPv4 Prefix
+        let mut pdu = ipv4_prefix_pdu([10, 0, 0, 0], 8, 16, 64513);
+        pdu.truncate(12);
+        pdu[7] = 12;
+        assert!(RtrPdu::encode(&pdu).is_err());
+        // ASPAのprovider ASが4 byteの倍数でない
+        assert!(RtrPdu::encode(&[RTR_PROTOCOL_VERSION, 11, 1, 0, 0, 0, 0, 14, 0, 0, 0xfc, 0x01, 0, 0]).is_err());
+        // headerより短い
+        assert!(RtrPdu::encode(&[]).is_err());
+        assert!(RtrPdu::encode(&[RTR_PROTOCOL_VERSION, 4, 0]).is_err());
+
+        let mut client = RtrClient::new("127.0.0.1:323");
+        client.session_id = Some(7);
+        client.serial = Some(1);
+        client.buf = pdu;
+        assert!(!client.handle_received_pdus());
+        assert_eq!(client.session_id, None);
+        assert_eq!(client.serial, None);
+        assert!(client.buf.is_empty());
+    }
+
+    #[test]
+    fn test_rtr_client_with_fixture_cache() {
+        // Reset Queryを受け取ったらfixtureのROAを返すだけのcache
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let cache = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reset_query = [0u8; 8];
+            stream.read_exact(&mut reset_query).unwrap();
+            assert_eq!(reset_query[1], 2);
+            let mut response = vec![RTR_PROTOCOL_VERSION, 3, 0, 7, 0, 0, 0, 8];
+            response.append(&mut ipv4_prefix_pdu([10, 0, 0, 0], 8, 16, 64513));
+            response.append(&mut ipv4_prefix_pdu([192, 168, 0, 0], 16, 24, 64514));
+            response.append(&mut vec![RTR_PROTOCOL_VERSION, 7, 0, 7, 0, 0, 0, 24, 0, 0, 0, 1]);
+            response.append(&mut vec![0, 0, 14, 16, 0, 0, 2, 88, 0, 0, 28, 32]);
+            stream.write_all(&response).unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+
+        let mut client = RtrClient::new(&address);
+        let mut is_changed = false;
+        for _ in 0..50 {
+            is_changed |= client.poll();
+            if is_changed {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        cache.join().unwrap();
+        assert!(is_changed);
+        assert_eq!(client.get_roa_table().0.len(), 2);
+
+        let roa_table = client.get_roa_table();
+        let as_path = |as_numbers: Vec<u16>| AsPath::new(vec![AsPathSegment::AsSequence(as_numbers)]);
+        let prefix = |network_address: [u8; 4], prefix_length| IpPrefix::new(network_address.into(), prefix_length);
+        assert_eq!(roa_table.validate(&prefix([10, 1, 0, 0], 16), &as_path(vec![64512, 64513])), RpkiValidationState::Valid);
+        assert_eq!(roa_table.validate(&prefix([10, 1, 1, 0], 24), &as_path(vec![64513])), RpkiValidationState::Invalid);
+        assert_eq!(roa_table.validate(&prefix([10, 1, 0, 0], 16), &as_path(vec![64515])), RpkiValidationState::Invalid);
+        assert_eq!(roa_table.validate(&prefix([172, 16, 0, 0], 16), &as_path(vec![64515])), RpkiValidationState::NotFound);
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use serde::Deserialize;
+use toml::{Spanned, Value};
+use crate::{Config, DaemonConfig, Mode};
+use crate::bgp::AutonomousSystemNumber;
+use crate::policy::{Policy, PrefixList};
+use crate::aggregation::AggregateAddress;
+use crate::network::NetworkStatement;
+use crate::redistribute::RedistributeConfig;
+use crate::fib::FibConfig;
+
+// TOMLの設定ファイル (bgp.tomlを参照)
+//   [global]            asn, router-id, listen-addresses, rpki-cache, aspa-file, control-socket
+//                       それ以外のkeyは全てのneighborのdefaultのoption
+//   [[neighbor]]        address, remote-asn, local-address, mode, networks
+//                       それ以外のkeyはpeerのoption (hold-time = 30, graceful-restart = true など)
+//   [[prefix-list]]     name, entries = ["<prefix> [ge <length>] [le <length>]", ...]
+//   [[policy]]          name, terms = ["<term> [<match condition>...] then <action>...", ...]
+//   [[network]]         prefix とnetwork.rsのoption
+//   [[aggregate-address]] prefix とaggregation.rsのoption
+//   [redistribute]      redistribute.rsのoption
+//   [fib]               fib.rsのoption
+// optionはlegacyの形式の`key=value`に直してparseするので、意味はそちらと同じ。
+// TOMLはtoml crateで読み、keyとvalueの位置からエラーの行番号を出す。
+
+type SpannedTable = Spanned<BTreeMap<Spanned<String>, Spanned<Value>>>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct TomlDocument {
+    global: Option<SpannedTable>,
+    #[serde(default)]
+    neighbor: Vec<SpannedTable>,
+    #[serde(default)]
+    prefix_list: Vec<Spanned<PrefixListSection>>,
+    #[serde(default)]
+    policy: Vec<Spanned<PolicySection>>,
+    #[serde(default)]
+    network: Vec<SpannedTable>,
+    #[serde(default)]
+    aggregate_address: Vec<SpannedTable>,
+    redistribute: Option<SpannedTable>,
+    fib: Option<SpannedTable>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrefixListSection {
+    name: String,
+    #[serde(default)]
+    entries: Vec<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicySection {
+    name: String,
+    #[serde(default)]
+    terms: Vec<Spanned<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TomlError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl TomlError {
+    pub fn new(line: usize, message: String) -> Self {
+        Self { line, message }
+    }
+}
+
+struct TomlEntry<'a> {
+    key: &'a str,
+    value: &'a Value,
+    line: usize,
+}
+
+struct TomlTable<'a> {
+    name: &'a str,
+    line: usize,
+    entries: Vec<TomlEntry<'a>>, // 設定ファイルに書いた順
+}
+
+impl<'a> TomlTable<'a> {
+    fn new(input: &str, name: &'a str, table: &'a SpannedTable) -> Self {
+        let mut entries: Vec<TomlEntry> = table.get_ref().iter()
+            .map(|(key, value)| TomlEntry { key: key.get_ref(), value: value.get_ref(), line: line_of(input, key.span().start) })
+            .collect();
+        entries.sort_by_key(|entry| entry.line);
+        Self { name, line: line_of(input, table.span().start), entries }
+    }
+
+    fn get(&self, key: &str) -> Option<&TomlEntry<'a>> {
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+
+    fn get_string(&self, key: &str) -> Result<Option<String>, TomlError> {
+        match self.get(key) {
+            Some(TomlEntry { value: Value::String(s), .. }) => Ok(Some(s.clone())),
+            Some(entry) => Err(TomlError::new(entry.line, format!("`{}` must be a string", key))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_integer(&self, key: &str) -> Result<Option<i64>, TomlError> {
+        match self.get(key) {
+            Some(TomlEntry { value: Value::Integer(i), .. }) => Ok(Some(*i)),
+            Some(entry) => Err(TomlError::new(entry.line, format!("`{}` must be an integer", key))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_strings(&self, key: &str) -> Result<Vec<String>, TomlError> {
+        // 配列の代わりに文字列を1つだけ書いてもよい
+        let entry = match self.get(key) {
+            Some(entry) => entry,
+            None => return Ok(vec![]),
+        };
+        let values = match entry.value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        values.into_iter().map(|value| match value {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(TomlError::new(entry.line, format!("`{}` must be an array of strings", key))),
+        }).collect()
+    }
+
+    fn require<T>(&self, key: &str, value: Option<T>) -> Result<T, TomlError> {
+        value.ok_or_else(|| TomlError::new(self.line, format!("`{}` is required in [{}]", key, self.name)))
+    }
+}
+
+fn line_of(input: &str, offset: usize) -> usize {
+    input[..offset.min(input.len())].matches('\n').count() + 1
+}
+
+const GLOBAL_KEYS: [&str; 6] = ["asn", "router-id", "listen-addresses", "rpki-cache", "aspa-file", "control-socket"];
+const NEIGHBOR_KEYS: [&str; 5] = ["address", "remote-asn", "local-address", "mode", "networks"];
+
+pub fn parse(input: &str) -> Result<DaemonConfig, TomlError> {
+    // 知らないsectionや[[neighbor]]を[neighbor]と書いた誤りはtoml crateのエラーになる
+    let document: TomlDocument = toml::from_str(input).map_err(|e| {
+        let line = e.span().map_or(0, |span| line_of(input, span.start));
+        TomlError::new(line, e.message().trim().replace('\n', " "))
+    })?;
+    let global = document.global.as_ref().ok_or_else(|| TomlError::new(0, "[global] is required".to_string()))?;
+    let global = TomlTable::new(input, "global", global);
+
+    let mut prefix_lists: Vec<PrefixList> = vec![];
+    for section in &document.prefix_list {
+        let mut prefix_list = PrefixList::new(&section.get_ref().name);
+        for entry in &section.get_ref().entries {
+            let args: Vec<&str> = entry.get_ref().split_whitespace().collect();
+            prefix_list.add_entry_from_args(&args).map_err(|e| TomlError::new(line_of(input, entry.span().start), e))?;
+        }
+        prefix_lists.push(prefix_list);
+    }
+    let mut policies: Vec<Policy> = vec![];
+    for section in &document.policy {
+        let mut policy = Policy::new(&section.get_ref().name);
+        for term in &section.get_ref().terms {
+            let args: Vec<&str> = term.get_ref().split_whitespace().collect();
+            policy.add_term_from_args(&args, &prefix_lists).map_err(|e| TomlError::new(line_of(input, term.span().start), e))?;
+        }
+        policies.push(policy);
+    }
+
+    let as_number = parse_as_number(&global, "asn")?;
+    let router_id: Option<Ipv4Addr> = parse_value(&global, "router-id")?;
+    let listen_addresses: Vec<Ipv4Addr> = global.get_strings("listen-addresses")?.iter()
+        .map(|address| address.parse().map_err(|_| error_at(&global, "listen-addresses", format!("cannot parse listen-addresses: {}", address))))
+        .collect::<Result<_, _>>()?;
+    let default_options: Vec<&TomlEntry> = global.entries.iter().filter(|entry| !GLOBAL_KEYS.contains(&entry.key)).collect();
+    // neighborが無くてもglobalのdefaultのoptionの誤りが分かるように、一度peerの設定として読んでみる
+    let mut default_peer = Config::new(as_number, Ipv4Addr::UNSPECIFIED, as_number, Ipv4Addr::UNSPECIFIED, Mode::Passive, vec![]);
+    for entry in &default_options {
+        if let Some(option) = to_option(entry)? {
+            default_peer.parse_option(&option).map_err(|e| TomlError::new(entry.line, e))?;
+        }
+    }
+    default_peer.resolve_policies(&policies).map_err(|e| TomlError::new(global.line, e))?;
+
+    let mut config = DaemonConfig::default();
+    config.rpki_cache = global.get_string("rpki-cache")?;
+    config.aspa_file = global.get_string("aspa-file")?;
+    config.control_socket = global.get_string("control-socket")?;
+    for table in &document.neighbor {
+        let table = TomlTable::new(input, "neighbor", table);
+        let remote_ip_addr = table.require("address", parse_value(&table, "address")?)?;
+        let remote_as_number = parse_as_number(&table, "remote-asn")?;
+        // local-addressを書かなければlisten-addressesの最初のアドレスかrouter-idを使う
+        let my_ip_addr = match parse_value(&table, "local-address")? {
+            Some(my_ip_addr) => {
+                if !listen_addresses.is_empty() && !listen_addresses.contains(&my_ip_addr) {
+                    return Err(error_at(&table, "local-address", format!("local-address {} is not in listen-addresses", my_ip_addr)));
+                }
+                my_ip_addr
+            },
+            None => listen_addresses.first().copied().or(router_id)
+                .ok_or_else(|| TomlError::new(table.line, "local-address is required when neither listen-addresses nor router-id is set".to_string()))?,
+        };
+        let mode = match table.get_string("mode")? {
+            Some(mode) => mode.parse().map_err(|_| error_at(&table, "mode", format!("mode must be active or passive: {}", mode)))?,
+            None => Mode::Active,
+        };
+        let advertisement_networks = table.get_strings("networks")?.iter()
+            .map(|network| network.parse().map_err(|e| error_at(&table, "networks", e)))
+            .collect::<Result<_, _>>()?;
+        let mut peer = Config::new(as_number, my_ip_addr, remote_as_number, remote_ip_addr, mode, advertisement_networks);
+        peer.router_id = router_id;
+        // neighborに同じkeyがあればglobalのdefaultより優先する
+        let options = default_options.iter().copied()
+            .filter(|default_option| table.get(default_option.key).is_none())
+            .chain(table.entries.iter().filter(|entry| !NEIGHBOR_KEYS.contains(&entry.key)));
+        for entry in options {
+            if let Some(option) = to_option(entry)? {
+                peer.parse_option(&option).map_err(|e| TomlError::new(entry.line, e))?;
+            }
+        }
+        peer.resolve_policies(&policies).map_err(|e| TomlError::new(table.line, e))?;
+        config.peers.push(peer);
+    }
+
+    for table in &document.network {
+        let table = TomlTable::new(input, "network", table);
+        let args = to_args(&table, Some("prefix"))?;
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        config.network_statements.push(NetworkStatement::parse_from_args(&args).map_err(|e| TomlError::new(table.line, e))?);
+    }
+    for table in &document.aggregate_address {
+        let table = TomlTable::new(input, "aggregate-address", table);
+        let args = to_args(&table, Some("prefix"))?;
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        config.aggregate_addresses.push(AggregateAddress::parse_from_args(&args).map_err(|e| TomlError::new(table.line, e))?);
+    }
+    if let Some(table) = &document.redistribute {
+        let table = TomlTable::new(input, "redistribute", table);
+        let args = to_args(&table, None)?;
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        config.redistribute = Some(RedistributeConfig::parse_from_args(&args).map_err(|e| TomlError::new(table.line, e))?);
+    }
+    if let Some(table) = &document.fib {
+        let table = TomlTable::new(input, "fib", table);
+        let args = to_args(&table, None)?;
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        config.fib = FibConfig::parse_from_args(&args).map_err(|e| TomlError::new(table.line, e))?;
+    }
+    Ok(config)
+}
+
+fn error_at(table: &TomlTable, key: &str, message: String) -> TomlError {
+    // keyの行を指す。keyが無ければtableの行を指す
+    let line = table.get(key).map(|entry| entry.line).unwrap_or(table.line);
+    TomlError::new(line, message)
+}
+
+fn parse_value<T: std::str::FromStr>(table: &TomlTable, key: &str) -> Result<Option<T>, TomlError> {
+    match table.get_string(key)? {
+        Some(value) => value.parse().map(Some).map_err(|_| error_at(table, key, format!("cannot parse {}: {}", key, value))),
+        None => Ok(None),
+    }
+}
+
+fn parse_as_number(table: &TomlTable, key: &str) -> Result<AutonomousSystemNumber, TomlError> {
+    let as_number = table.require(key, table.get_integer(key)?)?;
+    match u16::try_from(as_number) {
+        Ok(as_number) => Ok(AutonomousSystemNumber::new(as_number)),
+        Err(_) => Err(error_at(table, key, format!("{} must be between 0 and 65535: {}", key, as_number))),
+    }
+}
+
+fn to_option_value(value: &Value) -> Option<String> {
+    // 配列はカンマ区切りにする。設定ファイルの`key=value,value`の形式に合わせるため
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Array(values) => values.iter().map(to_option_value).collect::<Option<Vec<_>>>().map(|values| values.join(",")),
+        Value::Table(_) | Value::Datetime(_) => None,
+    }
+}
+
+fn to_option(entry: &TomlEntry) -> Result<Option<String>, TomlError> {
+    // `key = true`は`key`、`key = false`は書かなかったことにする
+    match entry.value {
+        Value::Boolean(true) => Ok(Some(entry.key.to_string())),
+        Value::Boolean(false) => Ok(None),
+        value => match to_option_value(value) {
+            Some(value) => Ok(Some(format!("{}={}", entry.key, value))),
+            None => Err(TomlError::new(entry.line, format!("`{}` must be a string, number, boolean or array of them", entry.key))),
+        },
+    }
+}
+
+fn to_args(table: &TomlTable, positional_key: Option<&str>) -> Result<Vec<String>, TomlError> {
+    // legacyの形式の引数に直す。positional_keyの値は最初の引数にする
+    let mut args = vec![];
+    if let Some(key) = positional_key {
+        args.push(table.require(key, table.get_string(key)?)?);
+    }
+    for entry in &table.entries {
+        if Some(entry.key) == positional_key {
+            continue;
+        }
+        if let Some(option) = to_option(entry)? {
+            args.push(option);
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml_config() {
+        let config = parse(r#"
+[global]
+asn = 64512
+router-id = "10.255.0.1"
+listen-addresses = ["172.16.1.1"]
+hold-time = 30
+
+[[prefix-list]]
+name = "private"
+entries = ["192.168.0.0/16 le 32"]
+
+[[policy]]
+name = "import"
+terms = ["private prefix-list=private then reject", "others then accept"]
+
+[[neighbor]]
+address = "172.16.1.128"
+remote-asn = 64513
+mode = "passive"
+networks = ["192.168.90.0/24", "192.168.91.0/24"]
+hold-time = 9
+graceful-restart = true
+import-policy = "import"
+
+[[neighbor]]
+address = "172.16.1.129"
+remote-asn = 64512
+
+[[network]]
+prefix = "10.0.0.0/24"
+community = ["64512:100"]
+
+[fib]
+table = 100
+multipath-relax = true
+"#).unwrap();
+        assert_eq!(config.peers.len(), 2);
+        let peer = &config.peers[0];
+        assert_eq!(peer.my_ip_addr, Ipv4Addr::new(172, 16, 1, 1));
+        assert_eq!(peer.get_router_id(), Ipv4Addr::new(10, 255, 0, 1));
+        assert_eq!(peer.advertisement_networks.len(), 2);
+        assert_eq!(peer.get_hold_time(), 9);
+        assert!(peer.graceful_restart);
+        assert_eq!(peer.import_policy.as_ref().unwrap().name, "import");
+        assert_eq!(config.peers[1].get_hold_time(), 30);
+        assert!(config.peers[1].is_internal_peer());
+        assert_eq!(config.network_statements.len(), 1);
+        assert_eq!(config.fib.table, 100);
+
+        let error = parse("[global]\nasn = 64512\nrouter-id = \"10.0.0.1\"\n\n[[neighbor]]\naddress = \"10.0.0.2\"\nremote-asn = 64513\nhold-time = 2\n").unwrap_err();
+        assert_eq!(error, TomlError::new(8, "hold-time must be 0 or at least 3: hold-time=2".to_string()));
+        let error = parse("[global]\nasn = 64512\nrouter-id = '10.0.0.1'\n\n[[neighbor]]\naddress = '10.0.0.2'\nremote-asn = 64513\nrestart-time = 5000\n").unwrap_err();
+        assert_eq!(error, TomlError::new(8, "restart-time must be at most 4095: restart-time=5000".to_string()));
+        assert_eq!(parse("[global]\nasn = 64512\n\n[[neighbor]]\nremote-asn = 64513\n").unwrap_err(),
+            TomlError::new(4, "`address` is required in [neighbor]".to_string()));
+        // neighborが無くてもglobalの知らないkeyはエラーにする
+        assert_eq!(parse("[global]\nasn = 64512\nhold-tme = 30\n").unwrap_err(), TomlError::new(3, "unknown option: hold-tme=30".to_string()));
+        assert_eq!(parse("[global]\nasn = 64512\n\n[[neighbor]]\naddress = '10.0.0.2'\nremote-asn = 64513\n[bgp]\n").unwrap_err().line, 7);
+
+        let config = parse("[global]\nasn = 64512\nrouter-id = '10.0.0.1'\n\n[[neighbor]]\naddress = '10.0.0.2'\nremote-asn = 64513\n").unwrap();
+        assert_eq!(config.peers[0].remote_ip_addr, Ipv4Addr::new(10, 0, 0, 2));
+    }
+}